@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::PathBuf;
+use std::{env, fs, io};
+
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use path_absolutize::Absolutize;
+use teapot_tools::cipd::common::service_url;
+use teapot_tools::cipd::ensure::{ensure, expand_template, EnsureFile};
+use teapot_tools::cipd::install::instance_id;
+use teapot_tools::cipd::instance_cache::InstanceCache;
+use teapot_tools::cipd::repository::{list_instances, resolve_instance};
+use teapot_tools::host::{cipd_host_cpu, cipd_host_os};
+use teapot_tools::types::cipd::PackageInstance;
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Install the packages listed in an ensure file
+    Ensure {
+        #[clap(long, value_parser)]
+        /// Directory to install the packages to
+        root: PathBuf,
+
+        #[clap(long = "ensure-file", value_parser)]
+        /// Ensure file to read, "-" for stdin
+        ensure_file: String,
+    },
+    /// Print the instance a version of a package resolves to
+    Resolve {
+        #[clap(value_parser)]
+        package: String,
+
+        #[clap(long, value_parser, default_value = "latest")]
+        /// Tag, ref or instance id
+        version: String,
+    },
+    /// Print details of an instance of a package
+    Describe {
+        #[clap(value_parser)]
+        package: String,
+
+        #[clap(long, value_parser, default_value = "latest")]
+        /// Tag, ref or instance id
+        version: String,
+    },
+    /// List instances of a package
+    Instances {
+        #[clap(value_parser)]
+        package: String,
+
+        #[clap(long, value_parser, default_value_t = 20)]
+        /// How many instances to list at most
+        limit: usize,
+    },
+}
+
+/// luci-go's cipd takes long flags with a single dash (`-root`), clap wants two.
+/// only our own flag names are rewritten, values starting with a dash are left alone
+fn double_dash_flags(args: impl Iterator<Item = String>) -> Vec<String> {
+    let cli = Cli::command();
+    let long_flags: HashSet<_> = cli
+        .get_subcommands()
+        .flat_map(|command| command.get_arguments())
+        .filter_map(|arg| arg.get_long())
+        .chain(["help", "version"])
+        .collect();
+    args.map(|arg| match arg.strip_prefix('-') {
+        Some(flag) if long_flags.contains(flag.split('=').next().unwrap_or_default()) => {
+            format!("-{}", arg)
+        }
+        _ => arg,
+    })
+    .collect()
+}
+
+/// package name with ${platform} and such filled in for this host
+fn host_package(package: &str) -> Result<String> {
+    match expand_template(package, &cipd_host_os(), &cipd_host_cpu())? {
+        Some(package) => Ok(package),
+        None => bail!("{} is not for this platform", package),
+    }
+}
+
+fn package_instance_id(instance: &PackageInstance) -> Result<String> {
+    instance_id(
+        instance
+            .digest
+            .as_ref()
+            .context("cipd returned an instance without a digest")?,
+    )
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse_from(double_dash_flags(env::args()));
+
+    match cli.command {
+        Commands::Ensure { root, ensure_file } => {
+            let contents = if ensure_file == "-" {
+                let mut contents = String::new();
+                io::stdin()
+                    .read_to_string(&mut contents)
+                    .context("reading ensure file from stdin")?;
+                contents
+            } else {
+                fs::read_to_string(&ensure_file)
+                    .with_context(|| format!("reading ensure file {:?}", ensure_file))?
+            };
+            let ensure_file = EnsureFile::parse(&contents)?;
+            let root = root.absolutize()?.to_path_buf();
+            let ensured = ensure(
+                &root,
+                &ensure_file,
+                &InstanceCache::configured(&root, None, None)?,
+            )
+            .await?;
+            println!("{} package(s) in {:?}", ensured.len(), root);
+        }
+        Commands::Resolve { package, version } => {
+            let instance =
+                resolve_instance(&service_url(None), &host_package(&package)?, &version).await?;
+            println!("Packages:");
+            println!("  {}:{}", instance.package, package_instance_id(&instance)?);
+        }
+        Commands::Describe { package, version } => {
+            let instance =
+                resolve_instance(&service_url(None), &host_package(&package)?, &version).await?;
+            let digest = instance.digest.clone().unwrap_or_default();
+            println!("Package:       {}", instance.package);
+            println!("Instance ID:   {}", package_instance_id(&instance)?);
+            println!(
+                "Digest:        {}:{}",
+                digest.algorithm().as_str_name(),
+                digest.hex_digest
+            );
+            println!("Registered by: {}", instance.publisher);
+        }
+        Commands::Instances { package, limit } => {
+            let package = host_package(&package)?;
+            // one more, to tell if there are more
+            let instances = list_instances(&service_url(None), &package, limit + 1).await?;
+            println!("Instances of {}:", package);
+            for instance in instances.iter().take(limit) {
+                println!(
+                    "{} | {}",
+                    package_instance_id(instance)?,
+                    instance.publisher
+                );
+            }
+            if instances.len() > limit {
+                println!("...");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::double_dash_flags;
+
+    #[test]
+    fn test_double_dash_flags() {
+        let args = [
+            "cipd",
+            "ensure",
+            "-root",
+            "-odd-dir",
+            "-ensure-file=-",
+            "-x",
+            "--limit",
+        ];
+        assert_eq!(
+            double_dash_flags(args.into_iter().map(str::to_string)),
+            [
+                "cipd",
+                "ensure",
+                "--root",
+                "-odd-dir",
+                "--ensure-file=-",
+                "-x",
+                "--limit"
+            ]
+        );
+    }
+}
@@ -1,11 +1,11 @@
 use std::{env::current_dir, path::PathBuf};
 
-use anyhow::Context;
+use anyhow::{bail, Result};
 use clap::Parser;
 
 use globwalk::GlobWalkerBuilder;
 use path_absolutize::Absolutize;
-use teapot_tools::gs::download::{download, download_from_sha1_file};
+use teapot_tools::gs::download::{download, download_from_sha1_file, is_sha1_hex};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -39,58 +39,42 @@ struct Cli {
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     // sanity level 1
     if cli.recursive && !cli.directory {
-        panic!("--recursive is to be used with --directory");
+        bail!("--recursive is to be used with --directory");
     }
     if cli.sha1_file && cli.directory {
-        panic!("--sha1_file cannot be used with --directory");
+        bail!("--sha1_file cannot be used with --directory");
     }
 
     // sanity level 2
-    if !cli.sha1_file && !cli.directory {
-        assert_eq!(cli.target.len(), 40, "target must be sha1 hex");
-        assert!(
-            cli.target.chars().all(|c| c.is_ascii_alphanumeric()),
-            "target must be sha1 hex"
-        );
+    if !cli.sha1_file && !cli.directory && !is_sha1_hex(&cli.target) {
+        bail!("target must be sha1 hex");
     }
 
-    let cwd = current_dir().unwrap();
+    let cwd = current_dir()?;
     if !cli.sha1_file && !cli.directory {
         let file_target = cwd.join(&cli.target);
-        download(&cli.bucket, &cli.target, &file_target)
-            .await
-            .with_context(|| {
-                format!(
-                    "downloading {} from bucket {} to {:?}",
-                    cli.target, cli.bucket, file_target
-                )
-            })
-            .unwrap();
+        download(&cli.bucket, &cli.target, &file_target).await?;
     } else if cli.sha1_file {
         let sha1_file_ = PathBuf::from(&cli.target);
-        let sha1_file = sha1_file_.absolutize_from(&cwd).unwrap().to_path_buf();
-        download_from_sha1_file(&cli.bucket, sha1_file)
-            .await
-            .unwrap();
+        let sha1_file = sha1_file_.absolutize_from(&cwd)?.to_path_buf();
+        download_from_sha1_file(&cli.bucket, sha1_file).await?;
     } else {
         let base_path_ = PathBuf::from(&cli.target);
-        let base_path = base_path_.absolutize_from(&cwd).unwrap().to_path_buf();
+        let base_path = base_path_.absolutize_from(&cwd)?.to_path_buf();
         for sha1_file in
             GlobWalkerBuilder::new(base_path, if cli.recursive { "*.sha1" } else { "/*.sha1" })
                 .follow_links(true)
-                .build()
-                .unwrap()
+                .build()?
                 .filter_map(Result::ok)
                 .filter(|f| f.file_type().is_file())
         {
-            download_from_sha1_file(&cli.bucket, sha1_file.path())
-                .await
-                .unwrap();
+            download_from_sha1_file(&cli.bucket, sha1_file.path()).await?;
         }
     }
+    Ok(())
 }
@@ -1,13 +1,36 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use std::{env::current_dir, fs};
 
-use anyhow::{Context, Result};
-use teapot_tools::gclient::cloner::{clone_dependencies, git_clone, SyncOptions};
+use anyhow::{bail, Context, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use path_absolutize::Absolutize;
+use teapot_tools::cipd;
+use teapot_tools::cipd::instance_cache::InstanceCache;
+use teapot_tools::error::Error;
+use teapot_tools::gclient::cloner::{
+    clone_dependencies, git_clone, remove_stale_entries, split_git_url, url_with_revision,
+    SyncOptions,
+};
+use teapot_tools::gclient::deps_editor::{get_revision, get_var, set_revision, set_var};
 use teapot_tools::gclient::deps_parser::parse_deps;
+use teapot_tools::gclient::entries_cache::{
+    path_to_entries_cache, read_entries, write_entries, EntriesCache,
+};
+use teapot_tools::gclient::flatten::FlatDeps;
+use teapot_tools::gclient::git_cache;
+use teapot_tools::gclient::hooks::{hooks_cwd, hooks_matching_conditions, run_hooks};
+use teapot_tools::gclient::revinfo::{deps_revinfo, revinfo_json, solution_revinfo};
+use teapot_tools::gclient::sync_report::{write_sync_report, SyncAction, SyncReportEntry};
+use teapot_tools::gclient::unversioned_trees::{delete_unversioned_trees, find_unversioned_trees};
+use tokio::sync::{mpsc, Semaphore};
 
 use clap::{Parser, Subcommand};
 use teapot_tools::gclient::dotgclient::read_dotgclient;
-use teapot_tools::types::dotgclient::Solution;
+use teapot_tools::types::deps::{DepsSpec, Hook};
+use teapot_tools::types::dotgclient::{Dotgclient, Solution};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -35,9 +58,35 @@ enum Commands {
         jobs: Option<usize>,
 
         #[clap(short, long, value_parser, default_value_t = false)]
+        /// Update every dependency, even if unchanged since the last sync or with local changes
         force: bool,
 
+        #[clap(short = 'R', long, value_parser, default_value_t = false)]
+        /// Discard local changes in git checkouts before updating them
+        reset: bool,
+
+        #[clap(
+            short = 'D',
+            long = "delete_unversioned_trees",
+            value_parser,
+            default_value_t = false
+        )]
+        /// Delete git checkouts and cipd packages in solutions that no DEPS references anymore
+        /// - ones with local changes are kept, unless forced
+        delete_unversioned_trees: bool,
+
+        #[clap(long = "output-json", value_parser)]
+        /// Write what was done to every solution and dependency to a JSON file,
+        /// in the layout of depot_tools' gclient
+        output_json: Option<String>,
+
+        #[clap(short, long = "revision", value_parser)]
+        /// Sync a solution or dependency to a revision instead of the one in .gclient or DEPS,
+        /// as `name@revision` - a bare revision is for the first solution. Can be repeated
+        revisions: Vec<String>,
+
         #[clap(short, long = "nohooks", value_parser, default_value_t = false)]
+        /// Do not run hooks after syncing the dependencies
         no_hooks: bool,
 
         #[clap(
@@ -46,6 +95,7 @@ enum Commands {
             value_parser,
             default_value_t = false
         )]
+        /// Do not run pre_deps_hooks before syncing the dependencies
         no_prehooks: bool,
 
         #[clap(long = "no-history", value_parser, default_value_t = false)]
@@ -58,6 +108,54 @@ enum Commands {
         /// - pretty surely they are built binaries
         cipd_ignore_platformed: bool,
     },
+    /// Run hooks of the already synced solutions and recursedeps
+    Runhooks,
+    /// Print the resolved url and revision of every dependency
+    Revinfo {
+        #[clap(short, long, action)]
+        /// Read the revisions of the existing git checkouts instead of DEPS
+        actual: bool,
+
+        #[clap(long = "output-json", value_parser)]
+        /// Also write the revisions as JSON to this file
+        output_json: Option<String>,
+    },
+    /// Collapse DEPS of all solutions and recursedeps into one, with pinned revisions
+    Flatten {
+        #[clap(long = "output-deps", value_parser)]
+        /// File to write the flattened DEPS to, stdout if not set
+        output_deps: Option<String>,
+
+        #[clap(long = "output-deps-files", value_parser)]
+        /// Also write which DEPS file each dependency came from, as JSON
+        output_deps_files: Option<String>,
+    },
+    /// Print revisions of dependencies or values of vars from a DEPS file
+    Getdep {
+        #[clap(long = "var", value_parser)]
+        /// Name of a var to print
+        vars: Vec<String>,
+
+        #[clap(short, long = "revision", value_parser)]
+        /// Path of a dependency (or "{path}:{package}" for cipd) to print the revision of
+        revisions: Vec<String>,
+
+        #[clap(long = "deps-file", value_parser, default_value = "DEPS")]
+        deps_file: String,
+    },
+    /// Change revisions of dependencies or values of vars in a DEPS file
+    Setdep {
+        #[clap(long = "var", value_parser)]
+        /// "{name}={value}" of a var to change
+        vars: Vec<String>,
+
+        #[clap(short, long = "revision", value_parser)]
+        /// "{path}@{revision}" of a dependency (or "{path}:{package}@{version}" for cipd)
+        revisions: Vec<String>,
+
+        #[clap(long = "deps-file", value_parser, default_value = "DEPS")]
+        deps_file: String,
+    },
     // gclient config --spec 'solutions = [
     //   {
     //     "name": "src",
@@ -74,6 +172,350 @@ enum Commands {
     },
 }
 
+/// reads and parses .gclient
+fn load_dotgclient(dotgclient_location: &Path) -> Result<Dotgclient, Error> {
+    let contents = fs::read_to_string(dotgclient_location)
+        .map_err(|e| Error::filesystem(dotgclient_location, e))?;
+    read_dotgclient(contents).map_err(|e| Error::deps_parse(dotgclient_location, e))
+}
+
+/// reads and parses the DEPS file of a solution, returns its location too
+fn read_solution_deps(
+    current_dir: &Path,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<(PathBuf, DepsSpec), Error> {
+    let deps_file_location = current_dir
+        .join(&solution.name)
+        .join(solution.deps_file.as_deref().unwrap_or("DEPS"));
+    let deps_file = fs::read_to_string(&deps_file_location)
+        .map_err(|e| Error::filesystem(&deps_file_location, e))?;
+    let spec = parse_deps(&deps_file, solution, dotgclient)
+        .map_err(|e| Error::deps_parse(&deps_file_location, e))?;
+    Ok((deps_file_location, spec))
+}
+
+/// runs the hooks of the solutions, then the ones of their recursedeps, in the same order
+/// as sync. recursedeps that aren't checked out (for their condition) have none to run
+fn runhooks(current_dir: &Path, dotgclient: &Dotgclient, opts: &SyncOptions) -> Result<(), Error> {
+    let mut todo_solutions = dotgclient.solutions.clone();
+    let mut solution_num = 0;
+    while solution_num < todo_solutions.len() {
+        let solution = todo_solutions[solution_num].clone();
+        solution_num += 1;
+
+        if solution.tpot_internal_from_recursedeps && !current_dir.join(&solution.name).is_dir() {
+            if opts.verbosity >= 1 {
+                println!("{} is not checked out, skipping its hooks", solution.name);
+            }
+            continue;
+        }
+        let (_, spec) = read_solution_deps(current_dir, &solution, dotgclient)?;
+        todo_solutions.extend(recursedeps_solutions(&spec, &solution));
+
+        let hooks = hooks_matching_conditions(&spec.hooks, &spec, &solution, dotgclient)?;
+        run_hooks(&hooks, hooks_cwd(current_dir, &spec, &solution), opts)?;
+    }
+    Ok(())
+}
+
+/// `--revision` values by solution or dependency name. like gclient,
+/// a bare revision is for the first solution
+fn parse_revisions(
+    revisions: &[String],
+    dotgclient: &Dotgclient,
+) -> Result<HashMap<String, String>> {
+    let mut parsed = HashMap::new();
+    for revision in revisions {
+        let (name, rev) = match revision.split_once('@') {
+            Some((name, rev)) => (name.trim_end_matches('/').to_string(), rev),
+            None => match dotgclient.solutions.first() {
+                Some(solution) => (solution.name.clone(), revision.as_str()),
+                None => bail!("no solution to sync to {}", revision),
+            },
+        };
+        if rev.is_empty() {
+            bail!("no revision in --revision {}", revision);
+        }
+        parsed.insert(name, rev.to_string());
+    }
+    Ok(parsed)
+}
+
+/// makes internal solutions out of recursedeps, to follow their DEPS too.
+/// like in gclient, they inherit the deps_file, and the vars of the DEPS they're in
+/// (overriden by its custom_vars) take precedence over their own
+fn recursedeps_solutions(spec: &DepsSpec, solution: &Solution) -> Vec<Solution> {
+    let mut custom_vars = spec.vars.clone();
+    custom_vars.extend(solution.custom_vars.clone().unwrap_or_default());
+    spec.recursedeps
+        .iter()
+        .map(|d| Solution {
+            name: if spec.use_relative_paths {
+                // if paths are relative to current DEPS,
+                // add the path of current DEPS to it
+                format!("{}/{}", solution.name, d.path())
+            } else {
+                d.path().to_string()
+            },
+            url: "".to_string(),
+            deps_file: d
+                .deps_file()
+                .map(str::to_string)
+                .or_else(|| solution.deps_file.clone()),
+            custom_vars: Some(custom_vars.clone()),
+            tpot_no_checkout: true,
+            tpot_internal_from_recursedeps: true,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// which of the pending solutions (where they are in the tree of solutions, and their
+/// directory) can start next to the running ones. one inside another (or the other way
+/// around) waits for it, if it's running or pending before it. except for recursedeps
+/// and the solutions they're from: they're pending once the dependency they're in is synced
+fn ready_solutions(
+    pending: &[(Vec<usize>, PathBuf)],
+    running: &[(Vec<usize>, PathBuf)],
+) -> Vec<usize> {
+    let mut taken: Vec<&(Vec<usize>, PathBuf)> = running.iter().collect();
+    let mut ready = vec![];
+    for (i, solution) in pending.iter().enumerate() {
+        let (order, dir) = solution;
+        let must_wait = taken.iter().any(|(taken_order, taken_dir)| {
+            !order.starts_with(taken_order)
+                && (taken_dir.starts_with(dir) || dir.starts_with(taken_dir))
+        });
+        taken.push(solution);
+        if !must_wait {
+            ready.push(i);
+        }
+    }
+    ready
+}
+
+/// solutions first, then their recursedeps, level by level, whichever finished first
+fn in_solution_order<T>(mut by_order: Vec<(Vec<usize>, T)>) -> Vec<T> {
+    by_order.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+    by_order.into_iter().map(|(_, t)| t).collect()
+}
+
+/// what syncing a single solution came up with. its recursedeps are synced separately
+#[derive(Default)]
+struct SolutionSync {
+    report: Vec<SyncReportEntry>,
+    errors: Vec<Error>,
+    /// the hooks matching conditions, and where to run them
+    hooks: Option<(PathBuf, Vec<Hook>)>,
+    referenced_paths: Vec<PathBuf>,
+    /// its part of .gclient_entries, none if it failed before syncing its DEPS
+    entries: Option<EntriesCache>,
+}
+
+/// what the solutions syncing at once share
+struct SolutionSyncer<'a> {
+    current_dir: &'a Path,
+    dotgclient_location: &'a Path,
+    dotgclient: Arc<Dotgclient>,
+    /// .gclient_entries of the last sync
+    previous_entries: &'a EntriesCache,
+    opts: &'a SyncOptions,
+    no_hooks: bool,
+    no_prehooks: bool,
+    /// recursedeps, with where they are in the tree of solutions,
+    /// as soon as the dependency they're in is synced
+    recursedeps: mpsc::UnboundedSender<(Vec<usize>, Solution)>,
+}
+
+impl SolutionSyncer<'_> {
+    /// clones the solution and its dependencies. errors are reported, not returned,
+    /// as they don't stop the other solutions
+    async fn sync(&self, order: &[usize], solution: &Solution) -> SolutionSync {
+        let mut result = SolutionSync::default();
+        if let Err(e) = self.sync_into(order, solution, &mut result).await {
+            eprintln!("{} failed: {}", solution.name, e);
+            result.errors.push(e);
+        }
+        result
+    }
+
+    async fn sync_into(
+        &self,
+        order: &[usize],
+        solution: &Solution,
+        result: &mut SolutionSync,
+    ) -> Result<(), Error> {
+        let solution_dir = self.current_dir.join(&solution.name);
+        let solution_dir = solution_dir
+            .absolutize()
+            .map_err(|e| Error::filesystem(&solution_dir, e))?
+            .to_path_buf();
+        result.referenced_paths.push(solution_dir.clone());
+        if !solution.tpot_no_checkout {
+            let url = match self.opts.revisions.get(&solution.name) {
+                Some(revision) => url_with_revision(&solution.url, revision)
+                    .map_err(|e| Error::deps_parse(self.dotgclient_location, e))?,
+                None => solution.url.clone(),
+            };
+            if self.opts.verbosity >= 0 {
+                println!("cloning {} ({})", solution.name, url);
+            }
+            fs::create_dir_all(&solution_dir).map_err(|e| Error::filesystem(&solution_dir, e))?;
+            let started = Instant::now();
+            let action = if solution_dir.join(".git").exists() {
+                SyncAction::Updated
+            } else {
+                SyncAction::Cloned
+            };
+            let (repo_url, requested_revision) =
+                split_git_url(&url).map_err(|e| Error::deps_parse(self.dotgclient_location, e))?;
+            let entry = SyncReportEntry {
+                url: Some(repo_url),
+                requested_revision,
+                ..SyncReportEntry::new(
+                    solution.name.clone(),
+                    solution_dir.clone(),
+                    Some("git"),
+                    action,
+                )
+            };
+            let commit = {
+                let _slot = self
+                    .opts
+                    .job_slots
+                    .acquire()
+                    .await
+                    .expect("job slots are never closed");
+                let (url, solution_dir, opts) =
+                    (url.clone(), solution_dir.clone(), self.opts.clone());
+                tokio::task::spawn_blocking(move || git_clone(&url, &solution_dir, &opts)).await
+            };
+            match commit
+                .map_err(|e| Error::git(&solution_dir, e))
+                .and_then(|commit| commit)
+            {
+                Ok(commit) => result.report.push(
+                    SyncReportEntry {
+                        revision: Some(commit),
+                        ..entry
+                    }
+                    .with_duration(started.elapsed()),
+                ),
+                Err(e) => {
+                    result.report.push(SyncReportEntry {
+                        action: SyncAction::Failed,
+                        was_synced: false,
+                        error: Some(e.to_string()),
+                        ..entry
+                    });
+                    return Err(e);
+                }
+            }
+        } else if solution.tpot_internal_from_recursedeps && self.opts.verbosity >= 0 {
+            println!("following recursedeps in {}", solution.name);
+        }
+
+        let (deps_file_location, spec) = {
+            let (current_dir, solution, dotgclient) = (
+                self.current_dir.to_path_buf(),
+                solution.clone(),
+                self.dotgclient.clone(),
+            );
+            tokio::task::spawn_blocking(move || {
+                read_solution_deps(&current_dir, &solution, &dotgclient)
+            })
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?
+        };
+        let solution_hooks_cwd = hooks_cwd(self.current_dir, &spec, solution);
+
+        if !self.no_prehooks && !spec.pre_deps_hooks.is_empty() {
+            let pre_deps_hooks =
+                hooks_matching_conditions(&spec.pre_deps_hooks, &spec, solution, &self.dotgclient)?;
+            let (solution_hooks_cwd, opts) = (solution_hooks_cwd.clone(), self.opts.clone());
+            tokio::task::spawn_blocking(move || {
+                run_hooks(&pre_deps_hooks, &solution_hooks_cwd, &opts)
+            })
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+        }
+        if !self.no_hooks && !spec.hooks.is_empty() {
+            result.hooks = Some((
+                solution_hooks_cwd,
+                hooks_matching_conditions(&spec.hooks, &spec, solution, &self.dotgclient)?,
+            ));
+        }
+
+        // they're synced as solutions of their own, once their DEPS is there
+        let mut waiting_recursedeps = vec![];
+        for (i, recursedep) in recursedeps_solutions(&spec, solution)
+            .into_iter()
+            .enumerate()
+        {
+            let dir = self.current_dir.join(&recursedep.name);
+            let dir = dir
+                .absolutize()
+                .map_err(|e| Error::filesystem(&dir, e))?
+                .to_path_buf();
+            waiting_recursedeps.push(([order, &[i]].concat(), dir, recursedep));
+        }
+
+        let base_path = match deps_file_location.parent() {
+            Some(deps_dir) if spec.use_relative_paths => deps_dir.to_path_buf(),
+            _ => self.current_dir.to_path_buf(),
+        };
+        let (synced_deps, mut synced_deps_rx) = mpsc::unbounded_channel();
+        let cloned = clone_dependencies(
+            &spec,
+            base_path,
+            solution,
+            &self.dotgclient,
+            self.previous_entries,
+            SyncOptions {
+                synced_deps: Some(synced_deps),
+                ..self.opts.clone()
+            },
+        );
+        let send_recursedeps = async {
+            while let Some(synced) = synced_deps_rx.recv().await {
+                let (ready, waiting) = std::mem::take(&mut waiting_recursedeps)
+                    .into_iter()
+                    .partition(|(_, dir, _)| dir == &synced);
+                waiting_recursedeps = waiting;
+                for (order, _, recursedep) in ready {
+                    let _ = self.recursedeps.send((order, recursedep));
+                }
+            }
+        };
+        let (cloned, ()) = tokio::join!(cloned, send_recursedeps);
+        let (dep_report, entries, dep_errors) = cloned?;
+        // the rest are in dependencies that failed (and are in the errors already),
+        // or that aren't synced at all because of their condition
+        for (_, dir, recursedep) in waiting_recursedeps {
+            let failed = dep_report
+                .iter()
+                .any(|e| e.path == dir && e.action == SyncAction::Failed);
+            if failed && self.opts.verbosity >= 0 {
+                eprintln!(
+                    "not following recursedeps in {}, it failed to sync",
+                    recursedep.name
+                );
+            }
+        }
+        result.entries = Some(entries);
+        result.referenced_paths.extend(
+            dep_report
+                .iter()
+                .filter(|e| e.action != SyncAction::Deleted)
+                .map(|e| e.path.clone()),
+        );
+        result.report.extend(dep_report);
+        result.errors.extend(dep_errors);
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -83,120 +525,501 @@ async fn main() -> Result<()> {
     match cli.command {
         Commands::Sync {
             jobs: jobs_,
-            force: _,
-            no_hooks: _,
-            no_prehooks: _,
+            force,
+            reset,
+            delete_unversioned_trees: delete_unversioned_trees_,
+            revisions,
+            output_json,
+            no_hooks,
+            no_prehooks,
             no_history,
             cipd_ignore_platformed,
         } => {
-            let jobs = jobs_.unwrap_or_else(|| std::thread::available_parallelism().unwrap().get());
-            let current_dir = current_dir().expect("current dir");
+            let jobs = jobs_.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+            });
+            let current_dir = current_dir()?;
 
             let dotgclient_location = current_dir.join(cli.gclient_file);
-            let dotgclient = read_dotgclient(
-                fs::read_to_string(&dotgclient_location)
-                    .with_context(|| format!("cannot read file: {:?}", dotgclient_location))
-                    .unwrap(),
-            )
-            .unwrap();
+            let dotgclient = Arc::new(load_dotgclient(&dotgclient_location)?);
+            let cache_dir = git_cache::cache_dir(&current_dir, dotgclient.cache_dir.as_deref());
+            let cipd_cache = InstanceCache::configured(
+                &current_dir,
+                dotgclient.cipd_cache_dir.as_deref(),
+                dotgclient.cipd_cache_max_size,
+            )?;
+            let revisions = parse_revisions(&revisions, &dotgclient)?;
+            let opts = SyncOptions {
+                no_history,
+                jobs,
+                git_jobs: jobs,
+                verbosity,
+                force,
+                reset,
+                cipd_ignore_platformed,
+                cache_dir,
+                cipd_cache,
+                cipd_service_url: cipd::common::service_url(dotgclient.cipd_service_url.as_deref()),
+                revisions,
+                job_slots: Arc::new(Semaphore::new(jobs.max(1))),
+                synced_deps: None,
+            };
+            let entries_path = path_to_entries_cache(&current_dir);
+            let previous_entries =
+                read_entries(&entries_path).map_err(|e| Error::deps_parse(&entries_path, e))?;
 
-            let mut todo_solutions = dotgclient.solutions.clone();
-            let mut done_solutions: HashSet<usize> = HashSet::new();
+            let (recursedeps, mut recursedeps_rx) = mpsc::unbounded_channel();
+            let syncer = SolutionSyncer {
+                current_dir: &current_dir,
+                dotgclient_location: &dotgclient_location,
+                dotgclient: dotgclient.clone(),
+                previous_entries: &previous_entries,
+                opts: &opts,
+                no_hooks,
+                no_prehooks,
+                recursedeps,
+            };
+            // solutions waiting for their turn. the indices are where it is in the tree
+            // of solutions and recursedeps, to run the hooks in the same order every time
+            let mut pending: Vec<(Vec<usize>, Solution)> = dotgclient
+                .solutions
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(i, solution)| (vec![i], solution))
+                .collect();
+            let mut running_dirs: Vec<(Vec<usize>, PathBuf)> = vec![];
+            let mut running = FuturesUnordered::new();
+            // hooks are run after all the solutions are synced
+            let mut todo_hooks = vec![];
+            // everything the solutions and their DEPS account for, for -D and --revision
+            let mut referenced_paths: HashSet<PathBuf> = HashSet::new();
+            let mut sync_report: Vec<SyncReportEntry> = vec![];
+            // a failed solution doesn't stop the others, they're all listed at the end
+            let mut errors: Vec<Error> = vec![];
+            // .gclient_entries of all the solutions, as long as they all got that far
+            let mut entries = EntriesCache::new();
+            let mut all_entries = true;
 
-            while todo_solutions.len() != done_solutions.len() {
-                let tbd_solutions: Vec<_> = todo_solutions
-                    .clone()
+            loop {
+                while let Ok(recursedep) = recursedeps_rx.try_recv() {
+                    pending.push(recursedep);
+                }
+                let pending_dirs = pending
+                    .iter()
+                    .map(|(order, solution)| {
+                        let dir = current_dir.join(&solution.name).absolutize()?.to_path_buf();
+                        Ok((order.clone(), dir))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                for i in ready_solutions(&pending_dirs, &running_dirs)
                     .into_iter()
-                    .enumerate()
-                    .filter(|s| !done_solutions.contains(&s.0))
-                    .collect();
-                for solution in tbd_solutions.iter().map(|s| &s.1) {
-                    let solution_dir = current_dir.join(&solution.name);
-                    if !solution.tpot_no_checkout {
-                        if verbosity >= 0 {
-                            println!("cloning {} ({})", solution.name, solution.url);
-                        }
-                        fs::create_dir_all(&solution_dir)
-                            .with_context(|| {
-                                format!("cannot create solution directory: {:?}", &solution_dir)
-                            })
-                            .unwrap();
-                        git_clone(
-                            &solution.url,
-                            solution_dir.clone(),
-                            &SyncOptions {
-                                no_history,
-                                git_jobs: jobs,
-                                verbosity,
-                                ..Default::default()
-                            },
-                        )
-                        .unwrap();
-                    } else if solution.tpot_internal_from_recursedeps {
-                        if verbosity >= 0 {
-                            println!("following recursedeps in {}", solution.name);
-                        }
+                    .rev()
+                {
+                    let (order, solution) = pending.remove(i);
+                    let dir = pending_dirs[i].1.clone();
+                    running_dirs.push((order.clone(), dir.clone()));
+                    let syncer = &syncer;
+                    running.push(async move {
+                        let result = syncer.sync(&order, &solution).await;
+                        (order, dir, result)
+                    });
+                }
+
+                let (order, dir, result) = tokio::select! {
+                    Some(recursedep) = recursedeps_rx.recv() => {
+                        pending.push(recursedep);
+                        continue;
                     }
+                    finished = running.next() => match finished {
+                        Some(finished) => finished,
+                        None => break,
+                    },
+                };
+                running_dirs.retain(|(o, d)| (o, d) != (&order, &dir));
+                if let Some(hooks) = result.hooks {
+                    todo_hooks.push((order, hooks));
+                }
+                match result.entries {
+                    Some(solution_entries) => entries.extend(solution_entries),
+                    None => all_entries = false,
+                }
+                referenced_paths.extend(result.referenced_paths);
+                sync_report.extend(result.report);
+                errors.extend(result.errors);
+            }
 
-                    let deps_file_location =
-                        solution_dir.join(solution.deps_file.as_deref().unwrap_or("DEPS"));
-                    let deps_file = fs::read_to_string(&deps_file_location)
-                        .with_context(|| format!("cannot read file: {:?}", &deps_file_location))
-                        .unwrap();
-                    let spec = parse_deps(&deps_file, &solution, &dotgclient).unwrap();
-
-                    // if there are recursedeps, add them to the todo
-                    todo_solutions.extend(spec.recursedeps.iter().map(|d| Solution {
-                        name: if spec.use_relative_paths {
-                            // if paths are relative to current DEPS,
-                            // add the path of current DEPS to it
-                            format!("{}/{}", solution.name, d)
-                        } else {
-                            d.clone()
-                        },
-                        url: "".to_string(),
-                        tpot_no_checkout: true,
-                        tpot_internal_from_recursedeps: true,
-                        ..Default::default()
-                    }));
-
-                    clone_dependencies(
+            // what no solution has anymore is deleted. if one of them failed,
+            // who knows what it has, so its entries stay for the next sync
+            if all_entries {
+                match remove_stale_entries(&current_dir, &previous_entries, &entries, &opts) {
+                    Ok(deleted) => sync_report.extend(deleted),
+                    Err(e) => {
+                        errors.push(e);
+                        all_entries = false;
+                    }
+                }
+            }
+            if !all_entries {
+                for (key, value) in &previous_entries {
+                    entries.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            write_entries(&entries_path, &entries)
+                .map_err(|e| Error::filesystem(&entries_path, e))?;
+            let todo_hooks = in_solution_order(todo_hooks);
+
+            // with a failed solution, who knows what else would be referenced
+            if errors.is_empty() {
+                for name in opts.revisions.keys() {
+                    let path = current_dir.join(name).absolutize()?.to_path_buf();
+                    if !referenced_paths.contains(&path) {
+                        println!("warning: --revision for {}, which is not synced", name);
+                    }
+                }
+            }
+
+            if delete_unversioned_trees_ && errors.is_empty() {
+                for solution in &dotgclient.solutions {
+                    let solution_dir = current_dir.join(&solution.name).absolutize()?.to_path_buf();
+                    if !solution_dir.is_dir() {
+                        continue;
+                    }
+                    let trees = find_unversioned_trees(&solution_dir, &referenced_paths)?;
+                    for deleted in delete_unversioned_trees(&trees, &opts)? {
+                        let name = deleted
+                            .strip_prefix(&current_dir)
+                            .unwrap_or(&deleted)
+                            .to_string_lossy()
+                            .to_string();
+                        sync_report.push(SyncReportEntry::new(
+                            name,
+                            deleted,
+                            None,
+                            SyncAction::Deleted,
+                        ));
+                    }
+                }
+            } else if delete_unversioned_trees_ {
+                println!("not deleting unversioned trees, since the sync failed");
+            }
+
+            if errors.is_empty() {
+                for (solution_hooks_cwd, hooks) in todo_hooks {
+                    if let Err(e) = run_hooks(&hooks, solution_hooks_cwd, &opts) {
+                        errors.push(e);
+                        break;
+                    }
+                }
+            } else if !todo_hooks.is_empty() {
+                println!("not running hooks, since the sync failed");
+            }
+
+            if let Some(output_json) = output_json {
+                write_sync_report(output_json, &sync_report)?;
+            }
+
+            if !errors.is_empty() {
+                eprintln!("\n{} failure(s):", errors.len());
+                for e in &errors {
+                    eprintln!("- {}", e);
+                }
+                bail!("sync failed");
+            }
+        }
+        Commands::Runhooks => {
+            let current_dir = current_dir()?;
+
+            let dotgclient_location = current_dir.join(cli.gclient_file);
+            let dotgclient = load_dotgclient(&dotgclient_location)?;
+
+            runhooks(
+                &current_dir,
+                &dotgclient,
+                &SyncOptions {
+                    verbosity,
+                    ..Default::default()
+                },
+            )?;
+        }
+        Commands::Revinfo {
+            actual,
+            output_json,
+        } => {
+            let current_dir = current_dir()?;
+
+            let dotgclient_location = current_dir.join(cli.gclient_file);
+            let dotgclient = load_dotgclient(&dotgclient_location)?;
+
+            let opts = SyncOptions {
+                verbosity,
+                cipd_service_url: cipd::common::service_url(dotgclient.cipd_service_url.as_deref()),
+                ..Default::default()
+            };
+            let mut entries = vec![];
+            let mut todo_solutions = dotgclient.solutions.clone();
+            let mut solution_num = 0;
+            while solution_num < todo_solutions.len() {
+                let solution = todo_solutions[solution_num].clone();
+                solution_num += 1;
+
+                entries.extend(solution_revinfo(&current_dir, &solution, actual)?);
+                let (_, spec) = read_solution_deps(&current_dir, &solution, &dotgclient)?;
+                todo_solutions.extend(recursedeps_solutions(&spec, &solution));
+                entries.extend(
+                    deps_revinfo(&spec, &current_dir, &solution, &dotgclient, actual, &opts)
+                        .await?,
+                );
+            }
+
+            entries.sort_by_cached_key(|e| e.path.clone());
+            for entry in &entries {
+                println!("{}", entry.to_line());
+            }
+            if let Some(output_json) = output_json {
+                fs::write(&output_json, revinfo_json(&entries)?)
+                    .with_context(|| format!("writing revinfo json: {:?}", output_json))?;
+            }
+        }
+        Commands::Flatten {
+            output_deps,
+            output_deps_files,
+        } => {
+            let current_dir = current_dir()?;
+
+            let dotgclient_location = current_dir.join(cli.gclient_file);
+            let dotgclient = load_dotgclient(&dotgclient_location)?;
+
+            let opts = SyncOptions {
+                verbosity,
+                cipd_service_url: cipd::common::service_url(dotgclient.cipd_service_url.as_deref()),
+                ..Default::default()
+            };
+            let mut flat_deps = FlatDeps::default();
+            let mut todo_solutions = dotgclient.solutions.clone();
+            let mut solution_num = 0;
+            while solution_num < todo_solutions.len() {
+                let solution = todo_solutions[solution_num].clone();
+                solution_num += 1;
+
+                // like its condition is false, or it's not synced yet
+                if solution.tpot_internal_from_recursedeps
+                    && !current_dir.join(&solution.name).exists()
+                {
+                    continue;
+                }
+                flat_deps.add_solution(&current_dir, &solution)?;
+                let (deps_file_location, spec) =
+                    read_solution_deps(&current_dir, &solution, &dotgclient)?;
+                todo_solutions.extend(recursedeps_solutions(&spec, &solution));
+                let deps_file = deps_file_location
+                    .strip_prefix(&current_dir)
+                    .unwrap_or(&deps_file_location)
+                    .to_string_lossy();
+                flat_deps
+                    .add_spec(
+                        &current_dir,
                         &spec,
-                        if spec.use_relative_paths {
-                            current_dir.join(deps_file_location.parent().unwrap())
-                        } else {
-                            current_dir.clone()
-                        },
                         &solution,
                         &dotgclient,
-                        SyncOptions {
-                            no_history,
-                            jobs,
-                            verbosity,
-                            cipd_ignore_platformed,
-                            ..Default::default()
-                        },
+                        &deps_file,
+                        &opts,
                     )
                     .await?;
-                }
-                done_solutions.extend(tbd_solutions.iter().map(|s| s.0));
+            }
+
+            match output_deps {
+                Some(output_deps) => fs::write(&output_deps, flat_deps.to_deps_file())
+                    .with_context(|| format!("writing flattened DEPS: {:?}", output_deps))?,
+                None => print!("{}", flat_deps.to_deps_file()),
+            }
+            if let Some(output_deps_files) = output_deps_files {
+                fs::write(
+                    &output_deps_files,
+                    serde_json::to_string_pretty(&flat_deps.deps_files())?,
+                )
+                .with_context(|| format!("writing deps files json: {:?}", output_deps_files))?;
             }
         }
+        Commands::Getdep {
+            vars,
+            revisions,
+            deps_file: deps_file_location,
+        } => {
+            let deps_file = fs::read_to_string(&deps_file_location)
+                .with_context(|| format!("cannot read file: {:?}", &deps_file_location))?;
+            for var in vars {
+                println!("{}", get_var(&deps_file, &var)?);
+            }
+            for dep_path in revisions {
+                println!("{}", get_revision(&deps_file, &dep_path)?);
+            }
+        }
+        Commands::Setdep {
+            vars,
+            revisions,
+            deps_file: deps_file_location,
+        } => {
+            let mut deps_file = fs::read_to_string(&deps_file_location)
+                .with_context(|| format!("cannot read file: {:?}", &deps_file_location))?;
+            for var in vars {
+                let (name, value) = var
+                    .split_once('=')
+                    .with_context(|| format!("--var must be name=value, got {:?}", var))?;
+                deps_file = set_var(&deps_file, name, value)?;
+            }
+            for revision in revisions {
+                let (dep_path, rev) = revision
+                    .split_once('@')
+                    .with_context(|| format!("--revision must be path@rev, got {:?}", revision))?;
+                deps_file = set_revision(&deps_file, dep_path, rev)?;
+            }
+            fs::write(&deps_file_location, deps_file)
+                .with_context(|| format!("writing file: {:?}", &deps_file_location))?;
+        }
         Commands::Config { spec: maybe_spec } => {
-            let dotgclient_location = current_dir().unwrap().join(cli.gclient_file);
+            let dotgclient_location = current_dir()?.join(cli.gclient_file);
             if let Some(spec) = maybe_spec {
-                fs::write(&dotgclient_location, spec).expect("saving .gclient");
+                fs::write(&dotgclient_location, spec)
+                    .with_context(|| format!("writing file: {:?}", &dotgclient_location))?;
             } else {
                 // display (out of original gclient spec, but fuck it)
-                let dotgclient = read_dotgclient(
-                    fs::read_to_string(&dotgclient_location)
-                        .with_context(|| format!("cannot read file: {:?}", &dotgclient_location))
-                        .unwrap(),
-                )
-                .expect("parsing .gclient");
+                let dotgclient = load_dotgclient(&dotgclient_location)?;
                 println!("{:#?}", dotgclient);
             }
         }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use teapot_tools::gclient::cloner::SyncOptions;
+    use teapot_tools::test_dir::TestDir;
+    use teapot_tools::types::dotgclient::{Dotgclient, Solution};
+
+    use super::{in_solution_order, parse_revisions, ready_solutions, runhooks};
+
+    fn solution(order: &[usize], dir: &str) -> (Vec<usize>, PathBuf) {
+        (order.to_vec(), PathBuf::from("/w").join(dir))
+    }
+
+    #[test]
+    fn test_ready_solutions() {
+        // side by side, they all start at once
+        let pending = [solution(&[0], "src"), solution(&[1], "other")];
+        assert_eq!(ready_solutions(&pending, &[]), [0, 1]);
+
+        // nested solutions take turns, in either direction
+        let pending = [
+            solution(&[0], "src"),
+            solution(&[1], "src/v8"),
+            solution(&[2], "other"),
+        ];
+        assert_eq!(ready_solutions(&pending, &[]), [0, 2]);
+        let running = [solution(&[1], "src/v8")];
+        assert_eq!(ready_solutions(&pending[..1], &running), [] as [usize; 0]);
+
+        // a recursedep doesn't wait for the solution it's from (nor its ancestors),
+        // just for what it isn't part of
+        let running = [
+            solution(&[0], "src"),
+            solution(&[0, 0], "src/v8"),
+            solution(&[1], "src/third_party/x"),
+        ];
+        let pending = [
+            solution(&[0, 0, 0], "src/v8/third_party/y"),
+            solution(&[0, 1], "src/third_party"),
+            solution(&[0, 2], "src/buildtools"),
+        ];
+        assert_eq!(ready_solutions(&pending, &running), [0, 2]);
+    }
+
+    #[test]
+    fn test_in_solution_order() {
+        // as they finished
+        let finished = vec![
+            (vec![1, 0], "1.0"),
+            (vec![0, 1], "0.1"),
+            (vec![1], "1"),
+            (vec![0, 0, 0], "0.0.0"),
+            (vec![0], "0"),
+            (vec![0, 0], "0.0"),
+        ];
+        assert_eq!(
+            in_solution_order(finished),
+            ["0", "1", "0.0", "0.1", "1.0", "0.0.0"]
+        );
+    }
+
+    #[test]
+    fn test_parse_revisions() {
+        let dotgclient = Dotgclient {
+            solutions: vec![
+                Solution {
+                    name: "src".to_string(),
+                    ..Default::default()
+                },
+                Solution {
+                    name: "other".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let parse = |revisions: &[&str]| {
+            let revisions: Vec<String> = revisions.iter().map(|r| r.to_string()).collect();
+            parse_revisions(&revisions, &dotgclient)
+        };
+
+        let parsed = parse(&["abcd", "other@refs/heads/main", "src/v8/@1234"]).unwrap();
+        assert_eq!(parsed.len(), 3);
+        // a bare revision is for the first solution
+        assert_eq!(parsed["src"], "abcd");
+        assert_eq!(parsed["other"], "refs/heads/main");
+        assert_eq!(parsed["src/v8"], "1234");
+        // the last one wins
+        assert_eq!(parse(&["src@1", "src@2"]).unwrap()["src"], "2");
+
+        assert!(parse(&["src@"]).is_err());
+        let no_solutions = parse_revisions(&["abcd".to_string()], &Dotgclient::default());
+        assert!(no_solutions.is_err());
+    }
+
+    #[test]
+    fn test_runhooks() {
+        let root = TestDir::new("runhooks");
+        let deps = |hook: &str, recursedeps: &str| {
+            format!(
+                "deps = {{}}\nrecursedeps = [{}]\nhooks = [{{'name': '{}', 'pattern': '.', 'action': ['sh', '-c', 'echo {} >> ran']}}]\n",
+                recursedeps, hook, hook
+            )
+        };
+        for (dir, contents) in [
+            ("src", deps("src", "'src/good', 'src/off'")),
+            ("src/good", deps("good", "")),
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("DEPS"), contents).unwrap();
+        }
+        let dotgclient = Dotgclient {
+            solutions: vec![Solution {
+                name: "src".to_string(),
+                url: "https://example.com/src.git".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let opts = SyncOptions {
+            verbosity: -1,
+            ..Default::default()
+        };
+
+        // src/off's condition is false, so it's not there
+        runhooks(&root, &dotgclient, &opts).unwrap();
+        assert_eq!(fs::read_to_string(root.join("ran")).unwrap(), "src\ngood\n");
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use once_cell::sync::Lazy;
 use prost::{bytes::Bytes, DecodeError, Message};
 use reqwest::{
@@ -8,6 +8,25 @@ use reqwest::{
 
 use crate::host::{cipd_host_cpu, cipd_host_os};
 
+pub const DEFAULT_SERVICE_URL: &str = "https://chrome-infra-packages.appspot.com";
+
+/// overrides the service url from .gclient or the ensure file, same as in luci-go's cipd
+pub const SERVICE_URL_ENV: &str = "CIPD_SERVICE_URL";
+
+/// cipd backend to talk to: from the environment, or `configured`, or the default one
+pub fn service_url(configured: Option<&str>) -> String {
+    pick_service_url(std::env::var(SERVICE_URL_ENV).ok(), configured)
+}
+
+fn pick_service_url(from_env: Option<String>, configured: Option<&str>) -> String {
+    from_env
+        .filter(|url| !url.is_empty())
+        .or_else(|| configured.map(str::to_string))
+        .unwrap_or_else(|| DEFAULT_SERVICE_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
 static HTTP_HEADERS: Lazy<HeaderMap> = Lazy::new(|| {
     let mut default_headers = HeaderMap::new();
     default_headers.append(
@@ -43,14 +62,13 @@ pub static GENERIC_HTTP_CLIENT: Lazy<Client> = Lazy::new(|| {
 });
 
 pub async fn cipd_request<M: Message, R: Message, D: FnOnce(Bytes) -> Result<R, DecodeError>>(
+    service_url: &str,
     resource: &str,
     message: M,
     decoder: D,
 ) -> Result<R> {
     let res = CIPD_HTTP_CLIENT
-        .post(format!(
-            "https://chrome-infra-packages.appspot.com/prpc/{resource}"
-        ))
+        .post(format!("{service_url}/prpc/{resource}"))
         .body(message.encode_to_vec())
         .send()
         .await?;
@@ -59,8 +77,8 @@ pub async fn cipd_request<M: Message, R: Message, D: FnOnce(Bytes) -> Result<R,
         if res
             .headers()
             .get(CONTENT_TYPE)
-            .filter(|t| t.to_str().unwrap().starts_with("text/plain"))
-            .is_some()
+            .and_then(|t| t.to_str().ok())
+            .is_some_and(|t| t.starts_with("text/plain"))
         {
             bail!(
                 "cipd responded with http {}: {}",
@@ -72,12 +90,59 @@ pub async fn cipd_request<M: Message, R: Message, D: FnOnce(Bytes) -> Result<R,
         }
     }
 
-    Ok(decoder(res.bytes().await?).unwrap())
+    decoder(res.bytes().await?).with_context(|| format!("decoding the response to {}", resource))
 }
 
 pub fn fill_host_variables(source: &str) -> String {
-    return source
+    source
         .replace("${{platform}}", "${{os}}-${{arch}}")
         .replace("${{os}}", &cipd_host_os())
-        .replace("${{arch}}", &cipd_host_cpu());
+        .replace("${{arch}}", &cipd_host_cpu())
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::cipd::test_server::serve;
+    use crate::types::cipd::{PackageInstance, ResolveVersionRequest};
+
+    use super::{cipd_request, pick_service_url, DEFAULT_SERVICE_URL};
+
+    #[tokio::test]
+    async fn test_bad_response() {
+        let service_url = serve(|_, _| (200, b"\xffnot protobuf".to_vec())).await;
+        let response = cipd_request(
+            &service_url,
+            "cipd.Repository/ResolveVersion",
+            ResolveVersionRequest::default(),
+            PackageInstance::decode,
+        )
+        .await;
+        assert!(response
+            .unwrap_err()
+            .to_string()
+            .starts_with("decoding the response to cipd.Repository/ResolveVersion"));
+    }
+
+    #[test]
+    fn test_service_url() {
+        let env = || Some("https://env.example.com/".to_string());
+        let configured = Some("https://configured.example.com");
+        assert_eq!(
+            pick_service_url(env(), configured),
+            "https://env.example.com"
+        );
+        assert_eq!(pick_service_url(env(), None), "https://env.example.com");
+        assert_eq!(
+            pick_service_url(None, configured),
+            "https://configured.example.com"
+        );
+        // set, but empty
+        assert_eq!(
+            pick_service_url(Some("".to_string()), configured),
+            "https://configured.example.com"
+        );
+        assert_eq!(pick_service_url(None, None), DEFAULT_SERVICE_URL);
+    }
 }
@@ -0,0 +1,414 @@
+//! puts the files of an instance in place, the way its .cipdpkg/manifest.json says:
+//! copied, or kept in the site root with symlinks to them. exec bits and symlinks
+//! in the zip are restored. the files of every installed package are listed in
+//! the site root, so an upgrade can remove the ones the new version doesn't ship.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use super::install::CIPD_SITE_ROOT_DIR;
+
+/// in instances, with the manifest and nothing to install
+const PACKAGE_SERVICE_DIR: &str = ".cipdpkg";
+
+/// in the site root: a state file for every package, and files of symlinked ones
+const PACKAGES_DIR: &str = "pkgs";
+
+/// file type bits of a unix mode
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallMode {
+    /// files extracted right where they belong. what an empty install_mode means
+    #[default]
+    Copy,
+    /// files extracted to the site root, with symlinks to them where they belong
+    Symlink,
+}
+
+/// the part of .cipdpkg/manifest.json that matters here
+#[derive(Debug, Deserialize)]
+struct PackageManifest {
+    #[serde(default)]
+    install_mode: String,
+}
+
+/// what's installed of a package, in the site root
+#[derive(Debug, Deserialize, Serialize)]
+struct InstalledPackage {
+    package: String,
+    instance_id: String,
+    install_mode: InstallMode,
+    /// relative to the subdir, with "/" separators
+    files: Vec<String>,
+}
+
+/// state of a package in a subdir: `{site root}/.cipd/pkgs/{key}.json`,
+/// and `{site root}/.cipd/pkgs/{key}/` with its files if it's symlinked
+fn package_key(subdir: &str, package: &str) -> String {
+    sha256(format!("{}\n{}", subdir, package).as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn state_path(site_root: &Path, key: &str) -> PathBuf {
+    site_root
+        .join(CIPD_SITE_ROOT_DIR)
+        .join(PACKAGES_DIR)
+        .join(format!("{}.json", key))
+}
+
+fn read_state(path: &Path) -> Result<Option<InstalledPackage>> {
+    match fs::read_to_string(path) {
+        Ok(state) => Ok(Some(
+            serde_json::from_str(&state).with_context(|| format!("parsing {:?}", path))?,
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {:?}", path)),
+    }
+}
+
+/// a symlink in a package can't point outside of it
+fn check_symlink_target(name: &Path, target: &str) -> Result<()> {
+    let mut depth = name.components().count() as i32 - 1;
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => bail!(
+                "symlink {:?} points outside of the package: {:?}",
+                name,
+                target
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// removes whatever file or symlink is at `path`, so a new one can take its place
+fn remove_file_at(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => {
+            fs::remove_file(path).with_context(|| format!("removing {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// extracts the files of an instance to `dir`, except for .cipdpkg.
+/// returns the manifest, and the names of what was extracted
+fn extract_files(zip_file: &Path, dir: &Path) -> Result<(PackageManifest, Vec<PathBuf>)> {
+    let mut archive = ZipArchive::new(
+        File::open(zip_file)
+            .with_context(|| format!("reading cipd instance file: {:?}", zip_file))?,
+    )
+    .with_context(|| format!("parsing cipd instance file: {:?}", zip_file))?;
+
+    let mut manifest = None;
+    let mut names = vec![];
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry
+            .enclosed_name()
+            .with_context(|| format!("bad file name in cipd instance: {:?}", entry.name()))?
+            .to_path_buf();
+        if name.starts_with(PACKAGE_SERVICE_DIR) {
+            if name == Path::new(PACKAGE_SERVICE_DIR).join("manifest.json") {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                manifest = Some(
+                    serde_json::from_str::<PackageManifest>(&contents)
+                        .context("parsing .cipdpkg/manifest.json")?,
+                );
+            }
+            continue;
+        }
+        if entry.is_dir() {
+            continue;
+        }
+
+        let path = dir.join(&name);
+        fs::create_dir_all(path.parent().unwrap())
+            .with_context(|| format!("creating {:?}", path.parent()))?;
+        remove_file_at(&path)?;
+        let mode = entry.unix_mode().unwrap_or(0o644);
+        if mode & S_IFMT == S_IFLNK {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            check_symlink_target(&name, &target)?;
+            symlink(Path::new(&target), &path)
+                .with_context(|| format!("creating symlink {:?}", path))?;
+        } else {
+            let mut file = File::create(&path).with_context(|| format!("creating {:?}", path))?;
+            io::copy(&mut entry, &mut file).with_context(|| format!("extracting {:?}", path))?;
+            set_executable(&path, mode & 0o111 != 0)
+                .with_context(|| format!("setting permissions of {:?}", path))?;
+        }
+        names.push(name);
+    }
+
+    let manifest =
+        manifest.with_context(|| format!("no .cipdpkg/manifest.json in {:?}", zip_file))?;
+    Ok((manifest, names))
+}
+
+/// removes files (and directories they leave empty) of a previous install,
+/// unless they're still in `keep`
+fn remove_files(dest: &Path, files: &[String], keep: &HashSet<&str>) -> Result<()> {
+    for file in files.iter().filter(|f| !keep.contains(f.as_str())) {
+        let path = dest.join(file);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing {:?}", path));
+            }
+            _ => {}
+        }
+        // fails as soon as there's something else in the directory
+        let mut dir = path.parent();
+        while let Some(d) = dir.filter(|d| *d != dest) {
+            if fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+    Ok(())
+}
+
+/// installs an instance from `zip_file` to `subdir` of `site_root`, replacing whatever
+/// version of the package was there. blocking, run it on spawn_blocking
+pub fn deploy_instance(
+    zip_file: &Path,
+    site_root: &Path,
+    subdir: &str,
+    package: &str,
+    instance_id: &str,
+) -> Result<()> {
+    let dest = site_root.join(subdir);
+    let key = package_key(subdir, package);
+    let state_path = state_path(site_root, &key);
+    let packages_dir = state_path.parent().unwrap();
+    let previous = read_state(&state_path)?;
+
+    let instance_dir = packages_dir.join(&key).join(instance_id);
+    // leftovers of an install that didn't finish
+    remove_instance_dir(&instance_dir)?;
+    fs::create_dir_all(&instance_dir).with_context(|| format!("creating {:?}", instance_dir))?;
+    let (manifest, names) = extract_files(zip_file, &instance_dir)?;
+    let install_mode = match manifest.install_mode.as_str() {
+        // there are no symlinks to rely on there
+        _ if cfg!(windows) => InstallMode::Copy,
+        "" | "copy" => InstallMode::Copy,
+        "symlink" => InstallMode::Symlink,
+        mode => bail!("unknown install mode {:?} of {}", mode, package),
+    };
+
+    let files: Vec<String> = names
+        .iter()
+        .map(|name| {
+            name.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect();
+    match install_mode {
+        // extracted to the site root first anyway, since the manifest comes last in the zip
+        InstallMode::Copy => {
+            for name in &names {
+                let (from, to) = (instance_dir.join(name), dest.join(name));
+                fs::create_dir_all(to.parent().unwrap())
+                    .with_context(|| format!("creating {:?}", to.parent()))?;
+                remove_file_at(&to)?;
+                fs::rename(&from, &to).with_context(|| format!("moving to {:?}", to))?;
+            }
+            remove_instance_dir(&instance_dir)?;
+            // empty unless an older version is symlinked
+            let _ = fs::remove_dir(packages_dir.join(&key));
+        }
+        // relative, so the checkout can be moved
+        InstallMode::Symlink => {
+            for name in &names {
+                let link = dest.join(name);
+                let depth = Path::new(subdir).join(name).components().count() - 1;
+                let target = Path::new(&"../".repeat(depth))
+                    .join(CIPD_SITE_ROOT_DIR)
+                    .join(PACKAGES_DIR)
+                    .join(&key)
+                    .join(instance_id)
+                    .join(name);
+                fs::create_dir_all(link.parent().unwrap())
+                    .with_context(|| format!("creating {:?}", link.parent()))?;
+                remove_file_at(&link)?;
+                symlink(&target, &link).with_context(|| format!("creating symlink {:?}", link))?;
+            }
+        }
+    }
+
+    if let Some(previous) = previous {
+        let keep = files.iter().map(String::as_str).collect();
+        remove_files(&dest, &previous.files, &keep)?;
+        if previous.install_mode == InstallMode::Symlink && previous.instance_id != instance_id {
+            remove_instance_dir(&packages_dir.join(&key).join(&previous.instance_id))?;
+        }
+    }
+    let state = InstalledPackage {
+        package: package.to_string(),
+        instance_id: instance_id.to_string(),
+        install_mode,
+        files,
+    };
+    fs::write(&state_path, serde_json::to_string_pretty(&state)?)
+        .with_context(|| format!("writing {:?}", state_path))
+}
+
+fn remove_instance_dir(dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("removing {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// removes the files of a package installed to `subdir` of `site_root`.
+/// false if it wasn't installed there
+pub fn remove_package(site_root: &Path, subdir: &str, package: &str) -> Result<bool> {
+    let key = package_key(subdir, package);
+    let state_path = state_path(site_root, &key);
+    let Some(previous) = read_state(&state_path)? else {
+        return Ok(false);
+    };
+    remove_files(&site_root.join(subdir), &previous.files, &HashSet::new())?;
+    remove_instance_dir(&state_path.with_extension(""))?;
+    fs::remove_file(&state_path).with_context(|| format!("removing {:?}", state_path))?;
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::{check_symlink_target, deploy_instance, remove_package};
+    use crate::test_dir::TestDir;
+
+    /// an instance with `files` (name, contents, mode), a symlink to `bin/tool` and a manifest
+    pub(crate) fn write_instance(path: &Path, install_mode: &str, files: &[(&str, &str, u32)]) {
+        let mut zip = ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, contents, mode) in files {
+            zip.start_file(*name, FileOptions::default().unix_permissions(*mode))
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.add_symlink("tool", "bin/tool", FileOptions::default())
+            .unwrap();
+        zip.start_file(
+            ".cipdpkg/manifest.json",
+            FileOptions::default().unix_permissions(0o400),
+        )
+        .unwrap();
+        write!(
+            zip,
+            r#"{{"format_version": "1.1", "install_mode": "{install_mode}"}}"#
+        )
+        .unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn mode(path: PathBuf) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_deploy_instance() {
+        let dir = TestDir::new("deploy");
+        let root = dir.join("root");
+        let (v1, v2) = (dir.join("v1.zip"), dir.join("v2.zip"));
+        fs::create_dir_all(&root).unwrap();
+        write_instance(
+            &v1,
+            "copy",
+            &[("bin/tool", "v1", 0o755), ("share/old/data", "v1", 0o644)],
+        );
+        write_instance(&v2, "symlink", &[("bin/tool", "v2", 0o755)]);
+
+        deploy_instance(&v1, &root, "sub", "some/package", "v1").unwrap();
+        let sub = root.join("sub");
+        let v1_tool = fs::read_to_string(sub.join("tool")).unwrap();
+        let (tool_mode, data_mode) = (mode(sub.join("bin/tool")), mode(sub.join("share/old/data")));
+        let manifest_left = sub.join(".cipdpkg").exists();
+
+        // upgrading to v1 again (the manifest used to be in the way) and then to v2
+        deploy_instance(&v1, &root, "sub", "some/package", "v1").unwrap();
+        deploy_instance(&v2, &root, "sub", "some/package", "v2").unwrap();
+        let v2_tool = fs::read_to_string(sub.join("tool")).unwrap();
+        let tool_is_symlink = fs::symlink_metadata(sub.join("bin/tool"))
+            .unwrap()
+            .is_symlink();
+        let share_left = sub.join("share").exists();
+
+        let removed = remove_package(&root, "sub", "some/package").unwrap();
+        let left: Vec<_> = fs::read_dir(&sub)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        let removed_again = remove_package(&root, "sub", "some/package").unwrap();
+
+        assert_eq!(v1_tool, "v1");
+        assert_eq!((tool_mode, data_mode), (0o755, 0o644));
+        assert!(!manifest_left);
+        assert_eq!(v2_tool, "v2");
+        assert!(tool_is_symlink);
+        assert!(!share_left);
+        assert!(removed);
+        assert!(left.is_empty(), "{:?}", left);
+        assert!(!removed_again);
+    }
+
+    #[test]
+    fn test_check_symlink_target() {
+        assert!(check_symlink_target(Path::new("a/b/link"), "../c").is_ok());
+        assert!(check_symlink_target(Path::new("a/link"), "../../c").is_err());
+        assert!(check_symlink_target(Path::new("link"), "/etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! `cipd ensure`: installs the packages listed in an ensure file to a root directory.
+//! what's installed where is kept in the root's .cipd, so unchanged packages are skipped
+//! and removed ones are deleted.
+//! format: https://pkg.go.dev/go.chromium.org/luci/cipd/client/cipd/ensure
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path};
+
+use anyhow::{bail, Context, Result};
+
+use crate::host::{cipd_host_cpu, cipd_host_os};
+
+use super::common::service_url;
+use super::deploy::remove_package;
+use super::install::{install_instance, instance_id, CIPD_SITE_ROOT_DIR};
+use super::instance_cache::InstanceCache;
+
+/// subdir -> package -> instance id, in .cipd
+const INSTALLED_FILE: &str = "installed.json";
+
+/// a package line, with templates expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnsurePackage {
+    /// relative to the root, empty for the root itself
+    pub subdir: String,
+    pub package: String,
+    pub version: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EnsureFile {
+    pub service_url: Option<String>,
+    /// platforms the file is supposed to work on. informative, nothing is checked
+    pub verified_platforms: Vec<String>,
+    /// only the ones for the platform it was parsed for
+    pub packages: Vec<EnsurePackage>,
+}
+
+impl EnsureFile {
+    /// parses an ensure file for the host platform
+    pub fn parse(contents: &str) -> Result<Self> {
+        Self::parse_for(contents, &cipd_host_os(), &cipd_host_cpu())
+    }
+
+    /// parses an ensure file, with ${os} and ${arch} being these
+    pub fn parse_for(contents: &str, os: &str, arch: &str) -> Result<Self> {
+        let mut result = EnsureFile::default();
+        let mut subdir = String::new();
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            result
+                .parse_line(line, os, arch, &mut subdir)
+                .with_context(|| format!("ensure file line {}: {:?}", line_num + 1, line))?;
+        }
+        Ok(result)
+    }
+
+    fn parse_line(&mut self, line: &str, os: &str, arch: &str, subdir: &mut String) -> Result<()> {
+        let (first, rest) = match line.split_once(char::is_whitespace) {
+            Some((first, rest)) => (first, rest.trim()),
+            None => (line, ""),
+        };
+        if let Some(directive) = first.strip_prefix('$') {
+            match directive {
+                "ServiceURL" => {
+                    if self.service_url.is_some() {
+                        bail!("$ServiceURL is set twice");
+                    }
+                    if rest.is_empty() {
+                        bail!("$ServiceURL without an url");
+                    }
+                    self.service_url = Some(rest.trim_end_matches('/').to_string());
+                }
+                "VerifiedPlatform" => self
+                    .verified_platforms
+                    .extend(rest.split_whitespace().map(str::to_string)),
+                // these only change how luci-go's cipd goes about it
+                "ParanoidMode" | "ResolvedVersions" | "OverrideInstallMode" => {}
+                _ => bail!("unknown directive ${}", directive),
+            }
+        } else if let Some(setting) = first.strip_prefix('@') {
+            if setting != "Subdir" {
+                bail!("unknown setting @{}", setting);
+            }
+            *subdir = match expand_template(rest, os, arch)? {
+                Some(expanded) => normalize_subdir(&expanded)?,
+                None => bail!("@Subdir can't be limited to some platforms"),
+            };
+        } else {
+            let (package, version) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [version] => (first, version),
+                _ => bail!("expected a package and a version"),
+            };
+            let Some(package) = expand_template(package, os, arch)? else {
+                return Ok(());
+            };
+            if self
+                .packages
+                .iter()
+                .any(|p| p.subdir == *subdir && p.package == package)
+            {
+                bail!("{} is in {:?} twice", package, subdir);
+            }
+            self.packages.push(EnsurePackage {
+                subdir: subdir.clone(),
+                package,
+                version: version.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// expands ${os}, ${arch} and ${platform}. none if there's a ${var=value,...}
+/// not matching, meaning it's not for this platform
+pub fn expand_template(template: &str, os: &str, arch: &str) -> Result<Option<String>> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = start
+            + rest[start..]
+                .find('}')
+                .with_context(|| format!("unclosed ${{ in {:?}", template))?;
+        let (name, allowed) = match rest[start + 2..end].split_once('=') {
+            Some((name, allowed)) => (name, Some(allowed)),
+            None => (&rest[start + 2..end], None),
+        };
+        let value = match name {
+            "os" => os.to_string(),
+            "arch" => arch.to_string(),
+            "platform" => format!("{}-{}", os, arch),
+            _ => bail!("unknown variable ${{{}}} in {:?}", name, template),
+        };
+        if allowed.is_some_and(|allowed| !allowed.split(',').any(|a| a.trim() == value)) {
+            return Ok(None);
+        }
+        result.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(Some(result))
+}
+
+/// "a/b/" -> "a/b", and nothing going out of the root
+fn normalize_subdir(subdir: &str) -> Result<String> {
+    let mut parts = vec![];
+    for component in Path::new(subdir).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::CurDir => {}
+            _ => bail!(
+                "@Subdir must be a relative path inside the root, got {:?}",
+                subdir
+            ),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// an installed (or already there) package
+#[derive(Debug, Clone)]
+pub struct EnsuredPackage {
+    pub subdir: String,
+    pub package: String,
+    pub instance_id: String,
+}
+
+/// installs the packages of the ensure file to `root`, skipping the ones with the same instance
+/// installed already, and removes the packages that aren't in the ensure file anymore
+pub async fn ensure(
+    root: &Path,
+    ensure_file: &EnsureFile,
+    cache: &InstanceCache,
+) -> Result<Vec<EnsuredPackage>> {
+    let service_url = service_url(ensure_file.service_url.as_deref());
+    let site_root = root.join(CIPD_SITE_ROOT_DIR);
+    fs::create_dir_all(&site_root).with_context(|| format!("creating {:?}", site_root))?;
+    let installed_path = site_root.join(INSTALLED_FILE);
+    let previous: BTreeMap<String, BTreeMap<String, String>> = if installed_path.exists() {
+        serde_json::from_str(
+            &fs::read_to_string(&installed_path)
+                .with_context(|| format!("reading {:?}", installed_path))?,
+        )
+        .with_context(|| format!("parsing {:?}", installed_path))?
+    } else {
+        BTreeMap::new()
+    };
+
+    // first, so a package that took over files of a removed one keeps them
+    for (subdir, packages) in &previous {
+        for package in packages.keys() {
+            if !ensure_file
+                .packages
+                .iter()
+                .any(|p| p.subdir == *subdir && p.package == *package)
+            {
+                println!("removing {} from {:?}", package, root.join(subdir));
+                remove_package(root, subdir, package)
+                    .with_context(|| format!("removing {}", package))?;
+            }
+        }
+    }
+
+    let mut installed: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut ensured = vec![];
+    for package in &ensure_file.packages {
+        let instance = cache
+            .resolve(&service_url, &package.package, &package.version)
+            .await
+            .with_context(|| format!("resolving {} {}", package.package, package.version))?;
+        let instance_id = instance_id(
+            instance
+                .digest
+                .as_ref()
+                .context("cipd returned an instance without a digest")?,
+        )?;
+        let dest = root.join(&package.subdir);
+        let unchanged = previous
+            .get(&package.subdir)
+            .and_then(|packages| packages.get(&package.package))
+            == Some(&instance_id);
+        if !unchanged {
+            println!(
+                "installing {}:{} to {:?}",
+                package.package, instance_id, dest
+            );
+            install_instance(&service_url, &instance, cache, root, &package.subdir)
+                .await
+                .with_context(|| format!("installing {}", package.package))?;
+        }
+        installed
+            .entry(package.subdir.clone())
+            .or_default()
+            .insert(package.package.clone(), instance_id.clone());
+        ensured.push(EnsuredPackage {
+            subdir: package.subdir.clone(),
+            package: package.package.clone(),
+            instance_id,
+        });
+    }
+
+    fs::write(&installed_path, serde_json::to_string_pretty(&installed)?)
+        .with_context(|| format!("writing {:?}", installed_path))?;
+    Ok(ensured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnsureFile, EnsurePackage};
+
+    #[test]
+    fn test_parse_ensure_file() {
+        let contents = "
+# comment
+$ServiceURL https://chrome-infra-packages.appspot.com/
+$VerifiedPlatform linux-amd64 mac-arm64
+$VerifiedPlatform windows-amd64
+
+infra/tools/luci/vpython/${platform} git_revision:abc
+@Subdir bin/
+infra/3pp/tools/ninja/${os}-${arch} version:2@1.11.1
+infra/tools/mac_only/${os=mac} latest
+infra/tools/linux_only/${os=linux,windows}-${arch} latest
+";
+        let ensure_file = EnsureFile::parse_for(contents, "linux", "amd64").unwrap();
+        assert_eq!(
+            ensure_file.service_url.as_deref(),
+            Some("https://chrome-infra-packages.appspot.com")
+        );
+        assert_eq!(ensure_file.verified_platforms.len(), 3);
+        let package = |subdir: &str, package: &str, version: &str| EnsurePackage {
+            subdir: subdir.to_string(),
+            package: package.to_string(),
+            version: version.to_string(),
+        };
+        assert_eq!(
+            ensure_file.packages,
+            [
+                package(
+                    "",
+                    "infra/tools/luci/vpython/linux-amd64",
+                    "git_revision:abc"
+                ),
+                package(
+                    "bin",
+                    "infra/3pp/tools/ninja/linux-amd64",
+                    "version:2@1.11.1"
+                ),
+                package("bin", "infra/tools/linux_only/linux-amd64", "latest"),
+            ]
+        );
+
+        assert!(EnsureFile::parse_for("@Subdir ../out", "linux", "amd64").is_err());
+        assert!(EnsureFile::parse_for("some/package", "linux", "amd64").is_err());
+        assert!(EnsureFile::parse_for("a/${cpu} latest", "linux", "amd64").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::sha::{Sha1, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::types::cipd::{HashAlgorithm, InstanceDigest, PackageInstance};
+
+use super::common::GENERIC_HTTP_CLIENT;
+use super::deploy::deploy_instance;
+use super::instance_cache::InstanceCache;
+
+/// marks the root of cipd packages, so they can be told apart from other directories
+pub const CIPD_SITE_ROOT_DIR: &str = ".cipd";
+
+/// instance id as shown by cipd: the hex digest if sha1,
+/// otherwise base64 of the digest followed by the hash algorithm number
+pub fn instance_id(digest: &InstanceDigest) -> Result<String> {
+    if digest.algorithm() == HashAlgorithm::Sha1 {
+        return Ok(digest.hex_digest.clone());
+    }
+    let hex = &digest.hex_digest;
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!("not a hex digest: {:?}", hex);
+    }
+    let mut bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| format!("not a hex digest: {:?}", hex))?;
+    bytes.push(digest.algorithm as u8);
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// hashes an instance as it's downloaded, with the algorithm of its digest
+enum InstanceHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl InstanceHasher {
+    fn new(algorithm: HashAlgorithm) -> Result<Self> {
+        Ok(match algorithm {
+            HashAlgorithm::Sha1 => InstanceHasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => InstanceHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Unspecified => bail!("cipd returned a digest without a hash algorithm"),
+        })
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            InstanceHasher::Sha1(hasher) => hasher.update(bytes),
+            InstanceHasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finish_hex(self) -> String {
+        let digest = match self {
+            InstanceHasher::Sha1(hasher) => hasher.finish().to_vec(),
+            InstanceHasher::Sha256(hasher) => hasher.finish().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// downloads an instance from `url` to `file`, checking it against the digest on the way.
+/// if it doesn't match (or the download fails), the file is deleted.
+/// returns how many bytes were downloaded
+pub async fn download_instance(url: &str, digest: &InstanceDigest, file: &Path) -> Result<u64> {
+    let result = async {
+        let mut hasher = InstanceHasher::new(digest.algorithm())?;
+        let mut response = GENERIC_HTTP_CLIENT
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("downloading cipd instance: {:?}", url))?;
+        let mut out = tokio::fs::File::create(file)
+            .await
+            .with_context(|| format!("creating cipd zip: {:?}", file))?;
+        let mut downloaded_bytes = 0;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("downloading cipd instance: {:?}", url))?
+        {
+            hasher.update(&chunk);
+            out.write_all(&chunk)
+                .await
+                .with_context(|| format!("writing cipd zip: {:?}", file))?;
+            downloaded_bytes += chunk.len() as u64;
+        }
+        out.flush()
+            .await
+            .with_context(|| format!("writing cipd zip: {:?}", file))?;
+
+        let actual = hasher.finish_hex();
+        if !actual.eq_ignore_ascii_case(&digest.hex_digest) {
+            bail!(
+                "cipd instance from {:?} is corrupted: {} {} expected, got {}",
+                url,
+                digest.algorithm().as_str_name(),
+                digest.hex_digest,
+                actual
+            );
+        }
+        Ok(downloaded_bytes)
+    }
+    .await;
+    if result.is_err() && file.exists() {
+        fs::remove_file(file).with_context(|| format!("removing bad cipd zip: {:?}", file))?;
+    }
+    result
+}
+
+/// installs a resolved instance to `subdir` of `site_root`, downloading it to the cache first
+/// if it's not there. returns how many bytes were downloaded
+pub async fn install_instance(
+    service_url: &str,
+    instance: &PackageInstance,
+    cache: &InstanceCache,
+    site_root: &Path,
+    subdir: &str,
+) -> Result<u64> {
+    let digest = instance
+        .digest
+        .as_ref()
+        .context("cipd returned an instance without a digest")?;
+    let instance_id = instance_id(digest)?;
+    let (zip_file, downloaded_bytes) = cache.fetch(service_url, instance).await?;
+    let (site_root, subdir, package) = (
+        site_root.to_path_buf(),
+        subdir.to_string(),
+        instance.package.clone(),
+    );
+    tokio::task::spawn_blocking(move || {
+        deploy_instance(&zip_file, &site_root, &subdir, &package, &instance_id)
+    })
+    .await??;
+    Ok(downloaded_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use openssl::sha::Sha256;
+
+    use crate::cipd::test_server::{serve, serve_repeated};
+    use crate::test_dir::TestDir;
+    use crate::types::cipd::{HashAlgorithm, InstanceDigest};
+
+    use super::{download_instance, instance_id};
+
+    #[test]
+    fn test_instance_id() {
+        let sha256 = InstanceDigest {
+            algorithm: HashAlgorithm::Sha256 as i32,
+            hex_digest: "00".repeat(31) + "ff",
+        };
+        assert_eq!(instance_id(&sha256).unwrap(), "A".repeat(40) + "AP8C");
+
+        let sha1 = InstanceDigest {
+            algorithm: HashAlgorithm::Sha1 as i32,
+            hex_digest: "ab".repeat(20),
+        };
+        assert_eq!(instance_id(&sha1).unwrap(), "ab".repeat(20));
+    }
+
+    #[tokio::test]
+    async fn test_download_instance_digest() {
+        let url = serve(|_, _| (200, b"not really a zip".to_vec())).await;
+        let dir = TestDir::new("download");
+        let file = dir.join("instance.zip");
+        let digest = |algorithm: HashAlgorithm, hex_digest: &str| InstanceDigest {
+            algorithm: algorithm as i32,
+            hex_digest: hex_digest.to_string(),
+        };
+
+        let sha256 = digest(
+            HashAlgorithm::Sha256,
+            "d5bd64a5c43b6f8cc7f6c7897e954e58b89abfed62e851a47f842de714cca7d0",
+        );
+        assert_eq!(download_instance(&url, &sha256, &file).await.unwrap(), 16);
+        assert!(file.exists());
+        let sha1 = digest(
+            HashAlgorithm::Sha1,
+            "240db3986ff0c20e0de52ae69943b98496cb779b",
+        );
+        assert_eq!(download_instance(&url, &sha1, &file).await.unwrap(), 16);
+
+        let corrupted = digest(HashAlgorithm::Sha256, &"00".repeat(32));
+        let error = download_instance(&url, &corrupted, &file)
+            .await
+            .unwrap_err();
+        let not_found = serve(|_, _| (404, vec![])).await;
+        let not_downloaded = download_instance(&not_found, &sha256, &file).await;
+        let file_left = file.exists();
+        assert!(error.to_string().contains("corrupted"), "{}", error);
+        assert!(not_downloaded.is_err());
+        assert!(!file_left);
+    }
+
+    /// set for the process test_download_large_instance runs in on its own
+    #[cfg(target_os = "linux")]
+    const LARGE_INSTANCE_CHILD: &str = "TPOT_LARGE_INSTANCE_CHILD";
+
+    /// peak resident memory of the process, in KiB. none without /proc
+    #[cfg(target_os = "linux")]
+    fn peak_rss_kib() -> Option<u64> {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_download_large_instance() {
+        // memory is per process and the other tests run next to this one,
+        // so it's measured in a test process of its own
+        if std::env::var_os(LARGE_INSTANCE_CHILD).is_none() {
+            let child = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "cipd::install::tests::test_download_large_instance",
+                    "--nocapture",
+                ])
+                .env(LARGE_INSTANCE_CHILD, "1")
+                .output()
+                .unwrap();
+            assert!(
+                child.status.success(),
+                "{}{}",
+                String::from_utf8_lossy(&child.stdout),
+                String::from_utf8_lossy(&child.stderr)
+            );
+            return;
+        }
+        if peak_rss_kib().is_none() {
+            println!("skipping, no /proc to measure memory with");
+            return;
+        }
+        const CHUNK_SIZE: usize = 1 << 20;
+        const CHUNKS: usize = 256;
+        let chunk: Vec<u8> = (0..CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Sha256::new();
+        for _ in 0..CHUNKS {
+            hasher.update(&chunk);
+        }
+        let digest = InstanceDigest {
+            algorithm: HashAlgorithm::Sha256 as i32,
+            hex_digest: hasher
+                .finish()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        };
+        let url = serve_repeated(chunk, CHUNKS).await;
+        let dir = TestDir::new("large");
+        let file = dir.join("instance.zip");
+
+        // resets the peak to what's resident now
+        if let Err(e) = fs::write("/proc/self/clear_refs", "5") {
+            println!("skipping, can't reset peak memory: {}", e);
+            return;
+        }
+        let before = peak_rss_kib().unwrap();
+        let downloaded = download_instance(&url, &digest, &file).await;
+        let growth = peak_rss_kib().unwrap() - before;
+        let size = fs::metadata(&file).map(|m| m.len());
+        assert_eq!(downloaded.unwrap(), (CHUNK_SIZE * CHUNKS) as u64);
+        assert_eq!(size.unwrap(), (CHUNK_SIZE * CHUNKS) as u64);
+        // 256MiB downloaded, nowhere near that kept in memory
+        assert!(growth < 64 << 10, "peak memory grew by {}KiB", growth);
+    }
+}
@@ -0,0 +1,340 @@
+//! Downloaded cipd instances kept by digest and shared between checkouts, with the
+//! least recently used ones evicted above a size limit. Versions that can't change
+//! (tags and instance ids) are kept resolved too, so syncing them again needs no network.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use openssl::sha::sha256;
+use path_absolutize::Absolutize;
+
+use crate::types::cipd::{HashAlgorithm, InstanceDigest, PackageInstance};
+
+use super::common::fill_host_variables;
+use super::install::download_instance;
+use super::repository::{get_instance_url, resolve_instance};
+
+/// overrides `cipd_cache_dir` from .gclient, same as in luci-go's cipd
+pub const CACHE_DIR_ENV: &str = "CIPD_CACHE_DIR";
+
+/// overrides `cipd_cache_max_size` from .gclient, in bytes
+pub const CACHE_MAX_SIZE_ENV: &str = "TPOT_CIPD_CACHE_MAX_SIZE";
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 << 30;
+
+/// tells apart temporary files of concurrent writes
+static DOWNLOAD_NUM: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct InstanceCache {
+    pub dir: PathBuf,
+    pub max_size: u64,
+}
+
+impl Default for InstanceCache {
+    fn default() -> Self {
+        // only fails with a bad size in the environment
+        InstanceCache::configured(".", None, None).unwrap_or(InstanceCache {
+            dir: default_cache_dir(),
+            max_size: DEFAULT_MAX_SIZE,
+        })
+    }
+}
+
+/// $XDG_CACHE_HOME/teapot_tools/cipd or ~/.cache/teapot_tools/cipd,
+/// falling back to the temporary directory
+fn default_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("teapot_tools")
+        .join("cipd")
+}
+
+/// immutable versions: tags ("key:value") and instance ids
+fn is_immutable_version(version: &str) -> bool {
+    let is_instance_id = (version.len() == 40 && version.chars().all(|c| c.is_ascii_hexdigit()))
+        || (version.len() == 44
+            && version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    version.contains(':') || is_instance_id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// unique next to `path`, so concurrent syncs don't write to the same file
+fn tmp_path_for(path: &Path) -> PathBuf {
+    path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        DOWNLOAD_NUM.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// writes to a temporary file first, so readers never see half of it
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path_for(path);
+    fs::write(&tmp_path, contents).with_context(|| format!("writing {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("writing {:?}", path))
+}
+
+impl InstanceCache {
+    /// cache directory and size limit from the environment, or from .gclient, or the defaults.
+    /// relative directories are relative to `root_path`
+    pub fn configured<P: AsRef<Path>>(
+        root_path: P,
+        dotgclient_cache_dir: Option<&str>,
+        dotgclient_max_size: Option<u64>,
+    ) -> Result<Self> {
+        let dir = env::var_os(CACHE_DIR_ENV)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| dotgclient_cache_dir.map(PathBuf::from))
+            .map(|dir| {
+                let dir = root_path.as_ref().join(dir);
+                dir.absolutize().map(|d| d.to_path_buf()).unwrap_or(dir)
+            })
+            .unwrap_or_else(default_cache_dir);
+        let max_size = match env::var(CACHE_MAX_SIZE_ENV) {
+            Ok(size) if !size.is_empty() => size
+                .parse()
+                .with_context(|| format!("{} is not a number of bytes", CACHE_MAX_SIZE_ENV))?,
+            _ => dotgclient_max_size.unwrap_or(DEFAULT_MAX_SIZE),
+        };
+        Ok(InstanceCache { dir, max_size })
+    }
+
+    fn instances_dir(&self) -> PathBuf {
+        self.dir.join("instances")
+    }
+
+    fn instance_path(&self, digest: &InstanceDigest) -> Result<PathBuf> {
+        let hex_digest = &digest.hex_digest;
+        if hex_digest.is_empty() || !hex_digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("not a hex digest: {:?}", hex_digest);
+        }
+        Ok(self
+            .instances_dir()
+            .join(digest.algorithm().as_str_name().to_lowercase())
+            .join(hex_digest.to_lowercase()))
+    }
+
+    fn resolved_path(&self, service_url: &str, package: &str, version: &str) -> PathBuf {
+        let key = sha256(format!("{}\n{}\n{}", service_url, package, version).as_bytes());
+        self.dir.join("resolved").join(hex(&key))
+    }
+
+    /// resolves a version of a package, from the cache if it can't have changed since
+    pub async fn resolve(
+        &self,
+        service_url: &str,
+        package: &str,
+        version: &str,
+    ) -> Result<PackageInstance> {
+        let package = fill_host_variables(package);
+        if !is_immutable_version(version) {
+            return resolve_instance(service_url, &package, version).await;
+        }
+        let resolved_path = self.resolved_path(service_url, &package, version);
+        if let Ok(resolved) = fs::read_to_string(&resolved_path) {
+            if let Some((algorithm, hex_digest)) = resolved.trim().split_once(' ') {
+                if let Some(algorithm) = HashAlgorithm::from_str_name(algorithm) {
+                    return Ok(PackageInstance {
+                        package,
+                        digest: Some(InstanceDigest {
+                            algorithm: algorithm as i32,
+                            hex_digest: hex_digest.to_string(),
+                        }),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        let instance = resolve_instance(service_url, &package, version).await?;
+        if let Some(digest) = &instance.digest {
+            fs::create_dir_all(self.dir.join("resolved"))
+                .with_context(|| format!("creating {:?}", self.dir))?;
+            write_atomically(
+                &resolved_path,
+                format!("{} {}", digest.algorithm().as_str_name(), digest.hex_digest).as_bytes(),
+            )?;
+        }
+        Ok(instance)
+    }
+
+    /// path to the instance zip, downloaded if it's not in the cache yet.
+    /// returns how many bytes were downloaded too
+    pub async fn fetch(
+        &self,
+        service_url: &str,
+        instance: &PackageInstance,
+    ) -> Result<(PathBuf, u64)> {
+        let digest = instance
+            .digest
+            .as_ref()
+            .context("cipd returned an instance without a digest")?;
+        let path = self.instance_path(digest)?;
+        // the modification time is what's least recently used. best effort,
+        // the cache may be read-only or belong to someone else
+        match File::open(&path) {
+            Ok(file) => {
+                let _ = file.set_modified(SystemTime::now());
+                return Ok((path, 0));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("opening {:?}", path)),
+        }
+
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+        let tmp_path = tmp_path_for(&path);
+        let instance_url = get_instance_url(service_url, &instance.package, digest)
+            .await
+            .context("getting cipd instance url")?;
+        let downloaded_bytes = download_instance(&instance_url, digest, &tmp_path).await?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("moving to {:?}", path))?;
+        self.evict(&path)?;
+        Ok((path, downloaded_bytes))
+    }
+
+    /// removes the least recently used instances until the cache fits in its size limit,
+    /// except for `keep`
+    fn evict(&self, keep: &Path) -> Result<()> {
+        let mut instances = vec![];
+        let mut total_size = 0;
+        for algorithm_dir in fs::read_dir(self.instances_dir())? {
+            for entry in fs::read_dir(algorithm_dir?.path())? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    continue;
+                }
+                // might have been evicted by another sync just now
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                total_size += metadata.len();
+                instances.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+        instances.sort();
+        for (_, size, path) in instances {
+            if total_size <= self.max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total_size -= size,
+                Err(e) if e.kind() == ErrorKind::NotFound => total_size -= size,
+                Err(e) => return Err(e).with_context(|| format!("evicting {:?}", path)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use crate::test_dir::TestDir;
+    use crate::types::cipd::{HashAlgorithm, InstanceDigest, PackageInstance};
+
+    use super::{is_immutable_version, InstanceCache};
+
+    #[test]
+    fn test_immutable_versions() {
+        assert!(is_immutable_version("version:2@1.11.1.chromium.6"));
+        assert!(is_immutable_version(
+            "c64ba943bcce4b54d9ea87479b95b308bfb0ed69"
+        ));
+        assert!(is_immutable_version(
+            "6xWNI9xB2w2d9yQsWm_oIY8BRqdyiJ_0jbjxmvsL-pwC"
+        ));
+        assert!(!is_immutable_version("latest"));
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = TestDir::new("cipd_cache");
+        let cache = InstanceCache {
+            dir: dir.to_path_buf(),
+            max_size: 250,
+        };
+        let sha256_dir = cache.instances_dir().join("sha256");
+        fs::create_dir_all(&sha256_dir).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("old", 30), ("older", 60), ("new", 0), ("kept", 90)] {
+            let path = sha256_dir.join(name);
+            fs::write(&path, [0; 100]).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+
+        cache.evict(&sha256_dir.join("kept")).unwrap();
+        let mut left: Vec<_> = fs::read_dir(&sha256_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["kept", "new"]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_without_network() {
+        let dir = TestDir::new("cipd_cached");
+        let cache = InstanceCache {
+            dir: dir.to_path_buf(),
+            max_size: 1 << 20,
+        };
+        let digest = InstanceDigest {
+            algorithm: HashAlgorithm::Sha256 as i32,
+            hex_digest: "ab".repeat(32),
+        };
+        // nothing listens there
+        let service_url = "http://127.0.0.1:9";
+        let resolved_path = cache.resolved_path(service_url, "no/such/package", "version:1");
+        fs::create_dir_all(resolved_path.parent().unwrap()).unwrap();
+        fs::write(&resolved_path, format!("SHA256 {}", digest.hex_digest)).unwrap();
+        let instance_path = cache.instance_path(&digest).unwrap();
+        fs::create_dir_all(instance_path.parent().unwrap()).unwrap();
+        fs::write(&instance_path, "zip").unwrap();
+        let mut permissions = fs::metadata(&instance_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&instance_path, permissions).unwrap();
+
+        let resolved = cache
+            .resolve(service_url, "no/such/package", "version:1")
+            .await;
+        let fetched = cache
+            .fetch(
+                service_url,
+                &PackageInstance {
+                    package: "no/such/package".to_string(),
+                    digest: Some(digest.clone()),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert_eq!(resolved.unwrap().digest, Some(digest));
+        assert_eq!(fetched.unwrap(), (instance_path, 0));
+    }
+}
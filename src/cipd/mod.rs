@@ -1,2 +1,8 @@
 pub mod common;
+pub mod deploy;
+pub mod ensure;
+pub mod install;
+pub mod instance_cache;
 pub mod repository;
+#[cfg(test)]
+pub(crate) mod test_server;
@@ -4,14 +4,24 @@ use prost::Message;
 use crate::types::cipd::GetInstanceUrlRequest;
 use crate::types::cipd::InstanceDigest;
 use crate::types::cipd::InstanceUrl;
+use crate::types::cipd::ListInstancesRequest;
+use crate::types::cipd::ListInstancesResponse;
 use crate::types::cipd::PackageInstance;
 use crate::types::cipd::ResolveVersionRequest;
 
 use super::common::cipd_request;
 use super::common::fill_host_variables;
 
-pub async fn resolve_instance(package: &str, tag: &str) -> Result<PackageInstance> {
+/// the backend doesn't return more than that at once
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub async fn resolve_instance(
+    service_url: &str,
+    package: &str,
+    tag: &str,
+) -> Result<PackageInstance> {
     cipd_request(
+        service_url,
         "cipd.Repository/ResolveVersion",
         ResolveVersionRequest {
             package: fill_host_variables(package),
@@ -22,8 +32,13 @@ pub async fn resolve_instance(package: &str, tag: &str) -> Result<PackageInstanc
     .await
 }
 
-pub async fn get_instance_url(package: &str, digest: &InstanceDigest) -> Result<String> {
+pub async fn get_instance_url(
+    service_url: &str,
+    package: &str,
+    digest: &InstanceDigest,
+) -> Result<String> {
     cipd_request(
+        service_url,
         "cipd.Repository/GetInstanceURL",
         GetInstanceUrlRequest {
             package: package.to_string(),
@@ -35,43 +50,115 @@ pub async fn get_instance_url(package: &str, digest: &InstanceDigest) -> Result<
     .map(|i| i.url)
 }
 
+/// most recent instances first, at most `limit` of them
+pub async fn list_instances(
+    service_url: &str,
+    package: &str,
+    limit: usize,
+) -> Result<Vec<PackageInstance>> {
+    let package = fill_host_variables(package);
+    let mut instances = Vec::new();
+    let mut page_token = String::new();
+    while instances.len() < limit {
+        let page = cipd_request(
+            service_url,
+            "cipd.Repository/ListInstances",
+            ListInstancesRequest {
+                package: package.clone(),
+                page_size: (limit - instances.len()).min(MAX_PAGE_SIZE) as i32,
+                page_token,
+            },
+            ListInstancesResponse::decode,
+        )
+        .await?;
+        instances.extend(page.instance);
+        if page.next_page_token.is_empty() {
+            break;
+        }
+        page_token = page.next_page_token;
+    }
+    instances.truncate(limit);
+    Ok(instances)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::types::cipd::InstanceDigest;
+    use prost::Message;
+
+    use crate::cipd::test_server::serve;
+    use crate::types::cipd::{
+        GetInstanceUrlRequest, InstanceDigest, InstanceUrl, ListInstancesRequest,
+        ListInstancesResponse, PackageInstance, ResolveVersionRequest,
+    };
 
-    use super::{get_instance_url, resolve_instance};
+    use super::{get_instance_url, list_instances, resolve_instance};
+
+    const PACKAGE: &str = "dart/third_party/flutter/devtools";
+    const HEX_DIGEST: &str = "c64ba943bcce4b54d9ea87479b95b308bfb0ed699c87aa55fb0bfe15b94e7b66";
 
     #[tokio::test]
     async fn test_resolve_instance() {
-        let package = "dart/third_party/flutter/devtools";
+        let service_url = serve(|path, body| {
+            let request = ResolveVersionRequest::decode(body).unwrap();
+            if path != "/prpc/cipd.Repository/ResolveVersion" || request.package != PACKAGE {
+                return (404, vec![]);
+            }
+            assert_eq!(
+                request.tag,
+                "git_revision:40aae5e5ea2118e2b6dee8a8a20f166f7cec4270"
+            );
+            let instance = PackageInstance {
+                package: request.package,
+                digest: Some(InstanceDigest {
+                    algorithm: 2,
+                    hex_digest: HEX_DIGEST.to_string(),
+                }),
+                publisher: "user:kenzieschmoll@google.com".to_string(),
+            };
+            (200, instance.encode_to_vec())
+        })
+        .await;
         let solve = resolve_instance(
-            package,
+            &service_url,
+            PACKAGE,
             "git_revision:40aae5e5ea2118e2b6dee8a8a20f166f7cec4270",
         )
         .await
         .unwrap();
 
-        assert_eq!(solve.package, package);
+        assert_eq!(solve.package, PACKAGE);
 
         let digest = solve.digest.unwrap();
         assert_eq!(digest.algorithm, 2); // sha256, why are enums broken?
-        assert_eq!(
-            digest.hex_digest,
-            "c64ba943bcce4b54d9ea87479b95b308bfb0ed699c87aa55fb0bfe15b94e7b66"
-        );
+        assert_eq!(digest.hex_digest, HEX_DIGEST);
 
         assert_eq!(solve.publisher, "user:kenzieschmoll@google.com");
+
+        let not_found = resolve_instance(&service_url, "no/such/package", "latest").await;
+        assert!(not_found.unwrap_err().to_string().contains("http 404"));
     }
 
     #[tokio::test]
     async fn test_get_url() {
-        let package = "dart/third_party/flutter/devtools";
+        let service_url = serve(|path, body| {
+            assert_eq!(path, "/prpc/cipd.Repository/GetInstanceURL");
+            let request = GetInstanceUrlRequest::decode(body).unwrap();
+            let digest = request.digest.unwrap();
+            let url = InstanceUrl {
+                url: format!(
+                    "https://storage.googleapis.com/chrome-infra-packages/store/SHA256/{}",
+                    digest.hex_digest
+                ),
+            };
+            (200, url.encode_to_vec())
+        })
+        .await;
         let solve = get_instance_url(
-            package,
+            &service_url,
+            PACKAGE,
             &InstanceDigest {
                 algorithm: 2,
-                hex_digest: "c64ba943bcce4b54d9ea87479b95b308bfb0ed699c87aa55fb0bfe15b94e7b66"
-                    .to_string(),
+                hex_digest: HEX_DIGEST.to_string(),
             },
         )
         .await
@@ -79,4 +166,48 @@ mod tests {
 
         assert!(solve.starts_with("https://storage.googleapis.com/chrome-infra-packages/store/SHA256/c64ba943bcce4b54d9ea87479b95b308bfb0ed699c87aa55fb0bfe15b94e7b66"));
     }
+
+    #[tokio::test]
+    async fn test_list_instances() {
+        // 5 instances, pages of at most 2, whatever was asked for
+        let service_url = serve(|path, body| {
+            assert_eq!(path, "/prpc/cipd.Repository/ListInstances");
+            let request = ListInstancesRequest::decode(body).unwrap();
+            assert_eq!(request.package, PACKAGE);
+            assert!(request.page_size > 0);
+            let start: usize = request.page_token.parse().unwrap_or(0);
+            let end = (start + (request.page_size as usize).min(2)).min(5);
+            let response = ListInstancesResponse {
+                instance: (start..end)
+                    .map(|i| PackageInstance {
+                        package: request.package.clone(),
+                        publisher: format!("user:{}", i),
+                        ..Default::default()
+                    })
+                    .collect(),
+                next_page_token: if end < 5 {
+                    end.to_string()
+                } else {
+                    String::new()
+                },
+            };
+            (200, response.encode_to_vec())
+        })
+        .await;
+        let publishers = |limit| {
+            let service_url = service_url.clone();
+            async move {
+                list_instances(&service_url, PACKAGE, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|i| i.publisher)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(publishers(3).await, ["user:0", "user:1", "user:2"]);
+        assert_eq!(publishers(20).await.len(), 5);
+        assert!(publishers(0).await.is_empty());
+    }
 }
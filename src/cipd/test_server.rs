@@ -0,0 +1,103 @@
+//! a local http stand-in for the cipd backend and its storage, for tests
+
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// answers every request with what `handler` makes of its path and body (status, body).
+/// returns the base url, like "http://127.0.0.1:12345"
+pub async fn serve<H>(handler: H) -> String
+where
+    H: Fn(&str, &[u8]) -> (u16, Vec<u8>) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    accept(move |mut stream| {
+        let handler = handler.clone();
+        async move {
+            let (path, body) = read_request(&mut stream).await;
+            let (status, response) = handler(&path, &body);
+            let stream = stream.get_mut();
+            write_head(stream, status, response.len() as u64).await;
+            stream.write_all(&response).await.unwrap();
+        }
+    })
+    .await
+}
+
+/// answers every request with `chunk` repeated `times`, without ever having all of it in memory
+pub async fn serve_repeated(chunk: Vec<u8>, times: usize) -> String {
+    let chunk = Arc::new(chunk);
+    accept(move |mut stream| {
+        let chunk = chunk.clone();
+        async move {
+            read_request(&mut stream).await;
+            let stream = stream.get_mut();
+            write_head(stream, 200, (chunk.len() * times) as u64).await;
+            for _ in 0..times {
+                // the client hanging up early is fine
+                if stream.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+    .await
+}
+
+async fn accept<C, F>(connection: C) -> String
+where
+    C: Fn(BufReader<TcpStream>) -> F + Send + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(connection(BufReader::new(stream)));
+        }
+    });
+    url
+}
+
+/// path and body of a request
+async fn read_request(stream: &mut BufReader<TcpStream>) -> (String, Vec<u8>) {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await.unwrap();
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        stream.read_line(&mut header).await.unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.unwrap();
+    (path, body)
+}
+
+async fn write_head(stream: &mut TcpStream, status: u16, content_length: u64) {
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status, content_length
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+}
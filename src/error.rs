@@ -0,0 +1,119 @@
+//! What can go wrong while syncing, by where it went wrong,
+//! so failures of independent dependencies can be collected and summarized.
+//! the details (with their context) are kept in the anyhow sources.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    /// cloning, fetching or checking out, in a checkout or a git cache mirror
+    Git {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// resolving, downloading or extracting a cipd package
+    Cipd {
+        package: String,
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// downloading from google storage
+    Gcs {
+        url: String,
+        source: anyhow::Error,
+    },
+    /// reading or evaluating DEPS, .gclient or .gclient_entries
+    DepsParse {
+        file: PathBuf,
+        source: anyhow::Error,
+    },
+    /// `condition` of a dependency, a hook or a gn arg
+    Condition {
+        name: String,
+        source: anyhow::Error,
+    },
+    Filesystem {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    Hook {
+        name: String,
+        source: anyhow::Error,
+    },
+}
+
+impl Error {
+    pub fn git<P: AsRef<Path>, E: Into<anyhow::Error>>(path: P, source: E) -> Self {
+        Error::Git {
+            path: path.as_ref().to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    pub fn cipd<P: AsRef<Path>, E: Into<anyhow::Error>>(package: &str, path: P, source: E) -> Self {
+        Error::Cipd {
+            package: package.to_string(),
+            path: path.as_ref().to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    pub fn gcs<E: Into<anyhow::Error>>(url: &str, source: E) -> Self {
+        Error::Gcs {
+            url: url.to_string(),
+            source: source.into(),
+        }
+    }
+
+    pub fn deps_parse<P: AsRef<Path>, E: Into<anyhow::Error>>(file: P, source: E) -> Self {
+        Error::DepsParse {
+            file: file.as_ref().to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    pub fn condition<E: Into<anyhow::Error>>(name: &str, source: E) -> Self {
+        Error::Condition {
+            name: name.to_string(),
+            source: source.into(),
+        }
+    }
+
+    pub fn filesystem<P: AsRef<Path>, E: Into<anyhow::Error>>(path: P, source: E) -> Self {
+        Error::Filesystem {
+            path: path.as_ref().to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    pub fn hook<E: Into<anyhow::Error>>(name: &str, source: E) -> Self {
+        Error::Hook {
+            name: name.to_string(),
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // sources are printed here, not returned from source(), so one line has it all
+        match self {
+            Error::Git { path, source } => write!(f, "git, in {:?}: {:#}", path, source),
+            Error::Cipd {
+                package,
+                path,
+                source,
+            } => write!(f, "cipd, {} in {:?}: {:#}", package, path, source),
+            Error::Gcs { url, source } => write!(f, "google storage, {}: {:#}", url, source),
+            Error::DepsParse { file, source } => write!(f, "parsing {:?}: {:#}", file, source),
+            Error::Condition { name, source } => {
+                write!(f, "condition of {}: {:#}", name, source)
+            }
+            Error::Filesystem { path, source } => write!(f, "{:?}: {:#}", path, source),
+            Error::Hook { name, source } => write!(f, "hook {}: {:#}", name, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,26 +1,35 @@
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{fs, path::Path, process::Command};
 
-use anyhow::{bail, Context, Result};
-use futures::future::try_join_all;
+use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
 use linya::{Bar, Progress};
+use once_cell::sync::Lazy;
 use path_absolutize::*;
-use pyo3::types::PyBool;
-use pyo3::Python;
 use smart_default::SmartDefault;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use url::Url;
-use zip::ZipArchive;
 
-use crate::cipd::common::GENERIC_HTTP_CLIENT;
-use crate::cipd::repository::{get_instance_url, resolve_instance};
+use crate::cipd::common::DEFAULT_SERVICE_URL;
+use crate::cipd::deploy::remove_package;
+use crate::cipd::install::{install_instance, instance_id, CIPD_SITE_ROOT_DIR};
+use crate::cipd::instance_cache::InstanceCache;
+use crate::error::Error;
+use crate::gclient::git_cache::{add_alternate, update_mirror};
 use crate::gclient::gn_args::generate_gn_args;
-use crate::gclient::var_utils::{set_builtin_vars, set_vars_from_hashmap};
-use crate::types::deps::{CacheKVList, Dependency, DependencyDef, DepsSpec};
+use crate::gclient::revinfo::git_head;
+use crate::gclient::sync_report::{SyncAction, SyncReportEntry};
+use crate::gclient::var_utils::SpecVars;
+use crate::types::deps::{CacheKVList, CipdPackage, Dependency, DependencyDef, DepsSpec};
 use crate::types::dotgclient::{Dotgclient, Solution};
 
-use super::entries_cache::{path_to_entries_cache, read_entries};
+use super::entries_cache::EntriesCache;
 
 #[derive(Debug, SmartDefault, Clone)]
 pub struct SyncOptions {
@@ -36,181 +45,339 @@ pub struct SyncOptions {
     #[default = 0]
     pub verbosity: i8,
 
+    /// update every dependency, even the ones unchanged since the last sync,
+    /// and git checkouts with local changes
+    #[default = false]
+    pub force: bool,
+
+    /// discard local changes in git checkouts before updating them
+    #[default = false]
+    pub reset: bool,
+
     #[default = false]
     pub cipd_ignore_platformed: bool,
+
+    /// where git mirrors are kept, see git_cache
+    pub cache_dir: Option<PathBuf>,
+
+    /// where cipd instances are kept, shared between checkouts
+    pub cipd_cache: InstanceCache,
+
+    /// cipd backend, without a trailing slash
+    #[default(_code = "DEFAULT_SERVICE_URL.to_string()")]
+    pub cipd_service_url: String,
+
+    /// solution or dependency name -> revision to sync it to instead of the one in DEPS
+    pub revisions: HashMap<String, String>,
+
+    /// shared by everything that syncs at once, so there's never more than `jobs` of it.
+    /// one slot by default
+    #[default(_code = "Arc::new(Semaphore::new(1))")]
+    pub job_slots: Arc<Semaphore>,
+
+    /// told where every dependency is once it's synced (or skipped, it's there already),
+    /// for what's in them, like the DEPS of recursedeps
+    pub synced_deps: Option<UnboundedSender<PathBuf>>,
 }
 
+/// one for every solution syncing at once, so their bars don't draw over each other
+static PROGRESS: Lazy<Mutex<Progress>> = Lazy::new(|| Mutex::new(Progress::new()));
+
 #[derive(Clone)]
 struct NumberedDependency {
     pub dep_num: usize,
-    pub tmp_path: PathBuf,
+    pub name: String,
+    /// its keys in .gclient_entries
+    pub cache_keys: Vec<String>,
+    /// some of it is in .gclient_entries already
+    pub synced_before: bool,
     pub clone_path: PathBuf,
     pub dependency: Dependency,
     pub required_num: Option<usize>,
 }
 
-pub async fn clone_dependencies<P: AsRef<Path>>(
+/// name of a dependency (or its .gclient_entries key) like gclient has it,
+/// relative to the .gclient directory
+fn dep_name(spec: &DepsSpec, solution: &Solution, clone_path: &str) -> String {
+    if spec.use_relative_paths {
+        format!("{}/{}", solution.name, clone_path)
+    } else {
+        clone_path.to_string()
+    }
+}
+
+/// dependencies from DEPS (with custom_deps applied) that match their conditions, sorted by path
+pub fn deps_matching_conditions(
     spec: &DepsSpec,
-    base_path_: P,
     solution: &Solution,
     dotgclient: &Dotgclient,
-    opts: SyncOptions,
-) -> Result<()> {
-    let base_path = base_path_.as_ref();
+    opts: &SyncOptions,
+) -> Result<Vec<(String, Dependency, CacheKVList)>, Error> {
+    let spec_deps = spec.deps_with_custom_deps(solution.custom_deps.as_ref());
 
-    let mut deps_with_contitions = Python::with_gil(|py| {
-        let mut spec_vars = spec.vars.clone();
-        if let Some(custom_vars) = solution.custom_vars.clone() {
-            spec_vars.extend(custom_vars);
-        }
-        let (globals, vars) = set_vars_from_hashmap(py, &spec_vars);
-        set_builtin_vars(&dotgclient, vars);
-        if opts.verbosity >= 2 {
-            println!("{}", vars);
-        }
+    let spec_vars = SpecVars::new(spec, solution, dotgclient);
+    if opts.verbosity >= 2 {
+        print!("{}", spec_vars);
+    }
 
-        generate_gn_args(&py, globals, vars, spec, base_path);
-
-        let mut deps: Vec<(String, Dependency, CacheKVList)> = vec![];
-        for (clone_path, dep_def) in &spec.deps {
-            match dep_def {
-                DependencyDef::Simple(_) => {
-                    let dep: Dependency = dep_def.into();
-                    deps.push((
-                        clone_path.to_owned(),
-                        dep.clone(),
-                        dep.to_cache_kv_list(clone_path),
-                    ))
-                }
-                DependencyDef::Normal(dep) => {
-                    if opts.cipd_ignore_platformed {
-                        if let Dependency::CIPD { packages, .. } = dep {
-                            if packages.iter().any(|p| p.package.contains("${{")) {
-                                continue;
-                            }
+    let mut deps: Vec<(String, Dependency, CacheKVList)> = vec![];
+    for (clone_path, dep_def) in &spec_deps {
+        match dep_def {
+            DependencyDef::Simple(_) => {
+                let dep: Dependency = dep_def.into();
+                deps.push((
+                    clone_path.to_owned(),
+                    dep.clone(),
+                    dep.to_cache_kv_list(
+                        &dep_name(spec, solution, clone_path),
+                        &opts.cipd_service_url,
+                    ),
+                ))
+            }
+            DependencyDef::Normal(dep) => {
+                if opts.cipd_ignore_platformed {
+                    if let Dependency::CIPD { packages, .. } = dep {
+                        if packages.iter().any(|p| p.package.contains("${{")) {
+                            continue;
                         }
                     }
-                    let cache_kv_list = dep.to_cache_kv_list(clone_path);
-                    let maybe_condition = match dep {
-                        Dependency::Git { url: _, condition } => condition,
-                        Dependency::CIPD {
-                            packages: _,
-                            condition,
-                        } => condition,
-                    };
-                    if let Some(condition) = maybe_condition {
-                        if opts.verbosity >= 2 {
-                            print!("{}: checking... ", clone_path);
-                        }
-                        let status = py
-                            .eval(&format!("bool({})", condition), Some(globals), Some(vars))
-                            .unwrap()
-                            .downcast::<PyBool>()
-                            .unwrap()
-                            .is_true();
-                        if opts.verbosity >= 2 {
-                            println!("{}", status);
-                        }
-                        if status == true {
-                            deps.push((clone_path.to_owned(), dep.to_owned(), cache_kv_list));
-                        }
-                    } else {
+                }
+                let cache_kv_list = dep.to_cache_kv_list(
+                    &dep_name(spec, solution, clone_path),
+                    &opts.cipd_service_url,
+                );
+                let maybe_condition = match dep {
+                    Dependency::Git { url: _, condition } => condition,
+                    Dependency::CIPD {
+                        packages: _,
+                        condition,
+                    } => condition,
+                };
+                if let Some(condition) = maybe_condition {
+                    if opts.verbosity >= 2 {
+                        print!("{}: checking... ", clone_path);
+                    }
+                    let status = spec_vars
+                        .eval_condition(condition)
+                        .map_err(|e| Error::condition(clone_path, e))?;
+                    if opts.verbosity >= 2 {
+                        println!("{}", status);
+                    }
+                    if status {
                         deps.push((clone_path.to_owned(), dep.to_owned(), cache_kv_list));
                     }
+                } else {
+                    deps.push((clone_path.to_owned(), dep.to_owned(), cache_kv_list));
                 }
             }
         }
-        deps
-    });
+    }
+
+    // --revision overrides, by the name gclient knows the dependency by
+    for (clone_path, dep, cache_kv_list) in deps.iter_mut() {
+        let name = dep_name(spec, solution, clone_path);
+        let (Some(revision), Dependency::Git { url, .. }) = (opts.revisions.get(&name), &mut *dep)
+        else {
+            continue;
+        };
+        *url = url_with_revision(url, revision).map_err(|e| Error::git(&name, e))?;
+        *cache_kv_list = dep.to_cache_kv_list(&name, &opts.cipd_service_url);
+    }
+
+    deps.sort_by_cached_key(|(clone_path, ..)| clone_path.to_owned());
+    Ok(deps)
+}
+
+fn git_report_entry(
+    name: String,
+    path: PathBuf,
+    url_spec: &str,
+    action: SyncAction,
+) -> SyncReportEntry {
+    // .gclient_entries could have anything
+    let (url, requested_revision) =
+        split_git_url(url_spec).unwrap_or_else(|_| (url_spec.to_string(), None));
+    SyncReportEntry {
+        url: Some(url),
+        requested_revision,
+        ..SyncReportEntry::new(name, path, Some("git"), action)
+    }
+}
+
+fn cipd_report_entry(
+    name: &str,
+    path: PathBuf,
+    package: &CipdPackage,
+    action: SyncAction,
+    service_url: &str,
+) -> SyncReportEntry {
+    SyncReportEntry {
+        url: Some(format!("{}/{}", service_url, package.package)),
+        requested_revision: Some(package.version.clone()),
+        ..SyncReportEntry::new(
+            format!("{}:{}", name, package.package),
+            path,
+            Some("cipd"),
+            action,
+        )
+    }
+}
+
+fn failed_report_entries(
+    dep: &NumberedDependency,
+    error: String,
+    opts: &SyncOptions,
+) -> Vec<SyncReportEntry> {
+    let entries = match &dep.dependency {
+        Dependency::Git { url, .. } => vec![git_report_entry(
+            dep.name.clone(),
+            dep.clone_path.clone(),
+            url,
+            SyncAction::Failed,
+        )],
+        Dependency::CIPD { packages, .. } => packages
+            .iter()
+            .map(|package| {
+                cipd_report_entry(
+                    &dep.name,
+                    dep.clone_path.clone(),
+                    package,
+                    SyncAction::Failed,
+                    &opts.cipd_service_url,
+                )
+            })
+            .collect(),
+    };
+    entries
+        .into_iter()
+        .map(|entry| SyncReportEntry {
+            error: Some(error.clone()),
+            ..entry
+        })
+        .collect()
+}
+
+/// syncs the dependencies of a solution, reports what happened to each of them
+/// (matching conditions), updated or not. returns the solution's part of
+/// .gclient_entries too: there's one for all solutions, in the root of the workdir,
+/// written (and cleaned up with remove_stale_entries) once they're all synced.
+///
+/// a dependency failing doesn't stop the others (except the ones inside it),
+/// its error is returned with the report. only errors with the whole DEPS fail it all
+pub async fn clone_dependencies<P: AsRef<Path>>(
+    spec: &DepsSpec,
+    base_path_: P,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+    previous_entries_cache: &EntriesCache,
+    opts: SyncOptions,
+) -> Result<(Vec<SyncReportEntry>, EntriesCache, Vec<Error>), Error> {
+    let base_path = base_path_.as_ref();
+    // for errors, that's all we know here
+    let deps_file = Path::new(&solution.name).join(solution.deps_file.as_deref().unwrap_or("DEPS"));
+
+    generate_gn_args(&SpecVars::new(spec, solution, dotgclient), spec, base_path)?;
+
+    let deps_with_contitions = deps_matching_conditions(spec, solution, dotgclient, &opts)?;
     if opts.verbosity >= 0 {
         println!(
             "{} out of {} matching conditions",
             deps_with_contitions.len(),
-            spec.deps.len()
+            spec.deps_with_custom_deps(solution.custom_deps.as_ref())
+                .len()
         );
     }
 
-    deps_with_contitions.sort_by_cached_key(|(clone_path, ..)| clone_path.to_owned());
-
-    let entries_cache_path = path_to_entries_cache(base_path);
-    let previous_entries_cache = read_entries(entries_cache_path)?;
-
-    let mut new_entries_cache = HashMap::new();
+    let mut new_entries_cache: EntriesCache = HashMap::new();
 
     // fill new cache with dependencies
     for (_, _, cache_kv_list) in &deps_with_contitions {
         for (k, v) in cache_kv_list {
-            if new_entries_cache.insert(k.clone(), v).is_some() {
-                bail!("duplicate key: {:?}", k);
+            if new_entries_cache.insert(k.clone(), v.clone()).is_some() {
+                return Err(Error::deps_parse(
+                    &deps_file,
+                    anyhow!("duplicate key: {:?}", k),
+                ));
             }
         }
     }
 
-    // delete the git repositories that were removed from spec
-    // and paths with cipd packages that were either removed or changed
-    let paths_to_delete = previous_entries_cache
-        .keys()
-        .filter(|&k| !new_entries_cache.contains_key(k))
-        .chain(
-            new_entries_cache
-                .iter()
-                // if key exists in new entries, it's cipd and value (version) changed
-                .filter(|&(k, v)| k.contains(':') && previous_entries_cache.get(k) != Some(v))
-                .map(|(k, _)| k),
-        )
-        .map(|k| {
-            // if cipd, format is "{path}:{package}". if git, it's just path
-            k.split_once(':').map(|(path, _)| path).unwrap_or(k)
-        })
-        .unique()
-        .collect_vec();
-    if opts.verbosity >= 2 {
-        println!("{} paths to delete", paths_to_delete.len());
-    }
-    for path_str in paths_to_delete {
-        let path_ = PathBuf::try_from(path_str)?;
-        let path = path_.absolutize_from(base_path)?;
-        if opts.verbosity >= 2 {
-            println!("deleting {:?}", path);
-        }
-        match fs::remove_dir_all(path) {
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-            Err(e) => return Err(e.into()),
-            Ok(()) => {}
-        }
+    // cipd zips used to be downloaded here, they're in the instance cache now
+    let tpot_cipd_path = base_path.join(".tpot_cipd");
+    match fs::remove_dir_all(&tpot_cipd_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Error::filesystem(&tpot_cipd_path, e)),
+        Ok(()) => {}
     }
 
-    let tpot_cipd_path = base_path.join(".tpot_cipd");
-    fs::create_dir_all(&tpot_cipd_path).expect("create .tpot_cipd dir");
+    let dep_paths = deps_with_contitions
+        .iter()
+        .map(|(clone_path, ..)| {
+            let abs_clone_path = base_path.join(clone_path);
+            let abs_clone_path = abs_clone_path
+                .absolutize()
+                .map_err(|e| Error::filesystem(&abs_clone_path, e))?
+                .to_path_buf();
+            if !abs_clone_path.starts_with(base_path) {
+                return Err(Error::deps_parse(
+                    &deps_file,
+                    anyhow!(
+                        "{} is outside current workdir (impostor among us)",
+                        clone_path
+                    ),
+                ));
+            }
+            Ok(abs_clone_path)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
-    // if spec didn't change, we're not updating it
-    let deps_to_update = deps_with_contitions
+    let mut report = vec![];
+    let (deps_to_update, deps_to_skip): (Vec<_>, Vec<_>) = deps_with_contitions
         .into_iter()
-        .filter(|(_, _, cache_kv_list)| {
-            cache_kv_list
-                .iter()
-                .any(|(k, v)| previous_entries_cache.get(k) != Some(v))
+        .zip(dep_paths.iter().cloned())
+        .partition(|((_, dep, cache_kv_list), _)| {
+            needs_update(dep, cache_kv_list, previous_entries_cache, &opts)
         });
 
+    for ((clone_path, dep, _), abs_clone_path) in deps_to_skip {
+        notify_synced(&opts, &abs_clone_path);
+        let name = dep_name(spec, solution, &clone_path);
+        match dep {
+            Dependency::Git { url, .. } => {
+                let entry = git_report_entry(name, abs_clone_path, &url, SyncAction::Skipped);
+                // a pinned commit is what's checked out, as far as .gclient_entries knows
+                let revision = match &entry.requested_revision {
+                    Some(rev) if is_commit_hash(rev) => Some(rev.clone()),
+                    _ => git_head(&entry.path).ok(),
+                };
+                report.push(SyncReportEntry { revision, ..entry });
+            }
+            Dependency::CIPD { packages, .. } => {
+                for package in &packages {
+                    report.push(cipd_report_entry(
+                        &name,
+                        abs_clone_path.clone(),
+                        package,
+                        SyncAction::Skipped,
+                        &opts.cipd_service_url,
+                    ));
+                }
+            }
+        }
+    }
+
     let mut dep_num = 0;
     let mut numbered_deps: Vec<NumberedDependency> = deps_to_update
         .into_iter()
-        .map(|(clone_path, dep, _)| {
+        .map(|((clone_path, dep, cache_kv_list), abs_clone_path)| {
             dep_num += 1;
-            let abs_clone_path = base_path
-                .join(&clone_path)
-                .absolutize()
-                .unwrap()
-                .to_path_buf();
-            if !abs_clone_path.starts_with(base_path) {
-                panic!(
-                    "{} is outside current workdir (impostor among us)",
-                    &clone_path
-                );
-            }
             NumberedDependency {
                 dep_num,
-                tmp_path: tpot_cipd_path.clone(),
+                name: dep_name(spec, solution, &clone_path),
+                cache_keys: cache_kv_list.iter().map(|(k, _)| k.clone()).collect(),
+                synced_before: cache_kv_list
+                    .iter()
+                    .any(|(k, _)| previous_entries_cache.contains_key(k)),
                 clone_path: abs_clone_path,
                 dependency: dep,
                 required_num: None,
@@ -218,194 +385,1018 @@ pub async fn clone_dependencies<P: AsRef<Path>>(
         })
         .collect();
 
-    for i in 1..numbered_deps.len() {
-        let (a, b) = numbered_deps.split_at_mut(i + 1);
+    link_required(&mut numbered_deps);
+    let todo_deps = numbered_deps;
+    let (dep_report, failed, errors) =
+        sync_in_order(&todo_deps, &opts, &solution.name, handle_dep).await;
+    report.extend(dep_report);
 
-        let i_dep = a.get_mut(i).unwrap();
-        for n_dep in b {
-            // if i_dep clone_path is inside another dependency, mark it as a requirement
-            if i_dep.clone_path.starts_with(&n_dep.clone_path) {
-                i_dep.required_num = Some(n_dep.dep_num);
-                break;
-            }
+    // whatever failed keeps its previous entry, so the next sync retries it
+    for dep in todo_deps.iter().filter(|d| failed.contains(&d.dep_num)) {
+        for key in &dep.cache_keys {
+            match previous_entries_cache.get(key) {
+                Some(previous) => new_entries_cache.insert(key.clone(), previous.clone()),
+                None => new_entries_cache.remove(key),
+            };
         }
     }
+    Ok((report, new_entries_cache, errors))
+}
 
-    let todo_deps = numbered_deps;
+/// if spec didn't change, we're not updating it, unless asked to.
+/// local changes are not in .gclient_entries, so reset visits every git checkout
+fn needs_update(
+    dep: &Dependency,
+    cache_kv_list: &CacheKVList,
+    previous_entries_cache: &EntriesCache,
+    opts: &SyncOptions,
+) -> bool {
+    opts.force
+        || (opts.reset && matches!(dep, Dependency::Git { .. }))
+        || cache_kv_list
+            .iter()
+            .any(|(k, v)| previous_entries_cache.get(k) != Some(v))
+}
+
+/// if a dependency is inside another one, marks it as a requirement.
+/// sorted by path, so the closest one it's in is the last one before it
+fn link_required(deps: &mut [NumberedDependency]) {
+    for i in 1..deps.len() {
+        let (before, rest) = deps.split_at_mut(i);
+        let i_dep = &mut rest[0];
+        i_dep.required_num = before
+            .iter()
+            .rev()
+            .find(|n_dep| i_dep.clone_path.starts_with(&n_dep.clone_path))
+            .map(|n_dep| n_dep.dep_num);
+    }
+}
+
+/// syncs every dependency whose enclosing one is done right away, the job slots
+/// decide how many of them actually run. the ones inside a dependency that failed
+/// are reported failed, and not synced. returns the report, and what failed
+async fn sync_in_order<F, Fut>(
+    todo_deps: &[NumberedDependency],
+    opts: &SyncOptions,
+    solution_name: &str,
+    sync: F,
+) -> (Vec<SyncReportEntry>, HashSet<usize>, Vec<Error>)
+where
+    F: Fn(NumberedDependency, SyncOptions) -> Fut,
+    Fut: Future<Output = Result<Vec<SyncReportEntry>, Error>> + Send + 'static,
+{
+    let mut report = vec![];
     let mut done: HashSet<usize> = HashSet::new();
-    let mut progress = Progress::new();
+    let mut failed: HashSet<usize> = HashSet::new();
+    let mut started: HashSet<usize> = HashSet::new();
+    let mut errors = vec![];
     // if verbosity > 0 the bar is a mess because of the other logs
     let bar: Option<Bar> = if opts.verbosity == 0 {
-        Some(progress.bar(todo_deps.len(), "fetching dependencies"))
+        Some(PROGRESS.lock().unwrap().bar(
+            todo_deps.len(),
+            format!("fetching dependencies of {}", solution_name),
+        ))
     } else {
         None
     };
-    while todo_deps.len() != done.len() {
-        let deps_cur_pass: Vec<_> = todo_deps
-            .clone()
-            .into_iter()
-            .filter(|d| {
-                !done.contains(&d.dep_num)
-                    && d.required_num.map(|r| done.contains(&r)).unwrap_or(true)
-            })
-            .take(opts.jobs)
-            .map(|dep| {
-                let opts_ = opts.clone();
-                std::thread::spawn(move || handle_dep(dep, opts_))
-            })
-            .collect();
+    let mut running = JoinSet::new();
+    loop {
+        for dep in todo_deps {
+            if started.contains(&dep.dep_num) || failed.contains(&dep.dep_num) {
+                continue;
+            }
+            match dep.required_num {
+                // no point in trying the ones inside a dependency that failed
+                Some(r) if failed.contains(&r) => {
+                    failed.insert(dep.dep_num);
+                    report.extend(failed_report_entries(
+                        dep,
+                        "not synced, a dependency it's in failed".to_string(),
+                        opts,
+                    ));
+                }
+                Some(r) if !done.contains(&r) => {}
+                _ => {
+                    started.insert(dep.dep_num);
+                    let slots = opts.job_slots.clone();
+                    let dep_num = dep.dep_num;
+                    let syncing = sync(dep.clone(), opts.clone());
+                    running.spawn(async move {
+                        let _slot = slots
+                            .acquire_owned()
+                            .await
+                            .expect("semaphore is never closed");
+                        (dep_num, syncing.await)
+                    });
+                }
+            }
+        }
+        let (dep_num, result) = match running.join_next().await {
+            Some(Ok(finished)) => finished,
+            Some(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+            None => break,
+        };
+        let dep = todo_deps.iter().find(|d| d.dep_num == dep_num).unwrap();
+        match result {
+            Ok(dep_report) => {
+                notify_synced(opts, &dep.clone_path);
+                done.insert(dep_num);
+                report.extend(dep_report);
+            }
+            Err(e) => {
+                failed.insert(dep_num);
+                report.extend(failed_report_entries(dep, e.to_string(), opts));
+                errors.push(e);
+            }
+        }
+        if let Some(bar) = &bar {
+            PROGRESS
+                .lock()
+                .unwrap()
+                .set_and_draw(bar, done.len() + failed.len());
+        }
+    }
+    (report, failed, errors)
+}
 
-        for handle in deps_cur_pass {
-            match handle.join().unwrap().await {
-                Ok(dep_num) => {
-                    done.insert(dep_num);
-                    if let Some(bar) = &bar {
-                        progress.set_and_draw(bar, done.len());
-                    }
+fn notify_synced(opts: &SyncOptions, clone_path: &Path) {
+    if let Some(synced_deps) = &opts.synced_deps {
+        // nobody waiting for it anymore is fine
+        let _ = synced_deps.send(clone_path.to_path_buf());
+    }
+}
+
+/// deletes the checkouts of `previous` .gclient_entries that no solution has anymore,
+/// unless something still synced is inside them or they have local changes (and it's
+/// not forced), and reports the ones deleted.
+/// cipd packages are removed one by one, others at the same path stay
+pub fn remove_stale_entries(
+    root_path: &Path,
+    previous: &EntriesCache,
+    current: &EntriesCache,
+    opts: &SyncOptions,
+) -> Result<Vec<SyncReportEntry>, Error> {
+    let current_paths = current
+        .keys()
+        .filter_map(|k| checkout_path(root_path, entry_path(k)).ok())
+        .collect_vec();
+    let mut report = vec![];
+    for (key, url_spec) in previous.iter().sorted() {
+        if current.contains_key(key) {
+            continue;
+        }
+        let path = match checkout_path(root_path, entry_path(key)) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("not deleting {}: {}", key, e);
+                continue;
+            }
+        };
+        let entry = git_report_entry(key.clone(), path.clone(), url_spec, SyncAction::Deleted);
+        if let Some((_, package)) = key.split_once(':') {
+            if opts.verbosity >= 2 {
+                println!("removing {} from {:?}", package, path);
+            }
+            if remove_package(&path, "", package).map_err(|e| Error::cipd(package, &path, e))? {
+                report.push(SyncReportEntry {
+                    scm: Some("cipd"),
+                    ..entry
+                });
+            }
+            continue;
+        }
+        if !path.exists() {
+            continue;
+        }
+        if let Some(inside) = current_paths.iter().find(|p| p.starts_with(&path)) {
+            if opts.verbosity >= 1 {
+                println!("keeping {:?}, {:?} is inside it", path, inside);
+            }
+            continue;
+        }
+        if path.join(".git").exists() && !opts.force {
+            match local_changes(&path) {
+                Ok(changes) if changes.is_empty() => {}
+                Ok(changes) => {
+                    eprintln!(
+                        "not deleting {:?}, it has local changes (--force deletes it anyway):\n{}",
+                        path, changes
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "not deleting {:?}, can't tell if it has local changes: {:#}",
+                        path, e
+                    );
+                    continue;
                 }
-                Err(e) => panic!("{:#?}", e),
             }
         }
+        remove_checkout(&path, opts)?;
+        report.push(entry);
     }
-    Ok(())
+    Ok(report)
 }
 
-// pub and out of handle_dep() for handling .gclient solutions
-pub fn git_clone<P: AsRef<Path>>(url_spec: &str, clone_path: P, opts: &SyncOptions) -> Result<()> {
-    let mut url_parsed = Url::parse(&url_spec).unwrap();
+/// path of a .gclient_entries key: "{path}:{package}" if cipd, just the path if git
+fn entry_path(key: &str) -> &str {
+    key.split_once(':').map(|(path, _)| path).unwrap_or(key)
+}
+
+/// `path` from .gclient_entries in `root_path`, as long as it's inside it.
+/// the entries could have anything, and we're deleting these
+fn checkout_path(root_path: &Path, path: &str) -> Result<PathBuf, Error> {
+    let joined = root_path.join(path);
+    let abs_path = joined
+        .absolutize()
+        .map_err(|e| Error::filesystem(&joined, e))?
+        .to_path_buf();
+    if !abs_path.starts_with(root_path) || abs_path == root_path {
+        return Err(Error::filesystem(
+            &joined,
+            anyhow!("not inside {:?}", root_path),
+        ));
+    }
+    Ok(abs_path)
+}
+
+fn remove_checkout(path: &Path, opts: &SyncOptions) -> Result<(), Error> {
+    if opts.verbosity >= 2 {
+        println!("deleting {:?}", path);
+    }
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::filesystem(path, e)),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// splits "{url}@{revision}" into the url and the revision, if there is one
+pub fn split_git_url(url_spec: &str) -> Result<(String, Option<String>)> {
+    let mut url_parsed =
+        Url::parse(url_spec).with_context(|| format!("parsing git url: {}", url_spec))?;
     let url_path = url_parsed.path().to_string();
-    let (git_path, git_ref) = if url_path.contains('@') {
-        let (p, r) = url_path.split_once('@').unwrap();
-        (p, Some(r))
-    } else {
-        (url_path.as_str(), None)
+    let (git_path, git_ref) = match url_path.split_once('@') {
+        Some((p, r)) => (p, Some(r.to_string())),
+        None => (url_path.as_str(), None),
     };
     url_parsed.set_path(git_path);
-    let url = url_parsed.clone().to_string();
+    Ok((url_parsed.to_string(), git_ref))
+}
 
-    // TODO: check if repository exists there in first place
-    let git_init = Command::new("git")
-        .arg("init")
-        // suppresses the warning
-        .arg("--initial-branch=master")
-        .current_dir(&clone_path)
+/// "{url}@{revision}", with the revision of `url_spec` (if any) replaced
+pub fn url_with_revision(url_spec: &str, revision: &str) -> Result<String> {
+    let (url, _) = split_git_url(url_spec)?;
+    Ok(format!("{}@{}", url, revision))
+}
+
+/// runs git in `dir`, returns its trimmed stdout
+pub(crate) fn git<P: AsRef<Path>>(dir: P, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(&dir)
         .output()
-        .expect("git init spawn");
-    if git_init.status.code() != Some(0) {
-        panic!(
-            "git init failed on {:?}, exit code: {:?}\n{}",
-            clone_path.as_ref(),
-            git_init.status.code(),
-            String::from_utf8(git_init.stderr).unwrap()
+        .context("spawning git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed on {:?}, exit code: {:?}\n{}",
+            args.join(" "),
+            dir.as_ref(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
         );
     }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
 
-    let mut git_fetch_builder = Command::new("git");
-    git_fetch_builder.arg("fetch").arg(url);
-    if let Some(gref) = git_ref {
-        git_fetch_builder.arg(gref);
-    }
-    if opts.no_history {
-        git_fetch_builder.arg("--depth=1");
+pub(crate) fn is_commit_hash(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// the full hash of `rev`, if it's a commit hash (abbreviated or not) already in the repository.
+/// anything else is a ref that could have moved, so it has to be fetched
+pub(crate) fn local_commit<P: AsRef<Path>>(repo_path: P, rev: &str) -> Option<String> {
+    if !(7..=40).contains(&rev.len()) || !rev.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
-    git_fetch_builder.arg(format!("--jobs={}", opts.git_jobs));
+    git(
+        repo_path,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", rev),
+        ],
+    )
+    .ok()
+}
 
-    let git_fetch = git_fetch_builder
-        .current_dir(&clone_path)
-        .output()
-        .expect("git fetch spawn");
-    if git_fetch.status.code() != Some(0) {
-        panic!(
-            "git fetch failed on {:?}, exit code: {:?}\n{}",
-            clone_path.as_ref(),
-            git_fetch.status.code(),
-            String::from_utf8(git_fetch.stderr).unwrap(),
-        );
+/// the commit `rev` (a branch, tag or any other ref) is at in the remote repository,
+/// without fetching anything
+pub(crate) fn remote_commit<P: AsRef<Path>>(dir: P, url: &str, rev: &str) -> Result<String> {
+    let refs = git(dir, &["ls-remote", url, rev])?;
+    let refs = refs
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect_vec();
+    // the way git resolves a short name, with tags peeled to the commit
+    [
+        rev.to_string(),
+        format!("refs/heads/{}", rev),
+        format!("refs/tags/{}^{{}}", rev),
+        format!("refs/tags/{}", rev),
+    ]
+    .iter()
+    .find_map(|name| refs.iter().find(|(_, r)| r == name))
+    .map(|(commit, _)| commit.to_string())
+    .with_context(|| format!("no {} in {}", rev, url))
+}
+
+/// local modifications of tracked files, in `git status --porcelain` format
+fn local_changes<P: AsRef<Path>>(clone_path: P) -> Result<String> {
+    git(
+        clone_path,
+        &["status", "--porcelain", "--untracked-files=no"],
+    )
+}
+
+// pub and out of handle_dep() for handling .gclient solutions
+/// brings the checkout at `clone_path` to the revision in `url_spec`, as a detached HEAD.
+/// works on existing checkouts too, and doesn't fetch commits that are already there.
+/// returns the commit it checked out
+pub fn git_clone<P: AsRef<Path>>(
+    url_spec: &str,
+    clone_path: P,
+    opts: &SyncOptions,
+) -> Result<String, Error> {
+    sync_checkout(url_spec, clone_path.as_ref(), opts)
+        .with_context(|| format!("syncing {}", url_spec))
+        .map_err(|e| Error::git(clone_path, e))
+}
+
+fn sync_checkout(url_spec: &str, clone_path: &Path, opts: &SyncOptions) -> Result<String> {
+    let (url, git_ref) = split_git_url(url_spec)?;
+    let mirror = match &opts.cache_dir {
+        Some(cache_dir) => Some(update_mirror(cache_dir, &url, git_ref.as_deref(), opts)?),
+        None => None,
+    };
+
+    let existing = clone_path.join(".git").exists();
+    if !existing {
+        // suppresses the warning
+        git(clone_path, &["init", "--initial-branch=master"])?;
+        git(clone_path, &["remote", "add", "origin", &url])?;
+    } else if git(clone_path, &["rev-parse", "--verify", "--quiet", "HEAD"]).is_ok() {
+        let changes = local_changes(clone_path)?;
+        if !changes.is_empty() {
+            if opts.reset {
+                if opts.verbosity >= 1 {
+                    println!("discarding local changes in {:?}", clone_path);
+                }
+                git(clone_path, &["reset", "--hard", "--quiet"])?;
+            } else if !opts.force {
+                bail!(
+                    "{:?} has local changes, not touching it without --force or --reset:\n{}",
+                    clone_path,
+                    changes
+                );
+            }
+        }
     }
 
-    let git_merge = Command::new("git")
-        .arg("merge")
-        .arg("FETCH_HEAD")
-        .current_dir(&clone_path)
-        .output()
-        .expect("git merge spawn");
-    if git_merge.status.code() != Some(0) {
-        anyhow::bail!(
-            "git merge failed on {:?}, exit code: {:?}\n{}",
-            clone_path.as_ref(),
-            git_merge.status.code(),
-            String::from_utf8(git_merge.stderr).unwrap(),
-        );
+    let local = git_ref
+        .as_deref()
+        .and_then(|rev| local_commit(clone_path, rev));
+    let target = match (&mirror, local) {
+        (Some((mirror_path, commit)), _) => {
+            // objects come from the mirror, nothing to fetch
+            add_alternate(clone_path, mirror_path)?;
+            commit.clone()
+        }
+        (None, Some(commit)) => {
+            if opts.verbosity >= 2 {
+                println!("{} already in {:?}, not fetching", commit, clone_path);
+            }
+            commit
+        }
+        (None, None) => {
+            let mut fetch_args = vec!["fetch".to_string(), url.clone()];
+            fetch_args.extend(git_ref.clone());
+            if opts.no_history {
+                fetch_args.push("--depth=1".to_string());
+            }
+            fetch_args.push(format!("--jobs={}", opts.git_jobs));
+            git(
+                clone_path,
+                &fetch_args.iter().map(String::as_str).collect_vec(),
+            )?;
+            git(clone_path, &["rev-parse", "FETCH_HEAD^{commit}"])?
+        }
+    };
+
+    // like gclient, never merges - just moves HEAD.
+    // forced, local changes in the way are overwritten
+    let mut checkout_args = vec!["checkout", "--quiet", "--detach"];
+    if opts.force {
+        checkout_args.push("--force");
     }
-    Ok(())
+    checkout_args.push(&target);
+    git(clone_path, &checkout_args)?;
+    Ok(target)
 }
 
 async fn handle_dep(
     NumberedDependency {
-        dep_num,
-        tmp_path,
+        name,
+        synced_before,
         clone_path,
         dependency,
         ..
     }: NumberedDependency,
     opts: SyncOptions,
-) -> anyhow::Result<usize> {
+) -> Result<Vec<SyncReportEntry>, Error> {
     // mkdir -p
-    fs::create_dir_all(&clone_path).expect("mkdir success");
+    fs::create_dir_all(&clone_path).map_err(|e| Error::filesystem(&clone_path, e))?;
 
+    let mut report = vec![];
     match dependency {
         Dependency::Git {
             url: url_spec,
             condition: _,
         } => {
             if opts.verbosity >= 1 {
-                println!("cloning {} to {}", url_spec, clone_path.to_str().unwrap());
+                println!("cloning {} to {:?}", url_spec, clone_path);
+            }
+            let started = Instant::now();
+            let action = if clone_path.join(".git").exists() {
+                SyncAction::Updated
+            } else {
+                SyncAction::Cloned
+            };
+            let commit = {
+                let (url_spec, clone_path) = (url_spec.clone(), clone_path.clone());
+                tokio::task::spawn_blocking(move || git_clone(&url_spec, &clone_path, &opts))
             }
-            git_clone(&url_spec, &clone_path, &opts)
-                .with_context(|| format!("while cloning {} to {:?}", url_spec, clone_path))?;
+            .await
+            .map_err(|e| Error::git(&clone_path, e))??;
+            let entry = git_report_entry(name, clone_path, &url_spec, action);
+            report.push(
+                SyncReportEntry {
+                    revision: Some(commit),
+                    ..entry
+                }
+                .with_duration(started.elapsed()),
+            );
         }
         Dependency::CIPD {
             packages,
             condition: _,
         } => {
-            let instances = try_join_all(
-                packages
-                    .iter()
-                    .map(|p| resolve_instance(&p.package, &p.version)),
-            )
-            .await?;
-            for instance in &instances {
-                let digest = instance.digest.clone().unwrap();
-                let zip_file = tmp_path.join(&format!("{}.zip", &digest.hex_digest));
-                let instance_url = get_instance_url(&instance.package, &digest)
+            // packages are replaced in place, so it's the entries that tell
+            let action = if synced_before {
+                SyncAction::Updated
+            } else {
+                SyncAction::Cloned
+            };
+            for package in &packages {
+                let started = Instant::now();
+                let (id, downloaded_bytes) = install_cipd_package(package, &opts, &clone_path)
                     .await
-                    .with_context(|| {
-                        format!("getting cipd instance url (clone path: {:?})", clone_path)
-                    })?;
-                fs::write(
-                    &zip_file,
-                    GENERIC_HTTP_CLIENT
-                        .get(&instance_url)
-                        .send()
-                        .await
-                        .with_context(|| format!("downloading cipd instance: {:?}", instance_url))?
-                        .bytes()
-                        .await
-                        .with_context(|| {
-                            format!("getting bytes of cipd instance: {:?}", instance_url)
-                        })?,
-                )
-                .with_context(|| format!("writing cipd zip: {:?}", zip_file))?;
-                ZipArchive::new(fs::File::open(&zip_file).expect("reading cipd instance file"))
-                    .with_context(|| format!("parsing cipd instance file: {:?}", zip_file))?
-                    .extract(&clone_path)
-                    .with_context(|| format!("extracting cipd instance to: {:?}", clone_path))?;
-                // XXX: .cipdpkg/manifest.json files have permissions set to 400, leading to
-                // OS permission errors on override attempt - every time there's
-                // more than 1 cipd package in a single directory
-                fs::remove_dir_all(clone_path.join(".cipdpkg"))?;
+                    .map_err(|e| Error::cipd(&package.package, &clone_path, e))?;
+                let entry = cipd_report_entry(
+                    &name,
+                    clone_path.clone(),
+                    package,
+                    action,
+                    &opts.cipd_service_url,
+                );
+                report.push(
+                    SyncReportEntry {
+                        revision: Some(id),
+                        downloaded_bytes: Some(downloaded_bytes),
+                        ..entry
+                    }
+                    .with_duration(started.elapsed()),
+                );
             }
+            fs::create_dir_all(clone_path.join(CIPD_SITE_ROOT_DIR))
+                .map_err(|e| Error::filesystem(&clone_path, e))?;
         }
     };
-    Ok(dep_num)
+    Ok(report)
+}
+
+/// resolves, downloads (unless cached) and extracts a cipd package to `clone_path`.
+/// returns the instance id, and how many bytes were downloaded
+async fn install_cipd_package(
+    package: &CipdPackage,
+    opts: &SyncOptions,
+    clone_path: &Path,
+) -> Result<(String, u64)> {
+    let (service_url, cache) = (&opts.cipd_service_url, &opts.cipd_cache);
+    let instance = cache
+        .resolve(service_url, &package.package, &package.version)
+        .await
+        .with_context(|| format!("resolving version {}", package.version))?;
+    let downloaded_bytes = install_instance(service_url, &instance, cache, clone_path, "").await?;
+    let id = match &instance.digest {
+        Some(digest) => instance_id(digest)?,
+        None => String::new(),
+    };
+    Ok((id, downloaded_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use itertools::Itertools;
+    use tokio::sync::Semaphore;
+
+    #[cfg(unix)]
+    use crate::cipd::deploy::{deploy_instance, tests::write_instance};
+    use crate::error::Error;
+    use crate::gclient::sync_report::{SyncAction, SyncReportEntry};
+    use crate::test_dir::TestDir;
+    use crate::types::deps::{CipdPackage, Dependency, DependencyDef, DepsSpec};
+    use crate::types::dotgclient::{Dotgclient, Solution};
+
+    use super::{
+        deps_matching_conditions, git, link_required, needs_update, remove_stale_entries,
+        sync_checkout, sync_in_order, NumberedDependency, SyncOptions,
+    };
+
+    /// commits `contents` to file "a" in the repository at `repo`, returns the commit
+    fn commit(repo: &Path, contents: &str) -> String {
+        fs::write(repo.join("a"), contents).unwrap();
+        git(repo, &["add", "a"]).unwrap();
+        let identity = ["-c", "user.name=t", "-c", "user.email=t@t"];
+        git(
+            repo,
+            &[&identity[..], &["commit", "-q", "-m", contents]].concat(),
+        )
+        .unwrap();
+        git(repo, &["rev-parse", "HEAD"]).unwrap()
+    }
+
+    /// sorted by path, like clone_dependencies has them
+    fn numbered_deps(paths: &[&str]) -> Vec<NumberedDependency> {
+        let mut deps: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| NumberedDependency {
+                dep_num: i + 1,
+                name: path.to_string(),
+                cache_keys: vec![path.to_string()],
+                synced_before: false,
+                clone_path: PathBuf::from("/w").join(path),
+                dependency: Dependency::Git {
+                    url: format!("https://example.com/{}.git", path),
+                    condition: None,
+                },
+                required_num: None,
+            })
+            .collect();
+        link_required(&mut deps);
+        deps
+    }
+
+    fn opts(jobs: usize) -> SyncOptions {
+        SyncOptions {
+            verbosity: -1,
+            job_slots: Arc::new(Semaphore::new(jobs)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_link_required() {
+        let deps = numbered_deps(&["src", "src/a", "src/a-b", "src/a/c", "src/a/c/d", "tools"]);
+        let required: Vec<_> = deps
+            .iter()
+            .map(|d| {
+                (
+                    d.name.as_str(),
+                    d.required_num.map(|r| deps[r - 1].name.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            required,
+            [
+                ("src", None),
+                ("src/a", Some("src")),
+                // a sibling with a longer name is not a parent
+                ("src/a-b", Some("src")),
+                ("src/a/c", Some("src/a")),
+                ("src/a/c/d", Some("src/a/c")),
+                ("tools", None),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_in_order() {
+        let deps = numbered_deps(&["src", "src/a", "src/a-b", "src/a/c", "src/bad", "src/bad/x"]);
+        // (name, started), in the order it happened
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_ = events.clone();
+        let (report, failed, errors) = sync_in_order(&deps, &opts(4), "src", |dep, _| {
+            let events = events_.clone();
+            async move {
+                events.lock().unwrap().push((dep.name.clone(), true));
+                tokio::task::yield_now().await;
+                events.lock().unwrap().push((dep.name.clone(), false));
+                if dep.name == "src/bad" {
+                    return Err(Error::git(&dep.clone_path, anyhow!("nope")));
+                }
+                Ok(vec![SyncReportEntry::new(
+                    dep.name,
+                    dep.clone_path,
+                    Some("git"),
+                    SyncAction::Cloned,
+                )])
+            }
+        })
+        .await;
+
+        let events = events.lock().unwrap();
+        let at = |name: &str, started: bool| {
+            events
+                .iter()
+                .position(|e| e == &(name.to_string(), started))
+        };
+        // everything waits for what it's in, src/a/c for src/a, not src/a-b
+        for (dep, parent) in [
+            ("src/a", "src"),
+            ("src/a-b", "src"),
+            ("src/a/c", "src/a"),
+            ("src/bad", "src"),
+        ] {
+            assert!(
+                at(parent, false).unwrap() < at(dep, true).unwrap(),
+                "{}",
+                dep
+            );
+        }
+        assert_eq!(at("src/bad/x", true), None);
+
+        assert_eq!(failed.into_iter().sorted().collect_vec(), [5, 6]);
+        assert_eq!(errors.len(), 1);
+        let mut actions: Vec<_> = report.iter().map(|e| (e.name.as_str(), e.action)).collect();
+        actions.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            actions,
+            [
+                ("src", SyncAction::Cloned),
+                ("src/a", SyncAction::Cloned),
+                ("src/a-b", SyncAction::Cloned),
+                ("src/a/c", SyncAction::Cloned),
+                ("src/bad", SyncAction::Failed),
+                ("src/bad/x", SyncAction::Failed),
+            ]
+        );
+        assert!(report
+            .iter()
+            .filter(|e| e.action == SyncAction::Failed)
+            .all(|e| e.error.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_in_order_job_slots() {
+        let deps = numbered_deps(&["a", "b", "c", "d", "e", "f"]);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let (running_, most_running_) = (running.clone(), most_running.clone());
+        let (report, _, errors) = sync_in_order(&deps, &opts(2), "src", |dep, _| {
+            let (running, most_running) = (running_.clone(), most_running_.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                tokio::task::spawn_blocking(|| std::thread::sleep(Duration::from_millis(20)))
+                    .await
+                    .unwrap();
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec![SyncReportEntry::new(
+                    dep.name,
+                    dep.clone_path,
+                    Some("git"),
+                    SyncAction::Cloned,
+                )])
+            }
+        })
+        .await;
+        assert_eq!(report.len(), 6);
+        assert!(errors.is_empty());
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_remove_stale_entries() {
+        let base = TestDir::new("stale_entries");
+        let root = base.join("root");
+        for dir in [
+            "root/src/a",
+            "root/src/b",
+            "root/src/c/d",
+            "root/src/dirty",
+            "outside",
+        ] {
+            fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        let dirty = root.join("src/dirty");
+        git(&dirty, &["init", "-q"]).unwrap();
+        commit(&dirty, "first");
+        fs::write(dirty.join("a"), "mine").unwrap();
+        let url = "https://example.com/a.git@main".to_string();
+        let previous = HashMap::from([
+            ("src/a".to_string(), url.clone()),
+            ("src/b".to_string(), url.clone()),
+            ("src/c".to_string(), url.clone()),
+            ("src/dirty".to_string(), url.clone()),
+            ("src/gone".to_string(), url.clone()),
+            ("../outside".to_string(), url.clone()),
+            (
+                base.join("outside").to_string_lossy().to_string(),
+                url.clone(),
+            ),
+            ("src/..".to_string(), url.clone()),
+        ]);
+        // another solution has these now
+        let current = HashMap::from([
+            ("src/b".to_string(), url.clone()),
+            ("src/c/d".to_string(), url.clone()),
+        ]);
+
+        let deleted = |opts: &SyncOptions| {
+            remove_stale_entries(&root, &previous, &current, opts)
+                .unwrap()
+                .into_iter()
+                .map(|e| {
+                    assert_eq!(e.action, SyncAction::Deleted);
+                    (e.name, e.scm)
+                })
+                .collect_vec()
+        };
+        let exists = || {
+            [
+                "root/src/a",
+                "root/src/b",
+                "root/src/c/d",
+                "root/src/dirty",
+                "outside",
+            ]
+            .map(|dir| base.join(dir).exists())
+        };
+
+        // src/c is kept, and src/gone was never there: neither is reported
+        assert_eq!(
+            deleted(&SyncOptions::default()),
+            [("src/a".to_string(), Some("git"))]
+        );
+        assert_eq!(exists(), [false, true, true, true, true]);
+        let force = SyncOptions {
+            force: true,
+            ..Default::default()
+        };
+        assert_eq!(deleted(&force), [("src/dirty".to_string(), Some("git"))]);
+        assert_eq!(exists(), [false, true, true, false, true]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_stale_cipd_entries() {
+        let root = TestDir::new("stale_cipd_entries");
+        let tools = root.join("src/tools");
+        fs::create_dir_all(&tools).unwrap();
+        let zip = root.join("instance.zip");
+        write_instance(&zip, "copy", &[("bin/tool", "v1", 0o755)]);
+        deploy_instance(&zip, &tools, "", "some/package", "v1").unwrap();
+        // from the package that stays
+        fs::write(tools.join("other"), "kept").unwrap();
+        let previous = HashMap::from([
+            (
+                "src/tools:some/package".to_string(),
+                "some/package@v1".to_string(),
+            ),
+            (
+                "src/tools:never/installed".to_string(),
+                "never/installed@v1".to_string(),
+            ),
+            (
+                "src/tools:kept/package".to_string(),
+                "kept/package@v1".to_string(),
+            ),
+        ]);
+        let current = HashMap::from([(
+            "src/tools:kept/package".to_string(),
+            "kept/package@v1".to_string(),
+        )]);
+
+        let report = remove_stale_entries(&root, &previous, &current, &SyncOptions::default());
+
+        let deleted = report
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.scm))
+            .collect_vec();
+        assert_eq!(
+            deleted,
+            [("src/tools:some/package".to_string(), Some("cipd"))]
+        );
+        assert!(!tools.join("bin/tool").exists());
+        assert!(!tools.join("tool").exists());
+        assert!(tools.join("other").exists());
+    }
+
+    /// an upstream repository with two commits, and where to check it out
+    fn upstream(name: &str) -> (TestDir, PathBuf, [String; 2]) {
+        let root = TestDir::new(name);
+        let (upstream, checkout) = (root.join("upstream"), root.join("checkout"));
+        fs::create_dir_all(&upstream).unwrap();
+        fs::create_dir_all(&checkout).unwrap();
+        git(&upstream, &["init", "-q"]).unwrap();
+        let commits = [commit(&upstream, "first"), commit(&upstream, "second")];
+        (root, checkout, commits)
+    }
+
+    fn url_spec(root: &Path, rev: &str) -> String {
+        format!("file://{}@{}", root.join("upstream").display(), rev)
+    }
+
+    #[test]
+    fn test_sync_checkout_local_changes() {
+        let (root, checkout, [first, second]) = upstream("local_changes");
+        let opts = SyncOptions {
+            verbosity: -1,
+            ..Default::default()
+        };
+
+        let synced = sync_checkout(&url_spec(&root, &first), &checkout, &opts);
+        fs::write(checkout.join("a"), "mine").unwrap();
+        let refused = sync_checkout(&url_spec(&root, &second), &checkout, &opts);
+        let kept = fs::read_to_string(checkout.join("a"));
+        let head = git(&checkout, &["rev-parse", "HEAD"]);
+
+        assert_eq!(synced.unwrap(), first);
+        let refused = format!("{:#}", refused.unwrap_err());
+        assert!(refused.contains("has local changes"), "{}", refused);
+        assert!(refused.ends_with("M a"), "{}", refused);
+        assert_eq!(kept.unwrap(), "mine");
+        assert_eq!(head.unwrap(), first);
+    }
+
+    #[test]
+    fn test_sync_checkout_reset() {
+        let (root, checkout, [first, second]) = upstream("reset");
+        let opts = SyncOptions {
+            verbosity: -1,
+            reset: true,
+            ..Default::default()
+        };
+
+        let synced = sync_checkout(&url_spec(&root, &first), &checkout, &opts);
+        fs::write(checkout.join("a"), "mine").unwrap();
+        let reset = sync_checkout(&url_spec(&root, &second), &checkout, &opts);
+        let contents = fs::read_to_string(checkout.join("a"));
+
+        assert_eq!(synced.unwrap(), first);
+        assert_eq!(reset.unwrap(), second);
+        assert_eq!(contents.unwrap(), "second");
+    }
+
+    #[test]
+    fn test_sync_checkout_force() {
+        let (root, checkout, [first, second]) = upstream("force");
+        let opts = SyncOptions {
+            verbosity: -1,
+            force: true,
+            ..Default::default()
+        };
+
+        let synced = sync_checkout(&url_spec(&root, &first), &checkout, &opts);
+        // in the way of the next checkout
+        fs::write(checkout.join("a"), "mine").unwrap();
+        let forced = sync_checkout(&url_spec(&root, &second), &checkout, &opts);
+
+        assert_eq!(synced.unwrap(), first);
+        assert_eq!(forced.unwrap(), second);
+        assert_eq!(fs::read_to_string(checkout.join("a")).unwrap(), "second");
+        assert_eq!(git(&checkout, &["rev-parse", "HEAD"]).unwrap(), second);
+    }
+
+    #[test]
+    fn test_needs_update() {
+        let git_dep = Dependency::Git {
+            url: "https://example.com/a.git@main".to_string(),
+            condition: None,
+        };
+        let cipd_dep = Dependency::CIPD {
+            packages: vec![CipdPackage {
+                package: "infra/tools/x".to_string(),
+                version: "version:1".to_string(),
+            }],
+            condition: None,
+        };
+        let git_kv = git_dep.to_cache_kv_list("src/a", "https://cipd.example.com");
+        let cipd_kv = cipd_dep.to_cache_kv_list("src/tools", "https://cipd.example.com");
+        let synced: HashMap<_, _> = git_kv.iter().chain(&cipd_kv).cloned().collect();
+        let opts = SyncOptions::default();
+        let force = SyncOptions {
+            force: true,
+            ..Default::default()
+        };
+        let reset = SyncOptions {
+            reset: true,
+            ..Default::default()
+        };
+
+        // unchanged since the last sync
+        assert!(!needs_update(&git_dep, &git_kv, &synced, &opts));
+        assert!(!needs_update(&cipd_dep, &cipd_kv, &synced, &opts));
+        // --force doesn't care about .gclient_entries
+        assert!(needs_update(&git_dep, &git_kv, &synced, &force));
+        assert!(needs_update(&cipd_dep, &cipd_kv, &synced, &force));
+        // --reset is about git checkouts
+        assert!(needs_update(&git_dep, &git_kv, &synced, &reset));
+        assert!(!needs_update(&cipd_dep, &cipd_kv, &synced, &reset));
+        // never synced, or changed
+        assert!(needs_update(&git_dep, &git_kv, &HashMap::new(), &opts));
+        let changed = HashMap::from([(
+            git_kv[0].0.clone(),
+            "https://example.com/a.git@v2".to_string(),
+        )]);
+        assert!(needs_update(&git_dep, &git_kv, &changed, &opts));
+    }
+
+    #[test]
+    fn test_revision_override() {
+        let mut spec = DepsSpec {
+            deps: HashMap::from([
+                (
+                    "a".to_string(),
+                    DependencyDef::Simple("https://example.com/a.git@old".to_string()),
+                ),
+                (
+                    "b".to_string(),
+                    DependencyDef::Simple("https://example.com/b.git".to_string()),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let solution = Solution {
+            name: "src".to_string(),
+            ..Default::default()
+        };
+        let urls = |spec: &DepsSpec, revisions: &[(&str, &str)]| {
+            let opts = SyncOptions {
+                revisions: revisions
+                    .iter()
+                    .map(|(name, rev)| (name.to_string(), rev.to_string()))
+                    .collect(),
+                ..Default::default()
+            };
+            deps_matching_conditions(spec, &solution, &Dotgclient::default(), &opts)
+                .unwrap()
+                .into_iter()
+                .map(|(_, dep, cache_kv_list)| match dep {
+                    Dependency::Git { url, .. } => (url, cache_kv_list[0].1.clone()),
+                    Dependency::CIPD { .. } => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let overridden = urls(&spec, &[("a", "new"), ("b", "main"), ("src", "x")]);
+        assert_eq!(
+            overridden,
+            [
+                (
+                    "https://example.com/a.git@new".to_string(),
+                    "https://example.com/a.git@new".to_string()
+                ),
+                (
+                    "https://example.com/b.git@main".to_string(),
+                    "https://example.com/b.git@main".to_string()
+                ),
+            ]
+        );
+
+        // by the name gclient knows it by, relative to .gclient
+        spec.use_relative_paths = true;
+        assert_eq!(
+            urls(&spec, &[("a", "new")])[0].0,
+            "https://example.com/a.git@old"
+        );
+        assert_eq!(
+            urls(&spec, &[("src/a", "new")])[0].0,
+            "https://example.com/a.git@new"
+        );
+    }
 }
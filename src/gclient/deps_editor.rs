@@ -0,0 +1,392 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::gclient::eval::lexer::Span;
+use crate::gclient::eval::parser::{parse_module, BinOp, Expr, ExprKind, Statement};
+
+/// Where a value in DEPS comes from
+#[derive(Debug)]
+enum DepsValue {
+    /// Var('name')
+    Var { var: String },
+    /// string literal, `prefix` is the part of it that is not the value itself
+    /// (like the url before a revision). span is where the literal is in the file, quotes included
+    Literal {
+        value: String,
+        prefix: String,
+        span: Span,
+    },
+    /// anything else that can be read, but not edited
+    Other { value: String },
+}
+
+fn assignment<'a>(module: &'a [Statement], name: &str) -> Result<&'a Expr> {
+    module
+        .iter()
+        .find_map(|statement| match statement {
+            Statement::Assign { target, value } if target == name => Some(value),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no \"{}\" in DEPS", name))
+}
+
+fn dict_get<'a>(expr: &'a Expr, key: &str) -> Option<&'a Expr> {
+    match &expr.kind {
+        ExprKind::Dict(items) => items
+            .iter()
+            .find(|(k, _)| matches!(&k.kind, ExprKind::Str(k) if k == key))
+            .map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn call_arg<'a>(expr: &'a Expr, func: &str) -> Option<&'a Expr> {
+    match &expr.kind {
+        ExprKind::Call {
+            func: called,
+            args,
+            kwargs,
+            kwargs_mapping: None,
+        } if matches!(&called.kind, ExprKind::Name(name) if name == func)
+            && args.len() == 1
+            && kwargs.is_empty() =>
+        {
+            Some(&args[0])
+        }
+        _ => None,
+    }
+}
+
+fn var_name(expr: &Expr) -> Option<String> {
+    match &call_arg(expr, "Var")?.kind {
+        ExprKind::Str(name) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+fn literal(expr: &Expr, prefix: &str) -> Option<DepsValue> {
+    // Str('...') is just a marked string literal
+    if let Some(inner) = call_arg(expr, "Str") {
+        return literal(inner, prefix);
+    }
+    match &expr.kind {
+        ExprKind::Str(value) => Some(DepsValue::Literal {
+            value: value.clone(),
+            prefix: prefix.to_string(),
+            span: expr.span,
+        }),
+        _ => None,
+    }
+}
+
+fn value(expr: &Expr) -> Result<DepsValue> {
+    if let Some(var) = var_name(expr) {
+        return Ok(DepsValue::Var { var });
+    }
+    if let Some(literal) = literal(expr, "") {
+        return Ok(literal);
+    }
+    let value = match &expr.kind {
+        ExprKind::Name(name) if matches!(name.as_str(), "True" | "False" | "None") => name.clone(),
+        ExprKind::Int(i) => i.to_string(),
+        ExprKind::Float(f) => f.to_string(),
+        _ => bail!("unsupported expression: {:?}", expr.kind),
+    };
+    Ok(DepsValue::Other { value })
+}
+
+/// splits at the last "@" that isn't part of the url authority (user@host/...)
+fn split_revision(value: &str) -> Option<(&str, &str)> {
+    let at = value.rfind('@')?;
+    let rev = &value[at + 1..];
+    if rev.contains('/') {
+        return None;
+    }
+    Some((&value[..at + 1], rev))
+}
+
+fn revision_of_literal(expr: &Expr) -> Option<DepsValue> {
+    let value = match literal(expr, "")? {
+        DepsValue::Literal { value, .. } => value,
+        _ => return None,
+    };
+    let (prefix, rev) = split_revision(&value)?;
+    // '{chromium_git}/foo.git@{foo_revision}', formatted with vars later
+    if let Some(var) = rev.strip_prefix('{').and_then(|r| r.strip_suffix('}')) {
+        if !var.is_empty() && var.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Some(DepsValue::Var {
+                var: var.to_string(),
+            });
+        }
+    }
+    literal(expr, prefix)
+}
+
+fn url_revision(expr: &Expr) -> Result<DepsValue> {
+    // 'https://...@rev'
+    if let Some(found) = revision_of_literal(expr) {
+        return Ok(found);
+    }
+    // Var('chromium_git') + '/foo.git' + '@' + Var('foo_revision')
+    if let ExprKind::BinOp(left, BinOp::Add, right) = &expr.kind {
+        if let Some(found) = revision_of_literal(right) {
+            return Ok(found);
+        }
+        let left_end = match &left.kind {
+            ExprKind::BinOp(_, _, left_right) => left_right,
+            _ => left,
+        };
+        if let Some(DepsValue::Literal { value, .. }) = literal(left_end, "") {
+            if value.ends_with('@') {
+                if let Some(var) = var_name(right) {
+                    return Ok(DepsValue::Var { var });
+                }
+                if let Some(literal) = literal(right, "") {
+                    return Ok(literal);
+                }
+            }
+        }
+    }
+    bail!("cannot find the revision in: {:?}", expr.kind)
+}
+
+fn find_var(deps_file: &str, name: &str) -> Result<DepsValue> {
+    let module = parse_module(deps_file)?;
+    let value_expr = dict_get(assignment(&module, "vars")?, name)
+        .ok_or_else(|| anyhow!("no var \"{}\" in DEPS", name))?;
+    value(value_expr)
+}
+
+/// follows vars that are just Var() of another one, to the var with the value.
+/// returns its name too
+fn resolve_var(deps_file: &str, name: &str) -> Result<(String, DepsValue)> {
+    let mut referenced = vec![name.to_string()];
+    loop {
+        match find_var(deps_file, referenced.last().unwrap())? {
+            DepsValue::Var { var } => {
+                if referenced.contains(&var) {
+                    bail!(
+                        "cyclic reference to {:?} ({} -> {})",
+                        var,
+                        referenced.join(" -> "),
+                        var
+                    );
+                }
+                referenced.push(var);
+            }
+            value => return Ok((referenced.pop().unwrap(), value)),
+        }
+    }
+}
+
+fn find_revision(deps_file: &str, dep_path: &str) -> Result<DepsValue> {
+    let module = parse_module(deps_file)?;
+    let deps = assignment(&module, "deps")?;
+    if let Some(dep) = dict_get(deps, dep_path) {
+        let url = match &dep.kind {
+            ExprKind::Dict(_) => {
+                dict_get(dep, "url").ok_or_else(|| anyhow!("\"{}\" has no url", dep_path))?
+            }
+            _ => dep,
+        };
+        return url_revision(url);
+    }
+
+    // cipd, "{path}:{package}"
+    if let Some((path, package)) = dep_path.split_once(':') {
+        let packages = dict_get(deps, path).and_then(|dep| dict_get(dep, "packages"));
+        if let Some(ExprKind::List(packages)) = packages.map(|p| &p.kind) {
+            for package_expr in packages {
+                let name = dict_get(package_expr, "package").map(|n| &n.kind);
+                if matches!(name, Some(ExprKind::Str(name)) if name == package) {
+                    let version = dict_get(package_expr, "version")
+                        .ok_or_else(|| anyhow!("\"{}\" has no version", dep_path))?;
+                    return value(version);
+                }
+            }
+        }
+    }
+    bail!("no dependency \"{}\" in DEPS", dep_path)
+}
+
+/// swaps a string literal with a new one, keeping the quote style and everything around it
+fn replace_literal(deps_file: &str, (start, end): Span, new_value: &str) -> Result<String> {
+    let literal = &deps_file[start..end];
+    let quote = match literal.chars().next() {
+        Some(q @ ('\'' | '"'))
+            if !literal.starts_with(&q.to_string().repeat(3)) && literal.ends_with(q) =>
+        {
+            q
+        }
+        _ => bail!("unsupported string literal: {}", literal),
+    };
+    let escaped = new_value
+        .replace('\\', "\\\\")
+        .replace(quote, &format!("\\{}", quote));
+    Ok(format!(
+        "{}{quote}{escaped}{quote}{}",
+        &deps_file[..start],
+        &deps_file[end..]
+    ))
+}
+
+/// value of a `vars` entry
+pub fn get_var(deps_file: &str, name: &str) -> Result<String> {
+    match resolve_var(deps_file, name)?.1 {
+        DepsValue::Var { .. } => unreachable!("resolve_var follows them"),
+        DepsValue::Literal { value, .. } => Ok(value),
+        DepsValue::Other { value } => Ok(value),
+    }
+}
+
+/// revision of a git dependency ("{path}"), or version of a cipd package ("{path}:{package}")
+pub fn get_revision(deps_file: &str, dep_path: &str) -> Result<String> {
+    match find_revision(deps_file, dep_path)? {
+        DepsValue::Var { var } => get_var(deps_file, &var),
+        DepsValue::Literal { value, prefix, .. } => Ok(value[prefix.len()..].to_string()),
+        DepsValue::Other { value } => Ok(value),
+    }
+}
+
+/// returns the DEPS file with a `vars` entry changed, and nothing else
+pub fn set_var(deps_file: &str, name: &str, new_value: &str) -> Result<String> {
+    match resolve_var(deps_file, name)? {
+        (_, DepsValue::Var { .. }) => unreachable!("resolve_var follows them"),
+        (_, DepsValue::Literal { prefix, span, .. }) => {
+            replace_literal(deps_file, span, &format!("{}{}", prefix, new_value))
+        }
+        (name, DepsValue::Other { value }) => bail!("var {} is not a string: {}", name, value),
+    }
+}
+
+/// returns the DEPS file with a revision (or cipd version) changed, and nothing else.
+/// revisions set with Var() are changed in `vars`
+pub fn set_revision(deps_file: &str, dep_path: &str, revision: &str) -> Result<String> {
+    match find_revision(deps_file, dep_path)? {
+        DepsValue::Var { var } => set_var(deps_file, &var, revision),
+        DepsValue::Literal { prefix, span, .. } => {
+            replace_literal(deps_file, span, &format!("{}{}", prefix, revision))
+        }
+        DepsValue::Other { value } => {
+            bail!("revision of {} is not a string: {}", dep_path, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_revision, get_var, set_revision, set_var};
+
+    const DEPS: &str = r#"
+vars = {
+  'chromium_git': 'https://chromium.googlesource.com',
+  "v8_revision": "1111",
+  'skia_revision': '2222',
+  'aliased_revision': Var('skia_revision'),
+  'cycle_a': Var('cycle_b'),
+  'cycle_b': Var('cycle_a'),
+  'checkout_foo': True,
+}
+
+deps = {
+  # pinned right there
+  'src/literal': 'https://chromium.googlesource.com/literal.git@3333',
+  'src/v8': Var('chromium_git') + '/v8/v8.git' + '@' + Var('v8_revision'),
+  'src/skia': {
+    'url': '{chromium_git}/skia.git@{skia_revision}',
+    'condition': 'checkout_foo',
+  },
+  'src/aliased': '{chromium_git}/aliased.git@{aliased_revision}',
+  'src/cycle': '{chromium_git}/cycle.git@{cycle_a}',
+  'src/flag': '{chromium_git}/flag.git@{checkout_foo}',
+  'src/tools': {
+    'packages': [
+      {
+        'package': 'infra/tools/foo/${{platform}}',
+        'version': 'git_revision:4444',
+      },
+      {
+        'package': 'infra/tools/bar',
+        'version': Var('v8_revision'),
+      },
+    ],
+    'dep_type': 'cipd',
+  },
+}
+"#;
+
+    #[test]
+    fn test_get_revision() {
+        assert_eq!(get_revision(DEPS, "src/literal").unwrap(), "3333");
+        assert_eq!(get_revision(DEPS, "src/v8").unwrap(), "1111");
+        assert_eq!(get_revision(DEPS, "src/skia").unwrap(), "2222");
+        assert_eq!(get_revision(DEPS, "src/aliased").unwrap(), "2222");
+        assert_eq!(
+            get_revision(DEPS, "src/tools:infra/tools/foo/${{platform}}").unwrap(),
+            "git_revision:4444"
+        );
+        assert_eq!(
+            get_revision(DEPS, "src/tools:infra/tools/bar").unwrap(),
+            "1111"
+        );
+        assert_eq!(get_var(DEPS, "checkout_foo").unwrap(), "True");
+    }
+
+    #[test]
+    fn test_set_revision() {
+        let edit = |dep, revision| set_revision(DEPS, dep, revision).unwrap();
+        // only the revision changes, wherever it is
+        assert_eq!(edit("src/literal", "abcd"), DEPS.replace("@3333", "@abcd"));
+        assert_eq!(
+            edit("src/v8", "abcd"),
+            DEPS.replace(r#""v8_revision": "1111""#, r#""v8_revision": "abcd""#)
+        );
+        assert_eq!(
+            edit("src/skia", "abcd"),
+            DEPS.replace("'skia_revision': '2222'", "'skia_revision': 'abcd'")
+        );
+        assert_eq!(
+            edit("src/aliased", "abcd"),
+            DEPS.replace("'skia_revision': '2222'", "'skia_revision': 'abcd'")
+        );
+        assert_eq!(
+            edit("src/tools:infra/tools/foo/${{platform}}", "version:2@1.0"),
+            DEPS.replace("'git_revision:4444'", "'version:2@1.0'")
+        );
+
+        let edited = edit("src/literal", "abcd");
+        assert_eq!(get_revision(&edited, "src/literal").unwrap(), "abcd");
+    }
+
+    #[test]
+    fn test_set_var() {
+        // the quotes stay the same, escaped if they're in the value
+        assert_eq!(
+            set_var(DEPS, "v8_revision", r#"it's "quoted""#).unwrap(),
+            DEPS.replace(
+                r#""v8_revision": "1111""#,
+                r#""v8_revision": "it's \"quoted\"""#
+            )
+        );
+        assert_eq!(
+            set_var(DEPS, "skia_revision", "it's").unwrap(),
+            DEPS.replace("'skia_revision': '2222'", r"'skia_revision': 'it\'s'")
+        );
+        let edited = set_var(DEPS, "skia_revision", "it's").unwrap();
+        assert_eq!(get_var(&edited, "skia_revision").unwrap(), "it's");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |result: anyhow::Result<String>| format!("{:#}", result.unwrap_err());
+        assert!(error(get_revision(DEPS, "src/nope")).contains("no dependency \"src/nope\""));
+        assert!(error(set_revision(DEPS, "src/nope", "1")).contains("no dependency"));
+        assert!(error(get_revision(DEPS, "src/tools:infra/nope")).contains("no dependency"));
+        assert!(error(get_var(DEPS, "nope")).contains("no var \"nope\""));
+        assert!(error(set_var(DEPS, "nope", "1")).contains("no var \"nope\""));
+        assert!(error(set_var(DEPS, "checkout_foo", "1")).contains("not a string"));
+        assert!(error(set_revision(DEPS, "src/flag", "1")).contains("not a string"));
+        assert!(error(get_var(DEPS, "cycle_a"))
+            .contains("cyclic reference to \"cycle_a\" (cycle_a -> cycle_b -> cycle_a)"));
+        assert!(error(set_revision(DEPS, "src/cycle", "1")).contains("cyclic reference"));
+    }
+}
@@ -1,103 +1,544 @@
-use anyhow::Result;
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
+#[cfg(feature = "python")]
 use pyo3::types::{PyDict, PyString};
+#[cfg(feature = "python")]
 use pyo3::PyTypeInfo;
 
-use crate::gclient::var_utils::{set_builtin_vars, set_vars_from_hashmap};
+use crate::gclient::eval::{py_format, Interpreter, Value};
+use crate::gclient::var_utils::builtin_vars;
+#[cfg(feature = "python")]
+use crate::gclient::var_utils::{set_builtin_vars, set_py_vars};
 use crate::types::deps::DepsSpec;
 use crate::types::dotgclient::{Dotgclient, Solution};
 
+const DEPS_KEYS: [&str; 9] = [
+    "vars",
+    "deps",
+    "gclient_gn_args",
+    "gclient_gn_args_file",
+    "use_relative_paths",
+    "recursedeps",
+    "hooks",
+    "pre_deps_hooks",
+    "use_relative_hooks",
+];
+
+#[cfg(not(feature = "python"))]
+pub fn parse_deps(
+    deps_file: &str,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<DepsSpec> {
+    parse_deps_native(deps_file, solution, dotgclient)
+}
+
+/// same as parse_deps, without python. the default if built without the "python" feature
+pub fn parse_deps_native(
+    deps_file: &str,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<DepsSpec> {
+    Ok(serde_json::from_value(eval_deps_native(
+        deps_file, solution, dotgclient,
+    )?)?)
+}
+
+/// the DEPS_KEYS a DEPS file sets, as JSON
+fn eval_deps_native(
+    deps_file: &str,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<serde_json::Value> {
+    // like gclient, custom_vars first, then the builtin vars, then the vars from DEPS
+    let mut override_vars: HashMap<String, Value> = builtin_vars(dotgclient)
+        .iter()
+        .map(|(name, value)| (name.clone(), value.into()))
+        .collect();
+    override_vars.extend(
+        solution
+            .custom_vars
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.clone(), value.into())),
+    );
+    let var_overrides = override_vars.clone();
+    let mut interpreter = Interpreter::new(Box::new(move |name, globals| {
+        var_overrides
+            .get(name)
+            .or_else(|| globals.get("vars").and_then(|vars| vars.get(name)))
+            .cloned()
+    }));
+    interpreter.exec(deps_file)?;
+
+    // apparently sometimes they use "{var_name}" and not Var('var_name')
+    let mut vars = match interpreter.globals.get("vars") {
+        Some(Value::Dict(vars)) => vars.clone(),
+        Some(other) => bail!("vars is a {}, not a dict", other.type_name()),
+        None => vec![],
+    };
+    vars.retain(|(name, _)| !override_vars.contains_key(name));
+    vars.extend(override_vars);
+    if let Some(Value::Dict(deps)) = interpreter.globals.get_mut("deps") {
+        for (_, dep) in deps.iter_mut() {
+            let url = match dep {
+                Value::Dict(dep) => dep
+                    .iter_mut()
+                    .find(|(key, _)| key == "url")
+                    .map(|(_, url)| url),
+                url => Some(url),
+            };
+            if let Some(Value::Str(url)) = url {
+                *url = py_format(url, &[], &vars)?;
+            }
+        }
+    }
+
+    Ok(interpreter.globals_json(&DEPS_KEYS))
+}
+
+#[cfg(feature = "python")]
 pub fn parse_deps(
-    deps_file: &String,
+    deps_file: &str,
     solution: &Solution,
     dotgclient: &Dotgclient,
 ) -> Result<DepsSpec> {
-    Python::with_gil(|py| -> Result<DepsSpec> {
+    Ok(serde_json::from_value(eval_deps(
+        deps_file, solution, dotgclient,
+    )?)?)
+}
+
+/// same as eval_deps_native, in python
+#[cfg(feature = "python")]
+fn eval_deps(
+    deps_file: &str,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<serde_json::Value> {
+    Python::with_gil(|py| -> Result<serde_json::Value> {
         let globals = PyDict::new(py);
         // copy builtins (str()) over to globals
-        globals
-            .set_item("__builtins__", py.eval("__builtins__", None, None).unwrap())
-            .unwrap();
-        globals
-            .set_item("json", py.import("json").unwrap())
-            .unwrap();
-        globals
-            .set_item(
-                "Str",
-                py.eval("lambda x: {'literal': str(x)}", None, None)
-                    .unwrap(),
-            )
-            .unwrap();
+        globals.set_item("__builtins__", py.eval("__builtins__", None, None)?)?;
+        globals.set_item("json", py.import("json")?)?;
+        globals.set_item("Str", py.eval("lambda x: {'literal': str(x)}", None, None)?)?;
         let builtin_vars = PyDict::new(py);
-        set_builtin_vars(dotgclient, builtin_vars);
-        globals
-            .set_item("gclient_builtin_vars", builtin_vars)
-            .unwrap();
+        set_builtin_vars(dotgclient, builtin_vars)?;
+        globals.set_item("gclient_builtin_vars", builtin_vars)?;
         let custom_vars = PyDict::new(py);
-        if let Some(custom_vars) = &solution.custom_vars {
-            set_vars_from_hashmap(py, &custom_vars);
+        if let Some(solution_custom_vars) = &solution.custom_vars {
+            set_py_vars(custom_vars, solution_custom_vars)?;
         }
-        globals
-            .set_item("gclient_custom_vars", custom_vars)
-            .unwrap();
+        globals.set_item("gclient_custom_vars", custom_vars)?;
         py.run(
             include_str!("var_function.py"),
             Some(globals),
             Some(globals),
-        )
-        .unwrap();
+        )?;
 
-        py.run(deps_file, Some(globals), Some(globals)).unwrap();
+        py.run(deps_file, Some(globals), Some(globals))?;
 
         // apparently sometimes they use "{var_name}" and not Var('var_name')
-        for (dep_key, dep_val) in globals
-            .get_item("deps")
-            .unwrap()
-            .downcast::<PyDict>()
-            .unwrap()
-        {
-            let key = dep_key.downcast::<PyString>().unwrap();
-            if dep_val.is_instance(PyString::type_object(py)).unwrap() {
+        // (not just `vars`, that would be the builtin function if there are none)
+        if let Some(deps) = globals.get_item("deps") {
+            let deps = deps.downcast::<PyDict>().map_err(PyErr::from)?;
+            for (dep_key, dep_val) in deps {
+                let key = serde_json::to_string(&dep_key.str()?.to_string())?;
+                let url_path = if dep_val.is_instance(PyString::type_object(py))? {
+                    format!("deps[{}]", key)
+                } else if dep_val.is_instance(PyDict::type_object(py))?
+                    && dep_val
+                        .downcast::<PyDict>()
+                        .map_err(PyErr::from)?
+                        .get_item("url")
+                        .is_some()
+                {
+                    format!("deps[{}]['url']", key)
+                } else {
+                    continue;
+                };
                 py.run(
                     &format!(
-                        "deps[{0}] = deps[{0}].format(**vars)",
-                        serde_json::to_string(&key.to_string()).unwrap()
+                        "{0} = {0}.format(**{{**globals().get('vars', {{}}), \
+                         **gclient_builtin_vars, **gclient_custom_vars}})",
+                        url_path
                     ),
                     Some(globals),
                     Some(globals),
-                )
-                .unwrap();
-            } else if dep_val.is_instance(PyDict::type_object(py)).unwrap()
-                && dep_val
-                    .downcast::<PyDict>()
-                    .unwrap()
-                    .get_item("url")
-                    .is_some()
-            {
-                py.run(
-                    &format!(
-                        "deps[{0}]['url'] = deps[{0}]['url'].format(**vars)",
-                        serde_json::to_string(&key.to_string()).unwrap()
-                    ),
-                    Some(globals),
-                    Some(globals),
-                )
-                .unwrap();
+                )?;
             }
         }
 
         // something something "you should convert the Py* types instead of using JSON as intermediate" what about no :chad:
         let result = py
             .eval(
-                "json.dumps(dict((it for it in globals().items() if it[0] in ('vars', 'deps', 'gclient_gn_args', 'gclient_gn_args_file', 'use_relative_paths', 'recursedeps'))))",
+                &format!(
+                    "json.dumps(dict((it for it in globals().items() if it[0] in {:?})))",
+                    DEPS_KEYS
+                ),
                 Some(globals),
                 None,
-            )
-            .unwrap()
-            .downcast::<PyString>()
-            .unwrap()
+            )?
+            .str()?
             .to_string();
 
-        Ok(serde_json::from_str::<DepsSpec>(&result).unwrap())
+        Ok(serde_json::from_str(&result)?)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::read_to_string;
+
+    use crate::gclient::dotgclient::eval_dotgclient_native;
+    use crate::gclient::eval::condition::eval_condition;
+    use crate::gclient::eval::Value;
+    use crate::gclient::var_utils::builtin_vars;
+    use crate::types::deps::{Dependency, DependencyDef, DepsSpec, VarsPrimitive};
+    use crate::types::dotgclient::{Dotgclient, Solution};
+    use crate::types::machine::{GclientCPU, GclientOS};
+
+    use super::parse_deps_native;
+    use crate::gclient::var_utils::SpecVars;
+
+    fn dotgclient() -> Dotgclient {
+        Dotgclient {
+            solutions: vec![],
+            target_os: vec![GclientOS::Unix],
+            target_os_only: true,
+            target_cpu: vec![GclientCPU::X64],
+            target_cpu_only: true,
+            cache_dir: None,
+            cipd_cache_dir: None,
+            cipd_cache_max_size: None,
+            cipd_service_url: None,
+        }
+    }
+
+    fn parse_fixture(name: &str) -> DepsSpec {
+        let deps_file = read_to_string(format!("tests/fixtures/DEPS.{}", name)).unwrap();
+        parse_deps_native(&deps_file, &Solution::default(), &dotgclient()).unwrap()
+    }
+
+    fn url(spec: &DepsSpec, path: &str) -> String {
+        match Dependency::from(&spec.deps[path]) {
+            Dependency::Git { url, .. } => url,
+            Dependency::CIPD { .. } => panic!("{} is not a git dependency", path),
+        }
+    }
+
+    fn condition(spec: &DepsSpec, path: &str) -> Option<String> {
+        match Dependency::from(&spec.deps[path]) {
+            Dependency::Git { condition, .. } | Dependency::CIPD { condition, .. } => condition,
+        }
+    }
+
+    fn cipd_versions(spec: &DepsSpec, path: &str) -> Vec<(String, String)> {
+        match Dependency::from(&spec.deps[path]) {
+            Dependency::CIPD { packages, .. } => packages
+                .into_iter()
+                .map(|p| (p.package, p.version))
+                .collect(),
+            Dependency::Git { .. } => panic!("{} is not a cipd dependency", path),
+        }
+    }
+
+    fn check(spec: &DepsSpec, condition: &str) -> bool {
+        let mut vars: HashMap<String, Value> = spec
+            .vars
+            .iter()
+            .map(|(name, value)| (name.clone(), value.into()))
+            .collect();
+        vars.extend(
+            builtin_vars(&dotgclient())
+                .iter()
+                .map(|(name, value)| (name.clone(), value.into())),
+        );
+        eval_condition(condition, &vars).unwrap()
+    }
+
+    #[test]
+    fn test_chromium() {
+        let spec = parse_fixture("chromium");
+        assert_eq!(
+            url(&spec, "src/v8"),
+            "https://chromium.googlesource.com/v8/v8.git@2c84d5a1b3dbd2d5e1d2bc1ae1e5b9ed2a8bfa31"
+        );
+        assert_eq!(
+            cipd_versions(&spec, "src/third_party/ninja"),
+            [(
+                "infra/3pp/tools/ninja/${{platform}}".to_string(),
+                "version:2@1.11.1.chromium.6".to_string()
+            )]
+        );
+        assert!(matches!(
+            &spec.vars["cros_boards"],
+            VarsPrimitive::LiteralString(s) if s.literal.is_empty()
+        ));
+        assert_eq!(spec.gclient_gn_args.as_ref().unwrap().len(), 9);
+        assert_eq!(spec.hooks.len(), 3);
+        assert_eq!(spec.hooks[2].action[1], "src/build/util/lastchange.py");
+        assert_eq!(spec.recursedeps.len(), 3);
+
+        let openxr = condition(&spec, "src/third_party/openxr/src").unwrap();
+        assert!(!check(&spec, &openxr));
+        assert!(!check(&spec, "checkout_chromeos"));
+        assert!(check(&spec, "checkout_linux and not checkout_android"));
+    }
+
+    #[test]
+    fn test_chromium_custom_vars() {
+        let dotgclient = serde_json::from_value::<Dotgclient>(
+            eval_dotgclient_native(&read_to_string("tests/fixtures/dotgclient").unwrap()).unwrap(),
+        )
+        .unwrap();
+        let solution = &dotgclient.solutions[0];
+        assert_eq!(dotgclient.target_os, [GclientOS::Android]);
+        assert!(solution.custom_deps.as_ref().unwrap()["src/third_party/openxr/src"].is_none());
+
+        let deps_file = read_to_string("tests/fixtures/DEPS.chromium").unwrap();
+        let deps_file = deps_file.replace(
+            "'url': Var('chromium_git') + '/chromium-variations.git'",
+            "'url': Var('cros_boards') + '/chromium-variations.git'",
+        );
+        let spec = parse_deps_native(&deps_file, solution, &dotgclient).unwrap();
+        assert!(url(&spec, "src/third_party/chromium-variations").starts_with("amd64-generic/"));
+        assert!(!spec
+            .deps_with_custom_deps(solution.custom_deps.as_ref())
+            .contains_key("src/third_party/openxr/src"));
+    }
+
+    #[test]
+    fn test_custom_deps_keep_conditions() {
+        let deps_file = r#"
+deps = {
+  'src/git': {
+    'url': 'https://example.com/git.git',
+    'condition': 'checkout_git',
+  },
+  'src/cipd': {
+    'packages': [{'package': 'some/package', 'version': 'version:1'}],
+    'dep_type': 'cipd',
+    'condition': 'checkout_cipd',
+  },
+  'src/simple': 'https://example.com/simple.git',
+}
+"#;
+        let custom_deps = HashMap::from(["src/git", "src/cipd", "src/simple", "src/new"].map(
+            |path| {
+                (
+                    path.to_string(),
+                    Some(format!("https://example.com/{}", path)),
+                )
+            },
+        ));
+        let spec = DepsSpec {
+            deps: parse_deps_native(deps_file, &Solution::default(), &dotgclient())
+                .unwrap()
+                .deps_with_custom_deps(Some(&custom_deps)),
+            ..Default::default()
+        };
+
+        for (path, expected) in [
+            ("src/git", Some("checkout_git")),
+            ("src/cipd", Some("checkout_cipd")),
+            ("src/simple", None),
+            ("src/new", None),
+        ] {
+            assert_eq!(url(&spec, path), format!("https://example.com/{}", path));
+            assert_eq!(condition(&spec, path).as_deref(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_v8() {
+        let spec = parse_fixture("v8");
+        assert_eq!(
+            url(&spec, "build"),
+            "https://chromium.googlesource.com/chromium/src/build.git@f1b2f9c9bcf6e2a3e0a6b8c5e2d1f0a9b8c7d6e5"
+        );
+        assert_eq!(
+            cipd_versions(&spec, "tools/luci-go")
+                .iter()
+                .map(|(_, version)| version.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "git_revision:3df60a11d33a59614c0e8d2bccc58d8c30984901",
+                "git_revision:3df60a11d33a59614c0e8d2bccc58d8c30984901"
+            ]
+        );
+        assert_eq!(
+            cipd_versions(&spec, "third_party/fuchsia-sdk/sdk")[0].0,
+            "fuchsia/sdk/core/${{platform}}"
+        );
+        assert!(check(&spec, "checkout_fuchsia_product_bundles"));
+        assert_eq!(
+            check(&spec, &condition(&spec, "buildtools/linux64").unwrap()),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            check(&spec, &condition(&spec, "buildtools/mac").unwrap()),
+            cfg!(target_os = "macos")
+        );
+    }
+
+    #[test]
+    fn test_webrtc() {
+        let spec = parse_fixture("webrtc");
+        assert!(matches!(&spec.deps["src/base"], DependencyDef::Simple(_)));
+        assert_eq!(
+            cipd_versions(&spec, "src/third_party/fuchsia-sdk/sdk"),
+            [(
+                "fuchsia/sdk/core/${{platform}}".to_string(),
+                "version:17.20231204.3.1".to_string()
+            )]
+        );
+        // hook actions are not formatted with vars
+        assert_eq!(
+            spec.hooks[1].action[2],
+            "--cipd-prefix={fuchsia_sdk_cipd_prefix}"
+        );
+        assert!(check(&spec, "checkout_instrumented_libraries"));
+        assert!(spec.recursedeps.is_empty());
+    }
+
+    #[test]
+    fn test_dart() {
+        let spec = parse_fixture("dart");
+        assert_eq!(
+            spec.gclient_gn_args_file.as_deref(),
+            Some("sdk/build/config/gclient_args.gni")
+        );
+        assert_eq!(
+            url(&spec, "sdk/third_party/pkg/args"),
+            "https://dart.googlesource.com/args.git@6a2a3f9d4b5c7e8f0a1b2c3d4e5f60718293a4b5"
+        );
+        assert_eq!(
+            cipd_versions(&spec, "sdk/tests/co19/src")[0].1,
+            "git_revision:d8cd2398b1c1c3a4b2a6b4f8c1e5d7a9b0c2e4f6"
+        );
+        assert!(!check(&spec, "download_android_deps and host_os == win"));
+        assert!(!check(
+            &spec,
+            &condition(&spec, "sdk/third_party/fuchsia/sdk/linux").unwrap()
+        ));
+        assert_eq!(spec.hooks[2].condition.as_deref(), Some("checkout_win"));
+    }
+
+    #[test]
+    fn test_skia() {
+        let spec = parse_fixture("skia");
+        assert!(spec.use_relative_paths);
+        assert_eq!(spec.deps.len(), 12);
+        assert_eq!(
+            url(&spec, "third_party/externals/zlib"),
+            "https://chromium.googlesource.com/chromium/src/third_party/zlib@c876c8f87101c5a75f6014b0f832499afeb65b73"
+        );
+        assert!(!check(&spec, &condition(&spec, "../src").unwrap()));
+    }
+
+    #[test]
+    fn test_no_vars() {
+        let deps_file = "deps = {'src/a': 'https://example.com/a.git'}";
+        let spec = parse_deps_native(deps_file, &Solution::default(), &dotgclient()).unwrap();
+        assert!(spec.vars.is_empty());
+        assert!(spec.hooks.is_empty());
+        assert!(
+            parse_deps_native("hooks = []", &Solution::default(), &dotgclient())
+                .unwrap()
+                .deps
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_recursedeps_deps_file() {
+        let deps_file = "recursedeps = ['src/a', ('src/b', 'DEPS.alt')]";
+        let spec = parse_deps_native(deps_file, &Solution::default(), &dotgclient()).unwrap();
+        let recursedeps: Vec<_> = spec
+            .recursedeps
+            .iter()
+            .map(|d| (d.path(), d.deps_file()))
+            .collect();
+        assert_eq!(recursedeps, [("src/a", None), ("src/b", Some("DEPS.alt"))]);
+    }
+
+    #[test]
+    fn test_var_precedence() {
+        // custom_vars over builtin vars over vars from DEPS, in DEPS and in conditions
+        let deps_file = r#"
+vars = {
+  'host_os': 'from_deps',
+  'checkout_linux': 'False',
+  'only_deps': 'deps',
+  'custom': 'deps',
+}
+deps = {
+  'a': Var('host_os') + '/' + Var('custom') + '/' + Var('only_deps'),
+  'b': '{host_os}/{custom}/{only_deps}',
+}
+"#;
+        let solution = Solution {
+            custom_vars: Some(HashMap::from([
+                (
+                    "custom".to_string(),
+                    VarsPrimitive::String("custom".to_string()),
+                ),
+                ("checkout_linux".to_string(), VarsPrimitive::Bool(true)),
+            ])),
+            ..Default::default()
+        };
+        let spec = parse_deps_native(deps_file, &solution, &dotgclient()).unwrap();
+        let host_os = match &builtin_vars(&dotgclient())["host_os"] {
+            VarsPrimitive::String(host_os) => host_os.clone(),
+            other => panic!("host_os is {:?}", other),
+        };
+        let expected = format!("{}/custom/deps", host_os);
+        assert_eq!(url(&spec, "a"), expected);
+        assert_eq!(url(&spec, "b"), expected);
+
+        let spec_vars = SpecVars::new(&spec, &solution, &dotgclient());
+        assert!(spec_vars
+            .eval_condition(&format!("host_os == '{}'", host_os))
+            .unwrap());
+        assert!(spec_vars.eval_condition("checkout_linux").unwrap());
+        assert!(spec_vars.eval_condition("custom == 'custom'").unwrap());
+        assert!(spec_vars.eval_condition("only_deps == 'deps'").unwrap());
+    }
+
+    /// the native evaluator and python come up with the same thing, on the trimmed
+    /// fixtures and on the verbatim upstream files tests/fixtures/upstream/fetch.sh gets
+    #[cfg(feature = "python")]
+    #[test]
+    fn test_python_parity() {
+        let dotgclient = serde_json::from_value::<Dotgclient>(
+            eval_dotgclient_native(&read_to_string("tests/fixtures/dotgclient").unwrap()).unwrap(),
+        )
+        .unwrap();
+        let mut paths = ["chromium", "v8", "webrtc", "dart", "skia"]
+            .map(|name| std::path::PathBuf::from(format!("tests/fixtures/DEPS.{}", name)))
+            .to_vec();
+        if let Ok(upstream) = std::fs::read_dir("tests/fixtures/upstream") {
+            paths.extend(
+                upstream
+                    .map(|e| e.unwrap().path())
+                    .filter(|p| p.to_string_lossy().contains("/DEPS.")),
+            );
+        }
+        for path in paths {
+            let deps_file = read_to_string(&path).unwrap();
+            for solution in [&Solution::default(), &dotgclient.solutions[0]] {
+                assert_eq!(
+                    super::eval_deps_native(&deps_file, solution, &dotgclient).unwrap(),
+                    super::eval_deps(&deps_file, solution, &dotgclient).unwrap(),
+                    "{:?} with custom_vars {:?}",
+                    path,
+                    solution.custom_vars
+                );
+            }
+        }
+    }
+}
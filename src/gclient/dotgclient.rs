@@ -1,37 +1,68 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+#[cfg(feature = "python")]
 use pyo3::types::PyDict;
+#[cfg(feature = "python")]
 use pyo3::Python;
 
+use crate::gclient::eval::Interpreter;
 use crate::host::{gclient_host_cpu, gclient_host_os};
 use crate::types::dotgclient::Dotgclient;
 use crate::types::machine::{GclientOS, OS_LIST};
 
-pub fn read_dotgclient(contents: String) -> Result<Dotgclient> {
-    let result_json = Python::with_gil(|py| {
+const DOTGCLIENT_KEYS: [&str; 9] = [
+    "solutions",
+    "cache_dir",
+    "cipd_cache_dir",
+    "cipd_cache_max_size",
+    "cipd_service_url",
+    "target_os",
+    "target_os_only",
+    "target_cpu",
+    "target_cpu_only",
+];
+
+/// the interesting globals of .gclient, as json
+pub fn eval_dotgclient_native(contents: &str) -> Result<serde_json::Value> {
+    let mut interpreter = Interpreter::without_vars();
+    interpreter.exec(contents)?;
+    Ok(interpreter.globals_json(&DOTGCLIENT_KEYS))
+}
+
+#[cfg(not(feature = "python"))]
+fn eval_dotgclient(contents: &str) -> Result<serde_json::Value> {
+    eval_dotgclient_native(contents)
+}
+
+#[cfg(feature = "python")]
+fn eval_dotgclient(contents: &str) -> Result<serde_json::Value> {
+    let result_json = Python::with_gil(|py| -> Result<String> {
         let variables = PyDict::new(py);
         let globals = PyDict::new(py);
-        globals
-            .set_item("__builtins__", py.import("builtins").unwrap())
-            .unwrap();
-        globals
-            .set_item("json", py.import("json").unwrap())
-            .unwrap();
-        py.run(&contents, Some(variables), Some(variables)).unwrap();
-        py.eval(
-            "json.dumps(dict((it for it in locals().items() if it[0] in ('solutions', 'target_os', 'target_os_only', 'target_cpu', 'target_cpu_only'))))",
-            Some(globals),
-            Some(variables),
-        )
-        .unwrap()
-        .to_string()
-    });
-    let mut result: Dotgclient = serde_json::from_str(&result_json).unwrap();
+        globals.set_item("__builtins__", py.import("builtins")?)?;
+        globals.set_item("json", py.import("json")?)?;
+        py.run(contents, Some(variables), Some(variables))?;
+        Ok(py
+            .eval(
+                &format!(
+                    "json.dumps(dict((it for it in locals().items() if it[0] in {:?})))",
+                    DOTGCLIENT_KEYS
+                ),
+                Some(globals),
+                Some(variables),
+            )?
+            .to_string())
+    })?;
+    Ok(serde_json::from_str(&result_json)?)
+}
+
+pub fn read_dotgclient(contents: String) -> Result<Dotgclient> {
+    let mut result: Dotgclient = serde_json::from_value(eval_dotgclient(&contents)?)?;
     if result
         .solutions
         .iter()
-        .all(|s| s.tpot_internal_from_recursedeps == true)
+        .all(|s| s.tpot_internal_from_recursedeps)
     {
-        panic!("don't play with me");
+        bail!("no solutions in .gclient");
     }
     let host_os = gclient_host_os();
     if result.target_os.contains(&GclientOS::All) {
@@ -43,5 +74,5 @@ pub fn read_dotgclient(contents: String) -> Result<Dotgclient> {
     if !result.target_cpu_only && !result.target_cpu.contains(&host_cpu) {
         result.target_cpu.push(host_cpu);
     }
-    return Ok(result);
+    Ok(result)
 }
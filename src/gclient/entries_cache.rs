@@ -1,18 +1,23 @@
 use std::collections::HashMap;
-use std::fs::{read_to_string, write};
+use std::fs::{read_to_string, rename, write};
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use pyo3::types::{PyDict, PyString};
+use anyhow::{Context, Result};
+#[cfg(feature = "python")]
+use pyo3::types::PyDict;
+#[cfg(feature = "python")]
 use pyo3::Python;
 
+use crate::gclient::eval::Interpreter;
+
 pub fn path_to_entries_cache<P: AsRef<Path>>(root_path: P) -> PathBuf {
     root_path.as_ref().join(".gclient_entries")
 }
 
-/// key - '{path}', or '{path}:{package}' if cipd.
+/// key - '{path}', or '{path}:{package}' if cipd. paths are relative to the .gclient directory,
+/// one .gclient_entries there has the dependencies of every solution.
 ///
-/// value - '{url}@{revision pointer}', url to git or 'https://chrome-infra-packages.appspot.com/{package}'.
+/// value - '{url}@{revision pointer}', url to git or '{cipd service url}/{package}'.
 /// no revision is also possible.
 pub type EntriesCache = HashMap<String, String>;
 
@@ -24,25 +29,49 @@ pub fn read_entries<P: AsRef<Path>>(entries_path: P) -> Result<EntriesCache> {
         return Ok(HashMap::default());
     }
 
+    read_entries_from(&read_to_string(cache_path)?)
+}
+
+/// parses contents of .gclient_entries without python. the default if built without the "python" feature
+pub fn read_entries_native(contents: &str) -> Result<EntriesCache> {
+    let mut interpreter = Interpreter::without_vars();
+    interpreter.exec(contents)?;
+    let entries = interpreter
+        .globals
+        .get("entries")
+        .context("no entries in .gclient_entries")?;
+    Ok(serde_json::from_value(entries.to_json())?)
+}
+
+#[cfg(not(feature = "python"))]
+fn read_entries_from(contents: &str) -> Result<EntriesCache> {
+    read_entries_native(contents)
+}
+
+#[cfg(feature = "python")]
+fn read_entries_from(contents: &str) -> Result<EntriesCache> {
     Python::with_gil(|py| -> Result<EntriesCache> {
         let globals = PyDict::new(py);
         globals.set_item("json", py.import("json")?)?;
-        py.run(&read_to_string(cache_path)?, Some(globals), Some(globals))?;
+        py.run(contents, Some(globals), Some(globals))?;
         let result = py
-            .eval("json.dumps(entries)", Some(globals), Some(globals))
-            .unwrap()
-            .downcast::<PyString>()
-            .unwrap()
+            .eval("json.dumps(entries)", Some(globals), Some(globals))?
+            .str()?
             .to_string();
         Ok(serde_json::from_str(&result)?)
     })
 }
 
+/// writes to a temporary file first and renames it over the old one,
+/// so an interrupted write never leaves a partial cache behind
 pub fn write_entries<P: AsRef<Path>>(entries_path: P, entries_cache: &EntriesCache) -> Result<()> {
+    let cache_path = entries_path.as_ref();
+    let tmp_path = cache_path.with_extension("tmp");
     write(
-        entries_path,
+        &tmp_path,
         format!("entries = {}", serde_json::to_string_pretty(entries_cache)?),
     )?;
+    rename(&tmp_path, cache_path)?;
 
     Ok(())
 }
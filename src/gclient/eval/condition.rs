@@ -0,0 +1,181 @@
+//! `condition` strings of dependencies, hooks and gn args, evaluated like gclient does:
+//! names are vars, and vars holding strings are conditions themselves,
+//! evaluated when they are used as booleans.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+
+use super::parser::{parse_expression, Expr, ExprKind};
+use super::{compare, Value};
+
+struct ConditionEvaluator<'a> {
+    vars: &'a HashMap<String, Value>,
+    /// vars being evaluated right now, to catch cycles
+    referenced: Vec<String>,
+}
+
+impl ConditionEvaluator<'_> {
+    fn eval_str(&mut self, condition: &str) -> Result<bool> {
+        let expr = parse_expression(condition)?;
+        let value = self.eval(&expr)?;
+        self.truthy(value)
+    }
+
+    /// bool context. strings from vars are conditions, literal strings are just strings
+    fn truthy(&mut self, (value, var_name): (Value, Option<String>)) -> Result<bool> {
+        match (value, var_name) {
+            (Value::Str(condition), Some(var_name)) => {
+                if self.referenced.contains(&var_name) {
+                    bail!(
+                        "cyclic reference to {:?} ({} -> {})",
+                        var_name,
+                        self.referenced.join(" -> "),
+                        var_name
+                    );
+                }
+                self.referenced.push(var_name.clone());
+                let result = self
+                    .eval_str(&condition)
+                    .with_context(|| format!("in var {:?}: {}", var_name, condition));
+                self.referenced.pop();
+                result
+            }
+            (value, _) => Ok(value.is_truthy()),
+        }
+    }
+
+    /// returns the value, and the name of the var it came from (if it's just a var)
+    fn eval(&mut self, expr: &Expr) -> Result<(Value, Option<String>)> {
+        Ok(match &expr.kind {
+            ExprKind::Name(name) => match name.as_str() {
+                "True" => (Value::Bool(true), None),
+                "False" => (Value::Bool(false), None),
+                "None" => (Value::None, None),
+                _ => match self.vars.get(name) {
+                    Some(value) => (value.clone(), Some(name.clone())),
+                    None => bail!("unknown variable {:?}", name),
+                },
+            },
+            ExprKind::Str(s) => (Value::Str(s.clone()), None),
+            ExprKind::Int(i) => (Value::Int(*i), None),
+            ExprKind::List(items) | ExprKind::Tuple(items) => {
+                let mut values = vec![];
+                for item in items {
+                    values.push(self.eval(item)?.0);
+                }
+                (Value::List(values), None)
+            }
+            ExprKind::Not(value) => {
+                let value = self.eval(value)?;
+                (Value::Bool(!self.truthy(value)?), None)
+            }
+            ExprKind::And(left, right) => {
+                let left = self.eval(left)?;
+                let result = self.truthy(left)? && {
+                    let right = self.eval(right)?;
+                    self.truthy(right)?
+                };
+                (Value::Bool(result), None)
+            }
+            ExprKind::Or(left, right) => {
+                let left = self.eval(left)?;
+                let result = self.truthy(left)? || {
+                    let right = self.eval(right)?;
+                    self.truthy(right)?
+                };
+                (Value::Bool(result), None)
+            }
+            ExprKind::Compare(left, op, right) => {
+                let left = self.eval(left)?.0;
+                let right = self.eval(right)?.0;
+                (Value::Bool(compare(&left, *op, &right)?), None)
+            }
+            _ => bail!("unsupported expression in condition"),
+        })
+    }
+}
+
+/// evaluates a condition of a dependency, a hook or a gn arg
+pub fn eval_condition(condition: &str, vars: &HashMap<String, Value>) -> Result<bool> {
+    ConditionEvaluator {
+        vars,
+        referenced: vec![],
+    }
+    .eval_str(condition)
+    .with_context(|| format!("evaluating condition: {}", condition))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::eval_condition;
+    use crate::gclient::eval::Value;
+
+    fn vars() -> HashMap<String, Value> {
+        HashMap::from([
+            ("checkout_linux".to_string(), Value::Bool(true)),
+            ("checkout_arm64".to_string(), Value::Bool(false)),
+            ("host_os".to_string(), Value::Str("linux".to_string())),
+            ("host_cpu".to_string(), Value::Str("x64".to_string())),
+            ("linux".to_string(), Value::Str("linux".to_string())),
+            (
+                "checkout_android".to_string(),
+                Value::Str("checkout_android_native and checkout_linux".to_string()),
+            ),
+            (
+                "checkout_android_native".to_string(),
+                Value::Str("True".to_string()),
+            ),
+            ("cros_boards".to_string(), Value::LiteralStr("".to_string())),
+            ("cycle_a".to_string(), Value::Str("cycle_b".to_string())),
+            ("cycle_b".to_string(), Value::Str("not cycle_a".to_string())),
+        ])
+    }
+
+    fn eval(condition: &str) -> bool {
+        eval_condition(condition, &vars()).unwrap()
+    }
+
+    fn error(condition: &str) -> String {
+        format!("{:#}", eval_condition(condition, &vars()).unwrap_err())
+    }
+
+    #[test]
+    fn test_operators() {
+        assert!(eval(
+            "checkout_linux and (host_cpu == \"x64\" or checkout_arm64)"
+        ));
+        assert!(!eval(
+            "checkout_linux and not (host_cpu == 'x64' or checkout_arm64)"
+        ));
+        assert!(eval("host_os != 'mac'"));
+        assert!(eval("host_os == linux"));
+        assert!(eval("host_os in ('linux', 'mac')"));
+        assert!(eval("host_cpu not in ['arm', 'arm64']"));
+        assert!(!eval("False or None"));
+    }
+
+    #[test]
+    fn test_nested_vars() {
+        assert!(eval("checkout_android"));
+        assert!(!eval("not checkout_android"));
+        // Str() values are never conditions
+        assert!(!eval("cros_boards"));
+        // compared as strings, not evaluated
+        assert!(eval("checkout_android_native == 'True'"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(
+            error("checkout_linux and checkout_foo").contains("unknown variable \"checkout_foo\"")
+        );
+        assert!(error("cycle_a")
+            .contains("cyclic reference to \"cycle_a\" (cycle_a -> cycle_b -> cycle_a)"));
+        assert!(error("checkout_linux and").contains("evaluating condition: checkout_linux and"));
+        // `and` short-circuits, so this is fine
+        assert!(!eval("checkout_arm64 and checkout_foo"));
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Result};
+
+/// byte range in the source
+pub type Span = (usize, usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    /// punctuation and operators, like "(" or "=="
+    Op(&'static str),
+    /// end of a statement (not emitted inside brackets)
+    Newline,
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// longest first, so "==" is not lexed as two "="
+const OPS: [&str; 27] = [
+    "**", "//", "==", "!=", "<=", ">=", "+=", "(", ")", "[", "]", "{", "}", ",", ":", ".", ";",
+    "=", "+", "-", "*", "/", "%", "<", ">", "@", "~",
+];
+
+/// line and column (both 1-based) of a byte offset, for error messages
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+    (line, col)
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    depth: usize,
+    tokens: Vec<Token>,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn error<T>(&self, offset: usize, message: &str) -> Result<T> {
+        let (line, col) = line_col(self.source, offset);
+        bail!("{} at line {}, column {}", message, line, col)
+    }
+
+    fn push(&mut self, kind: TokenKind, start: usize) {
+        self.tokens.push(Token {
+            kind,
+            span: (start, self.pos),
+        });
+    }
+
+    fn newline(&mut self) {
+        let last_is_newline = matches!(
+            self.tokens.last().map(|t| &t.kind),
+            None | Some(TokenKind::Newline)
+        );
+        if self.depth == 0 && !last_is_newline {
+            self.push(TokenKind::Newline, self.pos);
+        }
+    }
+
+    fn lex(mut self) -> Result<Vec<Token>> {
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            match c {
+                '\n' => {
+                    self.newline();
+                    self.pos += 1;
+                }
+                ' ' | '\t' | '\r' | '\x0c' => self.pos += 1,
+                '#' => {
+                    self.pos = self.source[self.pos..]
+                        .find('\n')
+                        .map(|i| self.pos + i)
+                        .unwrap_or(self.source.len());
+                }
+                '\\' if self.source[self.pos + 1..].starts_with('\n') => self.pos += 2,
+                '\\' if self.source[self.pos + 1..].starts_with("\r\n") => self.pos += 3,
+                '0'..='9' => self.number()?,
+                '.' if matches!(self.source[self.pos + 1..].chars().next(), Some('0'..='9')) => {
+                    self.number()?
+                }
+                '\'' | '"' => self.string(start, false)?,
+                c if c.is_alphabetic() || c == '_' => {
+                    let len = self.source[self.pos..]
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                        .unwrap_or(self.source.len() - self.pos);
+                    let name = &self.source[self.pos..self.pos + len];
+                    let quote = self.source[self.pos + len..].chars().next();
+                    if matches!(quote, Some('\'' | '"'))
+                        && matches!(
+                            name.to_ascii_lowercase().as_str(),
+                            "r" | "u" | "b" | "br" | "rb"
+                        )
+                    {
+                        self.pos += len;
+                        self.string(start, name.to_ascii_lowercase().contains('r'))?;
+                    } else if matches!(quote, Some('\'' | '"'))
+                        && name.to_ascii_lowercase().contains('f')
+                        && name.len() <= 2
+                    {
+                        return self.error(start, "f-strings are not supported");
+                    } else {
+                        self.pos += len;
+                        self.push(TokenKind::Name(name.to_string()), start);
+                    }
+                }
+                _ => match OPS
+                    .iter()
+                    .find(|op| self.source[self.pos..].starts_with(**op))
+                {
+                    Some(op) => {
+                        match *op {
+                            "(" | "[" | "{" => self.depth += 1,
+                            ")" | "]" | "}" => self.depth = self.depth.saturating_sub(1),
+                            _ => {}
+                        }
+                        self.pos += op.len();
+                        if *op == ";" {
+                            self.newline();
+                        } else {
+                            self.push(TokenKind::Op(op), start);
+                        }
+                    }
+                    None => return self.error(start, &format!("unexpected character {:?}", c)),
+                },
+            }
+        }
+        self.newline();
+        self.push(TokenKind::Eof, self.pos);
+        Ok(self.tokens)
+    }
+
+    fn number(&mut self) -> Result<()> {
+        let start = self.pos;
+        let len = self.source[self.pos..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(self.source.len() - self.pos);
+        let text = self.source[start..start + len].replace('_', "");
+        self.pos += len;
+        let kind = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            i64::from_str_radix(hex, 16).ok().map(TokenKind::Int)
+        } else if text.contains(['.', 'e', 'E']) {
+            text.parse().ok().map(TokenKind::Float)
+        } else {
+            text.parse().ok().map(TokenKind::Int)
+        };
+        match kind {
+            Some(kind) => {
+                self.push(kind, start);
+                Ok(())
+            }
+            None => self.error(start, &format!("invalid number {:?}", text)),
+        }
+    }
+
+    fn string(&mut self, start: usize, raw: bool) -> Result<()> {
+        let quote = self.peek().unwrap();
+        let triple = quote.to_string().repeat(3);
+        let delimiter = if self.source[self.pos..].starts_with(&triple) {
+            triple
+        } else {
+            quote.to_string()
+        };
+        self.pos += delimiter.len();
+
+        let mut value = String::new();
+        loop {
+            let rest = &self.source[self.pos..];
+            if rest.starts_with(&delimiter) {
+                self.pos += delimiter.len();
+                break;
+            }
+            let c = match rest.chars().next() {
+                None => return self.error(start, "unterminated string"),
+                Some('\n') if delimiter.len() == 1 => {
+                    return self.error(start, "unterminated string")
+                }
+                Some(c) => c,
+            };
+            self.pos += c.len_utf8();
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let escaped = match self.peek() {
+                None => return self.error(start, "unterminated string"),
+                Some(e) => e,
+            };
+            self.pos += escaped.len_utf8();
+            if raw {
+                value.push('\\');
+                value.push(escaped);
+                continue;
+            }
+            match escaped {
+                '\n' => {}
+                '\\' | '\'' | '"' => value.push(escaped),
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                '0' => value.push('\0'),
+                'x' | 'u' | 'U' => {
+                    let len = match escaped {
+                        'x' => 2,
+                        'u' => 4,
+                        _ => 8,
+                    };
+                    let code = self
+                        .source
+                        .get(self.pos..self.pos + len)
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32);
+                    match code {
+                        Some(code) => value.push(code),
+                        None => return self.error(self.pos, "invalid escape sequence"),
+                    }
+                    self.pos += len;
+                }
+                // python keeps unknown escapes as they are
+                other => {
+                    value.push('\\');
+                    value.push(other);
+                }
+            }
+        }
+        self.push(TokenKind::Str(value), start);
+        Ok(())
+    }
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>> {
+    Lexer {
+        source,
+        pos: 0,
+        depth: 0,
+        tokens: vec![],
+    }
+    .lex()
+}
@@ -0,0 +1,636 @@
+//! Evaluator of the python subset used by DEPS, .gclient and .gclient_entries files,
+//! so they can be read without embedding CPython.
+
+pub mod condition;
+pub mod lexer;
+pub mod parser;
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::types::deps::{LiteralString, VarsPrimitive};
+use parser::{parse_module, BinOp, CmpOp, Expr, ExprKind, Statement};
+
+/// longest string `'...' * n` may build. DEPS files have no use for more,
+/// and a typo in n shouldn't take all the memory
+const MAX_REPEATED_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// Str('...') in DEPS, a string that is not a condition
+    LiteralStr(String),
+    /// lists and tuples
+    List(Vec<Value>),
+    /// keys are always strings in the files we care about
+    Dict(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "NoneType",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "str",
+            Value::LiteralStr(_) => "Str",
+            Value::List(_) => "list",
+            Value::Dict(_) => "dict",
+        }
+    }
+
+    /// python truthiness, strings are not evaluated as conditions here
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::Str(s) | Value::LiteralStr(s) => !s.is_empty(),
+            Value::List(l) => !l.is_empty(),
+            Value::Dict(d) => !d.is_empty(),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) | Value::LiteralStr(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(items) => items.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// like python str()
+    pub fn to_py_str(&self) -> String {
+        match self {
+            Value::Str(s) | Value::LiteralStr(s) => s.clone(),
+            other => other.to_py_repr(),
+        }
+    }
+
+    /// like python repr()
+    pub fn to_py_repr(&self) -> String {
+        match self {
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) if f.fract() == 0.0 && f.abs() < 1e16 => format!("{:.1}", f),
+            Value::Float(f) => f.to_string(),
+            Value::Str(s) | Value::LiteralStr(s) => {
+                format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+            }
+            Value::List(items) => format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(Value::to_py_repr)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Dict(items) => format!(
+                "{{{}}}",
+                items
+                    .iter()
+                    .map(|(k, v)| format!(
+                        "{}: {}",
+                        Value::Str(k.clone()).to_py_repr(),
+                        v.to_py_repr()
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// same shape the python implementation gets out of json.dumps
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::None => serde_json::Value::Null,
+            Value::Bool(b) => (*b).into(),
+            Value::Int(i) => (*i).into(),
+            Value::Float(f) => serde_json::Number::from_f64(*f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::Str(s) => s.clone().into(),
+            Value::LiteralStr(s) => serde_json::json!({ "literal": s }),
+            Value::List(items) => items.iter().map(Value::to_json).collect(),
+            Value::Dict(items) => serde_json::Value::Object(
+                items
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_json()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&VarsPrimitive> for Value {
+    fn from(var: &VarsPrimitive) -> Self {
+        match var {
+            VarsPrimitive::String(s) => Value::Str(s.clone()),
+            VarsPrimitive::LiteralString(LiteralString { literal }) => {
+                Value::LiteralStr(literal.clone())
+            }
+            VarsPrimitive::Int(i) => Value::Int(*i),
+            VarsPrimitive::Float(f) => Value::Float(*f),
+            VarsPrimitive::Bool(b) => Value::Bool(*b),
+        }
+    }
+}
+
+/// python str.format(), without format specs
+pub fn py_format(template: &str, args: &[Value], kwargs: &[(String, Value)]) -> Result<String> {
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    let mut auto_index = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => bail!("unmatched '{{' in format string {:?}", template),
+                    }
+                }
+                let name = field.split([':', '!']).next().unwrap_or_default();
+                let value = if name.is_empty() {
+                    auto_index += 1;
+                    args.get(auto_index - 1)
+                } else if let Ok(index) = name.parse::<usize>() {
+                    args.get(index)
+                } else {
+                    kwargs.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+                };
+                match value {
+                    Some(value) => result.push_str(&value.to_py_str()),
+                    None => bail!("KeyError: {:?} in format string {:?}", name, template),
+                }
+            }
+            '}' => bail!("single '}}' in format string {:?}", template),
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+/// like python's `template % args`, for %s, %d and %% (all that anyone uses in these files)
+pub fn percent_format(template: &str, args: &[Value]) -> Result<String> {
+    let mut result = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => result.push('%'),
+            Some(conversion @ ('s' | 'd')) => {
+                let arg = args.next().ok_or_else(|| {
+                    anyhow!("not enough arguments for format string {:?}", template)
+                })?;
+                match (conversion, arg) {
+                    ('s', arg) => result.push_str(&arg.to_py_str()),
+                    (_, Value::Int(i)) => result.push_str(&i.to_string()),
+                    (_, Value::Bool(b)) => result.push_str(&(*b as i64).to_string()),
+                    (_, Value::Float(f)) => result.push_str(&f.trunc().to_string()),
+                    (_, other) => {
+                        bail!("%d format: a number is required, not {}", other.type_name())
+                    }
+                }
+            }
+            Some(other) => bail!("unsupported format character {:?} in {:?}", other, template),
+            None => bail!("incomplete format {:?}", template),
+        }
+    }
+    if args.next().is_some() {
+        bail!(
+            "not all arguments converted during string formatting of {:?}",
+            template
+        );
+    }
+    Ok(result)
+}
+
+/// resolves Var('name'), gets the module globals to look at `vars` in
+pub type VarResolver<'a> = dyn Fn(&str, &HashMap<String, Value>) -> Option<Value> + 'a;
+
+pub struct Interpreter<'a> {
+    /// names assigned by the module, in order of first assignment
+    pub globals: HashMap<String, Value>,
+    pub assigned: Vec<String>,
+    resolve_var: Box<VarResolver<'a>>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(resolve_var: Box<VarResolver<'a>>) -> Self {
+        Interpreter {
+            globals: HashMap::new(),
+            assigned: vec![],
+            resolve_var,
+        }
+    }
+
+    /// interpreter without Var(), for .gclient and the like
+    pub fn without_vars() -> Self {
+        Self::new(Box::new(|_, _| None))
+    }
+
+    pub fn exec(&mut self, source: &str) -> Result<()> {
+        for statement in parse_module(source)? {
+            match statement {
+                Statement::Assign { target, value } => {
+                    let value = self.eval(&value)?;
+                    self.assign(target, value);
+                }
+                Statement::AugAssign { target, value } => {
+                    let current =
+                        self.globals.get(&target).cloned().ok_or_else(|| {
+                            anyhow!("NameError: name {:?} is not defined", target)
+                        })?;
+                    let value = self.eval(&value)?;
+                    let value = binop(current, BinOp::Add, value)?;
+                    self.assign(target, value);
+                }
+                Statement::Expr(expr) => {
+                    self.eval(&expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, target: String, value: Value) {
+        if !self.globals.contains_key(&target) {
+            self.assigned.push(target.clone());
+        }
+        self.globals.insert(target, value);
+    }
+
+    /// selected globals as a json object, like the python implementation dumps them
+    pub fn globals_json(&self, names: &[&str]) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.assigned
+                .iter()
+                .filter(|name| names.contains(&name.as_str()))
+                .map(|name| (name.clone(), self.globals[name].to_json()))
+                .collect(),
+        )
+    }
+
+    pub fn eval(&self, expr: &Expr) -> Result<Value> {
+        Ok(match &expr.kind {
+            ExprKind::Name(name) => match name.as_str() {
+                "True" => Value::Bool(true),
+                "False" => Value::Bool(false),
+                "None" => Value::None,
+                _ => self
+                    .globals
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("NameError: name {:?} is not defined", name))?,
+            },
+            ExprKind::Str(s) => Value::Str(s.clone()),
+            ExprKind::Int(i) => Value::Int(*i),
+            ExprKind::Float(f) => Value::Float(*f),
+            ExprKind::List(items) | ExprKind::Tuple(items) => Value::List(
+                items
+                    .iter()
+                    .map(|item| self.eval(item))
+                    .collect::<Result<_>>()?,
+            ),
+            ExprKind::Dict(items) => {
+                let mut dict: Vec<(String, Value)> = vec![];
+                for (key, value) in items {
+                    let key = match self.eval(key)? {
+                        Value::Str(k) | Value::LiteralStr(k) => k,
+                        other => bail!("unsupported dict key type: {}", other.type_name()),
+                    };
+                    let value = self.eval(value)?;
+                    match dict.iter_mut().find(|(k, _)| *k == key) {
+                        Some(existing) => existing.1 = value,
+                        None => dict.push((key, value)),
+                    }
+                }
+                Value::Dict(dict)
+            }
+            ExprKind::BinOp(left, op, right) => binop(self.eval(left)?, *op, self.eval(right)?)?,
+            ExprKind::Neg(value) => match self.eval(value)? {
+                Value::Int(i) => Value::Int(-i),
+                Value::Float(f) => Value::Float(-f),
+                other => bail!("bad operand type for unary -: {}", other.type_name()),
+            },
+            ExprKind::Not(value) => Value::Bool(!self.eval(value)?.is_truthy()),
+            ExprKind::And(left, right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    self.eval(right)?
+                } else {
+                    left
+                }
+            }
+            ExprKind::Or(left, right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    left
+                } else {
+                    self.eval(right)?
+                }
+            }
+            ExprKind::Compare(left, op, right) => {
+                Value::Bool(compare(&self.eval(left)?, *op, &self.eval(right)?)?)
+            }
+            ExprKind::IfElse(value, condition, otherwise) => {
+                if self.eval(condition)?.is_truthy() {
+                    self.eval(value)?
+                } else {
+                    self.eval(otherwise)?
+                }
+            }
+            ExprKind::Subscript(value, index) => match (self.eval(value)?, self.eval(index)?) {
+                (Value::Dict(items), Value::Str(key)) => items
+                    .into_iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v)
+                    .ok_or_else(|| anyhow!("KeyError: {:?}", key))?,
+                (Value::List(items), Value::Int(i)) => {
+                    let index = if i < 0 { items.len() as i64 + i } else { i };
+                    usize::try_from(index)
+                        .ok()
+                        .and_then(|i| items.get(i).cloned())
+                        .ok_or_else(|| anyhow!("IndexError: list index out of range"))?
+                }
+                (value, index) => bail!(
+                    "cannot index {} with {}",
+                    value.type_name(),
+                    index.type_name()
+                ),
+            },
+            ExprKind::Attribute(_, attribute) => {
+                bail!("unsupported attribute access: .{}", attribute)
+            }
+            ExprKind::Call {
+                func,
+                args,
+                kwargs,
+                kwargs_mapping,
+            } => {
+                let args: Vec<Value> = args.iter().map(|a| self.eval(a)).collect::<Result<_>>()?;
+                let mut kwargs: Vec<(String, Value)> = kwargs
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), self.eval(v)?)))
+                    .collect::<Result<_>>()?;
+                if let Some(mapping) = kwargs_mapping {
+                    match self.eval(mapping)? {
+                        Value::Dict(items) => kwargs.extend(items),
+                        other => bail!(
+                            "argument after ** must be a dict, not {}",
+                            other.type_name()
+                        ),
+                    }
+                }
+                self.call(func, args, kwargs)?
+            }
+        })
+    }
+
+    fn call(&self, func: &Expr, args: Vec<Value>, kwargs: Vec<(String, Value)>) -> Result<Value> {
+        let single_arg = |name: &str| -> Result<Value> {
+            match (args.as_slice(), kwargs.is_empty()) {
+                ([arg], true) => Ok(arg.clone()),
+                _ => bail!("{}() takes exactly one argument", name),
+            }
+        };
+        match &func.kind {
+            ExprKind::Name(name) if !self.globals.contains_key(name) => match name.as_str() {
+                "Var" => {
+                    let var_name = single_arg("Var")?;
+                    let var_name = var_name
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Var() takes a string"))?;
+                    (self.resolve_var)(var_name, &self.globals)
+                        .ok_or_else(|| anyhow!("Var(\"{}\") unresolved", var_name))
+                }
+                "Str" => Ok(Value::LiteralStr(single_arg("Str")?.to_py_str())),
+                "str" => Ok(Value::Str(single_arg("str")?.to_py_str())),
+                "bool" => Ok(Value::Bool(single_arg("bool")?.is_truthy())),
+                _ => bail!("NameError: name {:?} is not defined", name),
+            },
+            ExprKind::Attribute(object, method) => {
+                let object = self.eval(object)?;
+                match (&object, method.as_str()) {
+                    (Value::Str(s) | Value::LiteralStr(s), "format") => {
+                        Ok(Value::Str(py_format(s, &args, &kwargs)?))
+                    }
+                    (Value::Str(s) | Value::LiteralStr(s), "join") => match single_arg("join")? {
+                        Value::List(items) => Ok(Value::Str(
+                            items
+                                .iter()
+                                .map(|i| i.as_str().map(str::to_string))
+                                .collect::<Option<Vec<_>>>()
+                                .ok_or_else(|| anyhow!("join() takes a list of strings"))?
+                                .join(s),
+                        )),
+                        other => bail!("join() takes a list, not {}", other.type_name()),
+                    },
+                    (Value::Dict(_), "get") => {
+                        let key = args.first().and_then(Value::as_str).unwrap_or_default();
+                        Ok(object
+                            .get(key)
+                            .cloned()
+                            .or_else(|| args.get(1).cloned())
+                            .unwrap_or(Value::None))
+                    }
+                    _ => bail!("unsupported method: {}.{}()", object.type_name(), method),
+                }
+            }
+            _ => bail!("unsupported function call"),
+        }
+    }
+}
+
+fn binop(left: Value, op: BinOp, right: Value) -> Result<Value> {
+    Ok(match (left, op, right) {
+        (Value::Int(a), BinOp::Add, Value::Int(b)) => {
+            Value::Int(a.checked_add(b).context("integer overflow")?)
+        }
+        (Value::Int(a), BinOp::Sub, Value::Int(b)) => {
+            Value::Int(a.checked_sub(b).context("integer overflow")?)
+        }
+        (Value::Int(a), BinOp::Mul, Value::Int(b)) => {
+            Value::Int(a.checked_mul(b).context("integer overflow")?)
+        }
+        (Value::Float(a), BinOp::Add, Value::Float(b)) => Value::Float(a + b),
+        (Value::Float(a), BinOp::Sub, Value::Float(b)) => Value::Float(a - b),
+        (Value::Float(a), BinOp::Mul, Value::Float(b)) => Value::Float(a * b),
+        (Value::List(mut a), BinOp::Add, Value::List(b)) => {
+            a.extend(b);
+            Value::List(a)
+        }
+        (
+            Value::Str(a) | Value::LiteralStr(a),
+            BinOp::Add,
+            Value::Str(b) | Value::LiteralStr(b),
+        ) => Value::Str(a + &b),
+        (Value::Str(s) | Value::LiteralStr(s), BinOp::Mul, Value::Int(n)) => {
+            let n = usize::try_from(n).unwrap_or(0);
+            match s.len().checked_mul(n) {
+                Some(len) if len <= MAX_REPEATED_LEN => Value::Str(s.repeat(n)),
+                _ => bail!(
+                    "string repeated {} times is longer than {} bytes",
+                    n,
+                    MAX_REPEATED_LEN
+                ),
+            }
+        }
+        (Value::Str(template) | Value::LiteralStr(template), BinOp::Mod, args) => {
+            let args = match args {
+                Value::List(items) => items,
+                single => vec![single],
+            };
+            Value::Str(percent_format(&template, &args)?)
+        }
+        (left, op, right) => bail!(
+            "unsupported operand types for {:?}: {} and {}",
+            op,
+            left.type_name(),
+            right.type_name()
+        ),
+    })
+}
+
+pub fn compare(left: &Value, op: CmpOp, right: &Value) -> Result<bool> {
+    let as_number = |v: &Value| match v {
+        Value::Int(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        Value::Bool(b) => Some(*b as i64 as f64),
+        _ => None,
+    };
+    let equal = |a: &Value, b: &Value| match (a.as_str(), b.as_str(), as_number(a), as_number(b)) {
+        (Some(a), Some(b), ..) => a == b,
+        (_, _, Some(a), Some(b)) => a == b,
+        _ => a == b,
+    };
+    Ok(match op {
+        CmpOp::Eq => equal(left, right),
+        CmpOp::NotEq => !equal(left, right),
+        CmpOp::In | CmpOp::NotIn => {
+            let found = match right {
+                Value::List(items) => items.iter().any(|i| equal(left, i)),
+                Value::Dict(items) => items.iter().any(|(k, _)| left.as_str() == Some(k)),
+                Value::Str(s) | Value::LiteralStr(s) => match left.as_str() {
+                    Some(needle) => s.contains(needle),
+                    None => bail!("'in <string>' requires string as left operand"),
+                },
+                other => bail!("argument of type {} is not iterable", other.type_name()),
+            };
+            found == (op == CmpOp::In)
+        }
+        CmpOp::Lt | CmpOp::LtE | CmpOp::Gt | CmpOp::GtE => {
+            let ordering = match (
+                left.as_str(),
+                right.as_str(),
+                as_number(left),
+                as_number(right),
+            ) {
+                (Some(a), Some(b), ..) => a.partial_cmp(b),
+                (_, _, Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot compare {} and {}",
+                    left.type_name(),
+                    right.type_name()
+                )
+            })?;
+            match op {
+                CmpOp::Lt => ordering.is_lt(),
+                CmpOp::LtE => ordering.is_le(),
+                CmpOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            }
+        }
+    })
+}
+
+/// runs a file and returns the selected globals as a json object
+pub fn exec_to_json(
+    interpreter: &mut Interpreter,
+    source: &str,
+    names: &[&str],
+) -> Result<serde_json::Value> {
+    interpreter.exec(source)?;
+    Ok(interpreter.globals_json(names))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Interpreter, Value};
+
+    fn eval(expr: &str) -> Result<Value> {
+        let mut interpreter = Interpreter::without_vars();
+        interpreter.exec(&format!("x = {}", expr))?;
+        Ok(interpreter.globals["x"].clone())
+    }
+
+    fn error(expr: &str) -> String {
+        format!("{:#}", eval(expr).unwrap_err())
+    }
+
+    #[test]
+    fn test_int_overflow() {
+        assert_eq!(
+            eval("9223372036854775806 + 1").unwrap(),
+            Value::Int(i64::MAX)
+        );
+        assert!(error("9223372036854775807 + 1").contains("overflow"));
+        assert!(error("0 - 9223372036854775807 - 2").contains("overflow"));
+        assert!(error("4294967296 * 4294967296").contains("overflow"));
+    }
+
+    #[test]
+    fn test_str_repeat() {
+        assert_eq!(eval("'ab' * 3").unwrap(), Value::Str("ababab".to_string()));
+        assert_eq!(eval("'ab' * -1").unwrap(), Value::Str(String::new()));
+        assert!(error("'ab' * 9223372036854775807").contains("longer than"));
+    }
+
+    #[test]
+    fn test_percent_format() {
+        let str = |s: &str| Value::Str(s.to_string());
+        assert_eq!(eval("'%s@%s' % ('url', 'rev')").unwrap(), str("url@rev"));
+        assert_eq!(eval("'%d%%' % 5").unwrap(), str("5%"));
+        // braces are just text here
+        assert_eq!(eval("'{}/{name}/%s' % 'a'").unwrap(), str("{}/{name}/a"));
+        assert_eq!(eval("'%s' % '{}'").unwrap(), str("{}"));
+        assert!(error("'%s %s' % 'a'").contains("not enough arguments"));
+        assert!(error("'%s' % ('a', 'b')").contains("not all arguments converted"));
+        assert!(error("'%d' % 'a'").contains("number is required"));
+    }
+}
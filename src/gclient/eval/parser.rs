@@ -0,0 +1,468 @@
+use anyhow::{bail, Result};
+
+use super::lexer::{line_col, tokenize, Span, Token, TokenKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtE,
+    Gt,
+    GtE,
+    In,
+    NotIn,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Name(String),
+    /// adjacent literals are already concatenated, like python does
+    Str(String),
+    Int(i64),
+    Float(f64),
+    List(Vec<Expr>),
+    Tuple(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    BinOp(Box<Expr>, BinOp, Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, CmpOp, Box<Expr>),
+    /// `if` expression: (value, condition, value otherwise)
+    IfElse(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+        kwargs: Vec<(String, Expr)>,
+        /// f(**mapping)
+        kwargs_mapping: Option<Box<Expr>>,
+    },
+    Attribute(Box<Expr>, String),
+    Subscript(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Assign { target: String, value: Expr },
+    AugAssign { target: String, value: Expr },
+    Expr(Expr),
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.pos].kind
+    }
+
+    fn peek_at(&self, offset: usize) -> &TokenKind {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.pos + offset).min(last)].kind
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn last_end(&self) -> usize {
+        self.tokens[self.pos.saturating_sub(1)].span.1
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        let (line, col) = line_col(self.source, self.tokens[self.pos].span.0);
+        bail!("{} at line {}, column {}", message, line, col)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), TokenKind::Op(o) if *o == op)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), TokenKind::Name(n) if n == keyword)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.is_op(op) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}, found {:?}", op, self.peek()))
+        }
+    }
+
+    fn expr_from(&self, kind: ExprKind, start: usize) -> Expr {
+        Expr {
+            kind,
+            span: (start, self.last_end()),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        if let TokenKind::Name(target) = self.peek().clone() {
+            if matches!(self.peek_at(1), TokenKind::Op("=")) {
+                self.next();
+                self.next();
+                let value = self.expression_list()?;
+                return Ok(Statement::Assign { target, value });
+            }
+            if matches!(self.peek_at(1), TokenKind::Op("+=")) {
+                self.next();
+                self.next();
+                let value = self.expression_list()?;
+                return Ok(Statement::AugAssign { target, value });
+            }
+            if matches!(
+                target.as_str(),
+                "import" | "from" | "def" | "class" | "if" | "for" | "while" | "with" | "try"
+            ) {
+                return self.error(&format!("{:?} statements are not supported", target));
+            }
+        }
+        Ok(Statement::Expr(self.expression_list()?))
+    }
+
+    /// `a, b` without parentheses is a tuple too
+    fn expression_list(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let first = self.expression()?;
+        if !self.is_op(",") {
+            return Ok(first);
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if matches!(self.peek(), TokenKind::Newline | TokenKind::Eof) {
+                break;
+            }
+            items.push(self.expression()?);
+        }
+        Ok(self.expr_from(ExprKind::Tuple(items), start))
+    }
+
+    pub fn expression(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let value = self.or_test()?;
+        if self.eat_keyword("if") {
+            let condition = self.or_test()?;
+            if !self.eat_keyword("else") {
+                return self.error("expected \"else\"");
+            }
+            let otherwise = self.expression()?;
+            return Ok(self.expr_from(
+                ExprKind::IfElse(Box::new(value), Box::new(condition), Box::new(otherwise)),
+                start,
+            ));
+        }
+        Ok(value)
+    }
+
+    fn or_test(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut left = self.and_test()?;
+        while self.eat_keyword("or") {
+            let right = self.and_test()?;
+            left = self.expr_from(ExprKind::Or(Box::new(left), Box::new(right)), start);
+        }
+        Ok(left)
+    }
+
+    fn and_test(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut left = self.not_test()?;
+        while self.eat_keyword("and") {
+            let right = self.not_test()?;
+            left = self.expr_from(ExprKind::And(Box::new(left), Box::new(right)), start);
+        }
+        Ok(left)
+    }
+
+    fn not_test(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        if self.eat_keyword("not") {
+            let value = self.not_test()?;
+            return Ok(self.expr_from(ExprKind::Not(Box::new(value)), start));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut left = self.arith()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Op("==") => CmpOp::Eq,
+                TokenKind::Op("!=") => CmpOp::NotEq,
+                TokenKind::Op("<") => CmpOp::Lt,
+                TokenKind::Op("<=") => CmpOp::LtE,
+                TokenKind::Op(">") => CmpOp::Gt,
+                TokenKind::Op(">=") => CmpOp::GtE,
+                TokenKind::Name(n) if n == "in" => CmpOp::In,
+                TokenKind::Name(n)
+                    if n == "not" && matches!(self.peek_at(1), TokenKind::Name(i) if i == "in") =>
+                {
+                    self.next();
+                    CmpOp::NotIn
+                }
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.arith()?;
+            left = self.expr_from(
+                ExprKind::Compare(Box::new(left), op, Box::new(right)),
+                start,
+            );
+        }
+    }
+
+    fn arith(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Op("+") => BinOp::Add,
+                TokenKind::Op("-") => BinOp::Sub,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.term()?;
+            left = self.expr_from(ExprKind::BinOp(Box::new(left), op, Box::new(right)), start);
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut left = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                TokenKind::Op("*") => BinOp::Mul,
+                TokenKind::Op("%") => BinOp::Mod,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.factor()?;
+            left = self.expr_from(ExprKind::BinOp(Box::new(left), op, Box::new(right)), start);
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        if self.eat_op("-") {
+            let value = self.factor()?;
+            return Ok(self.expr_from(ExprKind::Neg(Box::new(value)), start));
+        }
+        if self.eat_op("+") {
+            return self.factor();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let mut value = self.atom()?;
+        loop {
+            if self.eat_op("(") {
+                let mut args = vec![];
+                let mut kwargs = vec![];
+                let mut kwargs_mapping = None;
+                while !self.eat_op(")") {
+                    if self.eat_op("**") {
+                        kwargs_mapping = Some(Box::new(self.expression()?));
+                    } else if let (TokenKind::Name(name), TokenKind::Op("=")) =
+                        (self.peek().clone(), self.peek_at(1))
+                    {
+                        self.next();
+                        self.next();
+                        kwargs.push((name, self.expression()?));
+                    } else {
+                        args.push(self.expression()?);
+                    }
+                    if !self.eat_op(",") {
+                        self.expect_op(")")?;
+                        break;
+                    }
+                }
+                value = self.expr_from(
+                    ExprKind::Call {
+                        func: Box::new(value),
+                        args,
+                        kwargs,
+                        kwargs_mapping,
+                    },
+                    start,
+                );
+            } else if self.eat_op(".") {
+                match self.next().kind {
+                    TokenKind::Name(attribute) => {
+                        value =
+                            self.expr_from(ExprKind::Attribute(Box::new(value), attribute), start)
+                    }
+                    other => return self.error(&format!("expected a name, found {:?}", other)),
+                }
+            } else if self.eat_op("[") {
+                let index = self.expression()?;
+                self.expect_op("]")?;
+                value =
+                    self.expr_from(ExprKind::Subscript(Box::new(value), Box::new(index)), start);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// items until `close`, with an optional trailing comma.
+    /// returns whether there was any comma at all (to tell `(a)` from `(a,)`)
+    fn items(&mut self, close: &str) -> Result<(Vec<Expr>, bool)> {
+        let mut items = vec![];
+        let mut had_comma = false;
+        while !self.eat_op(close) {
+            items.push(self.expression()?);
+            if self.eat_op(",") {
+                had_comma = true;
+            } else {
+                self.expect_op(close)?;
+                break;
+            }
+        }
+        Ok((items, had_comma))
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let start = self.tokens[self.pos].span.0;
+        let token_pos = self.pos;
+        let token = self.next();
+        let kind = match token.kind {
+            TokenKind::Str(first) => {
+                let mut value = first;
+                while let TokenKind::Str(next) = self.peek() {
+                    value.push_str(next);
+                    self.next();
+                }
+                ExprKind::Str(value)
+            }
+            TokenKind::Int(i) => ExprKind::Int(i),
+            TokenKind::Float(f) => ExprKind::Float(f),
+            TokenKind::Name(name) => {
+                if matches!(
+                    name.as_str(),
+                    "and" | "or" | "not" | "in" | "if" | "else" | "lambda"
+                ) {
+                    self.pos = token_pos;
+                    return self.error(&format!("unexpected {:?}", name));
+                }
+                ExprKind::Name(name)
+            }
+            TokenKind::Op("(") => {
+                let (mut items, had_comma) = self.items(")")?;
+                if items.len() == 1 && !had_comma {
+                    // just parentheses
+                    return Ok(items.pop().unwrap());
+                }
+                ExprKind::Tuple(items)
+            }
+            TokenKind::Op("[") => ExprKind::List(self.items("]")?.0),
+            TokenKind::Op("{") => {
+                let mut items = vec![];
+                while !self.eat_op("}") {
+                    let key = self.expression()?;
+                    self.expect_op(":")?;
+                    let value = self.expression()?;
+                    items.push((key, value));
+                    if !self.eat_op(",") {
+                        self.expect_op("}")?;
+                        break;
+                    }
+                }
+                ExprKind::Dict(items)
+            }
+            other => {
+                self.pos = token_pos;
+                return self.error(&format!("unexpected {:?}", other));
+            }
+        };
+        Ok(self.expr_from(kind, start))
+    }
+}
+
+/// parses a whole file, like DEPS or .gclient
+pub fn parse_module(source: &str) -> Result<Vec<Statement>> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut statements = vec![];
+    loop {
+        match parser.peek() {
+            TokenKind::Eof => return Ok(statements),
+            TokenKind::Newline => {
+                parser.next();
+            }
+            _ => {
+                statements.push(parser.statement()?);
+                if !matches!(parser.peek(), TokenKind::Newline | TokenKind::Eof) {
+                    return parser.error(&format!("unexpected {:?}", parser.peek()));
+                }
+            }
+        }
+    }
+}
+
+/// parses a single expression, like a dependency condition
+pub fn parse_expression(source: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    while matches!(parser.peek(), TokenKind::Newline) {
+        parser.next();
+    }
+    let expr = parser.expression()?;
+    while matches!(parser.peek(), TokenKind::Newline) {
+        parser.next();
+    }
+    if !matches!(parser.peek(), TokenKind::Eof) {
+        return parser.error(&format!("unexpected {:?}", parser.peek()));
+    }
+    Ok(expr)
+}
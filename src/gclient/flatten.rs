@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::cipd::install::instance_id;
+use crate::cipd::repository::resolve_instance;
+use crate::gclient::cloner::{is_commit_hash, remote_commit, split_git_url, SyncOptions};
+use crate::gclient::revinfo::git_head;
+use crate::gclient::var_utils::SpecVars;
+use crate::types::deps::{CipdPackage, Dependency, DepsSpec, Hook, VarsPrimitive};
+use crate::types::dotgclient::{Dotgclient, Solution};
+
+/// DEPS files of solutions and recursedeps collapsed into one
+#[derive(Debug, Default)]
+pub struct FlatDeps {
+    pub vars: BTreeMap<String, VarsPrimitive>,
+    /// path relative to .gclient -> dependency (pinned to a commit if git,
+    /// to an instance if cipd), and the DEPS file it came from
+    pub deps: BTreeMap<String, (Dependency, String)>,
+    pub hooks: Vec<Hook>,
+    pub pre_deps_hooks: Vec<Hook>,
+    pub gclient_gn_args_file: Option<String>,
+    pub gclient_gn_args: Option<Vec<String>>,
+}
+
+fn py_str(s: &str) -> String {
+    format!(
+        "'{}'",
+        s.replace('\\', "\\\\")
+            .replace('\'', "\\'")
+            .replace('\n', "\\n")
+    )
+}
+
+fn py_var(var: &VarsPrimitive) -> String {
+    match var {
+        VarsPrimitive::String(s) => py_str(s),
+        VarsPrimitive::LiteralString(s) => format!("Str({})", py_str(&s.literal)),
+        VarsPrimitive::Int(i) => i.to_string(),
+        VarsPrimitive::Float(f) => f.to_string(),
+        VarsPrimitive::Bool(true) => "True".to_string(),
+        VarsPrimitive::Bool(false) => "False".to_string(),
+    }
+}
+
+/// "({a}) and ({b})", like gclient merges the condition of a dependency with the one it's in
+fn merge_conditions(a: Option<&str>, b: Option<&str>) -> Option<String> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(format!("({}) and ({})", a, b)),
+        (a, b) => a.or(b).map(str::to_string),
+    }
+}
+
+/// url with the commit from DEPS. a branch or any other ref (or none, for HEAD) is pinned to
+/// the commit checked out if there's a checkout, or to where it is upstream if not synced
+fn pinned_url(root_path: &Path, url_spec: &str, checkout_path: Option<&Path>) -> Result<String> {
+    let (url, rev) = split_git_url(url_spec)?;
+    let commit = match (rev, checkout_path) {
+        (Some(rev), _) if is_commit_hash(&rev) => rev,
+        (_, Some(checkout_path)) => git_head(checkout_path)
+            .with_context(|| format!("cannot pin {:?}, reading its HEAD", checkout_path))?,
+        (rev, None) => remote_commit(root_path, &url, rev.as_deref().unwrap_or("HEAD"))
+            .with_context(|| format!("cannot pin {}", url_spec))?,
+    };
+    Ok(format!("{}@{}", url, commit))
+}
+
+/// the package at the instance its version resolves to, for this host like sync installs it
+async fn pinned_package(package: CipdPackage, opts: &SyncOptions) -> Result<CipdPackage> {
+    let instance = resolve_instance(&opts.cipd_service_url, &package.package, &package.version)
+        .await
+        .with_context(|| format!("resolving {}@{}", package.package, package.version))?;
+    let digest = instance
+        .digest
+        .with_context(|| format!("no digest for {}@{}", package.package, package.version))?;
+    Ok(CipdPackage {
+        package: instance.package,
+        version: instance_id(&digest)?,
+    })
+}
+
+fn relative_to_root(spec: &DepsSpec, solution: &Solution, path: &str) -> String {
+    if spec.use_relative_paths {
+        format!("{}/{}", solution.name, path)
+    } else {
+        path.to_string()
+    }
+}
+
+impl FlatDeps {
+    /// the solution checkout itself, if it's managed by us
+    pub fn add_solution<P: AsRef<Path>>(
+        &mut self,
+        root_path: P,
+        solution: &Solution,
+    ) -> Result<()> {
+        if solution.tpot_no_checkout {
+            return Ok(());
+        }
+        let checkout_path = root_path.as_ref().join(&solution.name);
+        let url = pinned_url(
+            root_path.as_ref(),
+            &solution.url,
+            checkout_path.exists().then_some(checkout_path.as_path()),
+        )?;
+        self.deps.insert(
+            solution.name.clone(),
+            (
+                Dependency::Git {
+                    url,
+                    condition: None,
+                },
+                ".gclient".to_string(),
+            ),
+        );
+        Ok(())
+    }
+
+    /// adds everything from a DEPS file. vars already there are not overriden,
+    /// so the ones closer to .gclient win, except custom_vars which always do.
+    /// what's in a recursedep gets the condition of the dependency it's in.
+    /// all of it is pinned, synced or not, whatever its condition
+    pub async fn add_spec<P: AsRef<Path>>(
+        &mut self,
+        root_path: P,
+        spec: &DepsSpec,
+        solution: &Solution,
+        dotgclient: &Dotgclient,
+        deps_file: &str,
+        opts: &SyncOptions,
+    ) -> Result<()> {
+        let recursedep_condition = match self.deps.get(&solution.name) {
+            Some((Dependency::Git { condition, .. } | Dependency::CIPD { condition, .. }, _))
+                if solution.tpot_internal_from_recursedeps =>
+            {
+                condition.clone()
+            }
+            _ => None,
+        };
+        let spec_vars = SpecVars::new(spec, solution, dotgclient);
+
+        for (name, value) in &spec.vars {
+            self.vars
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+        for (name, value) in solution.custom_vars.iter().flatten() {
+            self.vars.insert(name.clone(), value.clone());
+        }
+
+        for (clone_path, dep_def) in spec.deps_with_custom_deps(solution.custom_deps.as_ref()) {
+            let path = relative_to_root(spec, solution, &clone_path);
+            let dep = match Dependency::from(dep_def) {
+                Dependency::Git { url, condition } => {
+                    let checkout_path = root_path.as_ref().join(&path);
+                    let synced = match &condition {
+                        Some(condition) => spec_vars
+                            .eval_condition(condition)
+                            .with_context(|| format!("condition of {}", path))?,
+                        None => true,
+                    } && checkout_path.exists();
+                    Dependency::Git {
+                        url: pinned_url(
+                            root_path.as_ref(),
+                            &url,
+                            synced.then_some(checkout_path.as_path()),
+                        )?,
+                        condition: merge_conditions(
+                            recursedep_condition.as_deref(),
+                            condition.as_deref(),
+                        ),
+                    }
+                }
+                Dependency::CIPD {
+                    packages,
+                    condition,
+                } => {
+                    let mut pinned = vec![];
+                    for package in packages {
+                        pinned.push(pinned_package(package, opts).await?);
+                    }
+                    Dependency::CIPD {
+                        packages: pinned,
+                        condition: merge_conditions(
+                            recursedep_condition.as_deref(),
+                            condition.as_deref(),
+                        ),
+                    }
+                }
+            };
+            self.deps.insert(path, (dep, deps_file.to_string()));
+        }
+
+        let with_hooks_cwd = |hook: &Hook| {
+            let mut hook = hook.clone();
+            hook.condition =
+                merge_conditions(recursedep_condition.as_deref(), hook.condition.as_deref());
+            if spec.use_relative_hooks {
+                hook.cwd = Some(match &hook.cwd {
+                    Some(cwd) => format!("{}/{}", solution.name, cwd),
+                    None => solution.name.clone(),
+                });
+            }
+            hook
+        };
+        self.hooks.extend(spec.hooks.iter().map(with_hooks_cwd));
+        self.pre_deps_hooks
+            .extend(spec.pre_deps_hooks.iter().map(with_hooks_cwd));
+
+        if self.gclient_gn_args_file.is_none() {
+            if let Some(gn_args_file) = &spec.gclient_gn_args_file {
+                self.gclient_gn_args_file = Some(relative_to_root(spec, solution, gn_args_file));
+                self.gclient_gn_args = spec.gclient_gn_args.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// path of every dependency -> DEPS file it came from
+    pub fn deps_files(&self) -> BTreeMap<&str, &str> {
+        self.deps
+            .iter()
+            .map(|(path, (_, deps_file))| (path.as_str(), deps_file.as_str()))
+            .collect()
+    }
+
+    fn write_hooks(lines: &mut Vec<String>, name: &str, hooks: &[Hook]) {
+        if hooks.is_empty() {
+            return;
+        }
+        lines.push(format!("{} = [", name));
+        for hook in hooks {
+            lines.push("  {".to_string());
+            for (key, value) in [
+                ("name", &hook.name),
+                ("pattern", &hook.pattern),
+                ("condition", &hook.condition),
+                ("cwd", &hook.cwd),
+            ] {
+                if let Some(value) = value {
+                    lines.push(format!("    '{}': {},", key, py_str(value)));
+                }
+            }
+            lines.push("    'action': [".to_string());
+            for arg in &hook.action {
+                lines.push(format!("      {},", py_str(arg)));
+            }
+            lines.push("    ],".to_string());
+            lines.push("  },".to_string());
+        }
+        lines.push("]".to_string());
+        lines.push("".to_string());
+    }
+
+    /// python syntax, like any other DEPS file
+    pub fn to_deps_file(&self) -> String {
+        let mut lines = vec![
+            "# generated by teapot_tools gclient flatten".to_string(),
+            "".to_string(),
+        ];
+
+        if let Some(gn_args_file) = &self.gclient_gn_args_file {
+            lines.push(format!("gclient_gn_args_file = {}", py_str(gn_args_file)));
+            lines.push("gclient_gn_args = [".to_string());
+            for arg in self.gclient_gn_args.iter().flatten() {
+                lines.push(format!("  {},", py_str(arg)));
+            }
+            lines.push("]".to_string());
+            lines.push("".to_string());
+        }
+
+        lines.push("vars = {".to_string());
+        for (name, value) in &self.vars {
+            lines.push(format!("  {}: {},", py_str(name), py_var(value)));
+        }
+        lines.push("}".to_string());
+        lines.push("".to_string());
+
+        lines.push("deps = {".to_string());
+        for (path, (dep, deps_file)) in &self.deps {
+            lines.push(format!("  # {}", deps_file));
+            lines.push(format!("  {}: {{", py_str(path)));
+            let condition = match dep {
+                Dependency::Git { url, condition } => {
+                    lines.push(format!("    'url': {},", py_str(url)));
+                    condition
+                }
+                Dependency::CIPD {
+                    packages,
+                    condition,
+                } => {
+                    lines.push("    'packages': [".to_string());
+                    for package in packages {
+                        lines.push("      {".to_string());
+                        lines.push(format!("        'package': {},", py_str(&package.package)));
+                        lines.push(format!("        'version': {},", py_str(&package.version)));
+                        lines.push("      },".to_string());
+                    }
+                    lines.push("    ],".to_string());
+                    condition
+                }
+            };
+            if let Some(condition) = condition {
+                lines.push(format!("    'condition': {},", py_str(condition)));
+            }
+            lines.push(format!(
+                "    'dep_type': {},",
+                py_str(match dep {
+                    Dependency::Git { .. } => "git",
+                    Dependency::CIPD { .. } => "cipd",
+                })
+            ));
+            lines.push("  },".to_string());
+        }
+        lines.push("}".to_string());
+        lines.push("".to_string());
+
+        Self::write_hooks(&mut lines, "pre_deps_hooks", &self.pre_deps_hooks);
+        Self::write_hooks(&mut lines, "hooks", &self.hooks);
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    use prost::Message;
+
+    use crate::cipd::test_server::serve;
+    use crate::gclient::cloner::SyncOptions;
+    use crate::gclient::deps_parser::parse_deps_native;
+    use crate::test_dir::TestDir;
+    use crate::types::cipd::{InstanceDigest, PackageInstance};
+    use crate::types::deps::VarsPrimitive;
+    use crate::types::dotgclient::{Dotgclient, Solution};
+
+    use super::FlatDeps;
+
+    /// a repository with one commit, the same one every time for the same name
+    fn repository(path: &Path) -> String {
+        fs::create_dir_all(path).unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+                .args(["-c", "commit.gpgsign=false"])
+                .args(args)
+                .current_dir(path)
+                .env("GIT_AUTHOR_DATE", "2020-01-01T00:00:00Z")
+                .env("GIT_COMMITTER_DATE", "2020-01-01T00:00:00Z")
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "-q", "--initial-branch=main"]);
+        fs::write(
+            path.join("name"),
+            path.file_name().unwrap().to_string_lossy().as_bytes(),
+        )
+        .unwrap();
+        git(&["add", "name"]);
+        git(&["commit", "-qm", "first"]);
+        git(&["rev-parse", "HEAD"])
+    }
+
+    #[tokio::test]
+    async fn test_flatten() {
+        let root = TestDir::new("flatten");
+        // what's synced: src/branch is at a commit of main. the others
+        // are pinned in DEPS or their condition is false
+        for dir in ["src/ios_only", "src/rec/third_party/a"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        repository(&root.join("src/branch"));
+        // where the ones that aren't synced are resolved
+        for upstream in [
+            "chromium/not_synced.git",
+            "chromium/ios_only.git",
+            "example/b.git",
+        ] {
+            repository(&root.join("upstream").join(upstream));
+        }
+        let upstream_url =
+            |host: &str| format!("file://{}", root.join("upstream").join(host).display());
+        let custom_vars = Some(HashMap::from([
+            (
+                "chromium_git".to_string(),
+                VarsPrimitive::String(upstream_url("chromium")),
+            ),
+            (
+                "rec_git".to_string(),
+                VarsPrimitive::String(upstream_url("example")),
+            ),
+        ]));
+        let service_url = serve(|_, _| {
+            let instance = PackageInstance {
+                package: "infra/tools/x/linux-amd64".to_string(),
+                digest: Some(InstanceDigest {
+                    algorithm: 2,
+                    hex_digest: "ab".repeat(32),
+                }),
+                publisher: "".to_string(),
+            };
+            (200, instance.encode_to_vec())
+        })
+        .await;
+        let opts = SyncOptions {
+            cipd_service_url: service_url,
+            ..Default::default()
+        };
+        let dotgclient = Dotgclient::default();
+        let solution = Solution {
+            name: "src".to_string(),
+            url: format!(
+                "https://chromium.googlesource.com/src.git@{}",
+                "0".repeat(40)
+            ),
+            custom_vars: custom_vars.clone(),
+            ..Default::default()
+        };
+        let recursedep = Solution {
+            name: "src/rec".to_string(),
+            deps_file: Some("DEPS.rec".to_string()),
+            custom_vars,
+            tpot_no_checkout: true,
+            tpot_internal_from_recursedeps: true,
+            ..Default::default()
+        };
+
+        let mut flat_deps = FlatDeps::default();
+        for (solution, deps_file) in [(&solution, "DEPS"), (&recursedep, "DEPS.rec")] {
+            let spec = parse_deps_native(
+                &fs::read_to_string(format!("tests/fixtures/flatten/{}", deps_file)).unwrap(),
+                solution,
+                &dotgclient,
+            )
+            .unwrap();
+            flat_deps.add_solution(&root, solution).unwrap();
+            let deps_file = format!("{}/{}", solution.name, deps_file);
+            flat_deps
+                .add_spec(&root, &spec, solution, &dotgclient, &deps_file, &opts)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            flat_deps
+                .to_deps_file()
+                .replace(
+                    &upstream_url("chromium"),
+                    "https://chromium.googlesource.com"
+                )
+                .replace(&upstream_url("example"), "https://example.com"),
+            fs::read_to_string("tests/fixtures/flatten/flattened").unwrap()
+        );
+        assert_eq!(
+            flat_deps.deps_files()["src/rec/third_party/a"],
+            "src/rec/DEPS.rec"
+        );
+    }
+}
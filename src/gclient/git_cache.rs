@@ -0,0 +1,370 @@
+//! Bare git mirrors shared between checkouts, like depot_tools' git_cache.py.
+//! Checkouts borrow the objects through git alternates, so each of them only
+//! has to fetch what the mirror doesn't have yet.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use path_absolutize::Absolutize;
+use url::Url;
+
+use crate::gclient::cloner::{git, local_commit, SyncOptions};
+
+/// overrides `cache_dir` from .gclient
+pub const CACHE_DIR_ENV: &str = "GIT_CACHE_PATH";
+
+/// how long to wait for another sync to finish with a mirror
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// cache directory from the environment, or from .gclient. relative paths are relative to
+/// the .gclient directory, and made absolute, since they end up in alternates of checkouts
+pub fn cache_dir<P: AsRef<Path>>(
+    root_path: P,
+    dotgclient_cache_dir: Option<&str>,
+) -> Option<PathBuf> {
+    env::var_os(CACHE_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dotgclient_cache_dir.map(PathBuf::from))
+        .map(|dir| {
+            let dir = root_path.as_ref().join(dir);
+            dir.absolutize().map(|d| d.to_path_buf()).unwrap_or(dir)
+        })
+}
+
+/// same naming as depot_tools, so the caches can be shared with it
+pub fn url_to_cache_dir(url: &str) -> String {
+    let norm_url = match Url::parse(url) {
+        Ok(parsed) => format!("{}{}", parsed.host_str().unwrap_or_default(), parsed.path()),
+        Err(_) => url.to_string(),
+    };
+    let norm_url = norm_url.strip_suffix(".git").unwrap_or(&norm_url);
+    // authenticated and unauthenticated urls are the same repository
+    norm_url
+        .replace("googlesource.com/a/", "googlesource.com/")
+        .replace(':', "__")
+        .replace('-', "--")
+        .replace('/', "-")
+        .to_lowercase()
+}
+
+/// lock file next to the mirror, removed when dropped.
+/// created exclusively, so it works between processes and threads alike
+struct MirrorLock {
+    path: PathBuf,
+}
+
+impl MirrorLock {
+    fn acquire(mirror_path: &Path, opts: &SyncOptions) -> Result<Self> {
+        let mut path = mirror_path.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let started = Instant::now();
+        let mut announced = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(MirrorLock { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if is_stale_lock(&path) {
+                        if opts.verbosity >= 1 {
+                            println!("taking over stale lock {:?}", path);
+                        }
+                        take_over_stale_lock(&path);
+                        continue;
+                    }
+                    if started.elapsed() > LOCK_TIMEOUT {
+                        bail!(
+                            "timed out waiting for {:?}, remove it if no other sync is running",
+                            path
+                        );
+                    }
+                    if !announced && opts.verbosity >= 1 {
+                        println!("waiting for another sync to release {:?}", path);
+                        announced = true;
+                    }
+                    sleep(Duration::from_millis(250));
+                }
+                Err(e) => return Err(e).with_context(|| format!("creating lock file {:?}", path)),
+            }
+        }
+    }
+}
+
+/// left behind by a sync that died: its process is gone, or it is older than
+/// anyone would wait for it
+fn is_stale_lock(path: &Path) -> bool {
+    let pid = fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse::<u32>().ok());
+    if let Some(alive) = pid.and_then(process_alive) {
+        return !alive;
+    }
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > LOCK_TIMEOUT)
+}
+
+/// removes a lock found stale. syncs finding it at the same time would otherwise race,
+/// the slower one removing the lock the faster one just took. it's renamed first, so
+/// only one of them gets it, and that one makes sure it's still the stale one
+fn take_over_stale_lock(path: &Path) {
+    let taken = path.with_extension(format!("lock.stale.{}", std::process::id()));
+    if fs::rename(path, &taken).is_err() {
+        return;
+    }
+    if !is_stale_lock(&taken) {
+        // someone else's by now. back where it was, unless there's another one already
+        let _ = fs::hard_link(&taken, path);
+    }
+    let _ = fs::remove_file(&taken);
+}
+
+/// None when it can't be told, without /proc
+fn process_alive(pid: u32) -> Option<bool> {
+    let proc = Path::new("/proc");
+    if !proc.join("self").exists() {
+        return None;
+    }
+    Some(proc.join(pid.to_string()).exists())
+}
+
+impl Drop for MirrorLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// name of the ref keeping a fetched commit from being garbage collected in the mirror
+pub fn mirror_ref(commit: &str) -> String {
+    format!("refs/tpot/{}", commit)
+}
+
+/// makes sure the mirror of `url` has `git_ref` (or the remote HEAD if none),
+/// and returns the mirror path with the commit it resolved to.
+/// commits already in the mirror are not fetched again
+pub fn update_mirror(
+    cache_dir: &Path,
+    url: &str,
+    git_ref: Option<&str>,
+    opts: &SyncOptions,
+) -> Result<(PathBuf, String)> {
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("creating git cache directory {:?}", cache_dir))?;
+    let mirror_path = cache_dir.join(url_to_cache_dir(url));
+    let _lock = MirrorLock::acquire(&mirror_path, opts)?;
+
+    if !mirror_path.join("HEAD").exists() {
+        if opts.verbosity >= 1 {
+            println!("creating git mirror of {} in {:?}", url, mirror_path);
+        }
+        fs::create_dir_all(&mirror_path)?;
+        git(&mirror_path, &["init", "--bare", "--initial-branch=master"])?;
+        git(&mirror_path, &["config", "remote.origin.url", url])?;
+        // fetching by commit hash from the mirror, for checkouts
+        git(
+            &mirror_path,
+            &["config", "uploadpack.allowAnySHA1InWant", "true"],
+        )?;
+    }
+
+    let commit = match git_ref.and_then(|rev| local_commit(&mirror_path, rev)) {
+        Some(commit) => commit,
+        None => {
+            if opts.verbosity >= 1 {
+                println!(
+                    "fetching {} of {} into the git cache",
+                    git_ref.unwrap_or("HEAD"),
+                    url
+                );
+            }
+            git(
+                &mirror_path,
+                &[
+                    "fetch",
+                    "origin",
+                    git_ref.unwrap_or("HEAD"),
+                    &format!("--jobs={}", opts.git_jobs),
+                ],
+            )?;
+            git(&mirror_path, &["rev-parse", "FETCH_HEAD^{commit}"])?
+        }
+    };
+    git(&mirror_path, &["update-ref", &mirror_ref(&commit), &commit])?;
+    Ok((mirror_path, commit))
+}
+
+/// lets the checkout use objects from the mirror instead of having its own copies
+pub fn add_alternate<P: AsRef<Path>>(checkout_path: P, mirror_path: &Path) -> Result<()> {
+    let objects = mirror_path.join("objects");
+    let alternates = checkout_path.as_ref().join(".git/objects/info/alternates");
+    let existing = fs::read_to_string(&alternates).unwrap_or_default();
+    let objects_line = objects.to_string_lossy();
+    if existing.lines().any(|line| line == objects_line) {
+        return Ok(());
+    }
+    if let Some(parent) = alternates.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&alternates, format!("{}{}\n", existing, objects_line))
+        .with_context(|| format!("writing {:?}", alternates))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::process::Command;
+    use std::time::{Duration, SystemTime};
+
+    use super::{
+        is_stale_lock, mirror_ref, take_over_stale_lock, url_to_cache_dir, MirrorLock, LOCK_TIMEOUT,
+    };
+    use crate::gclient::cloner::{git, git_clone, SyncOptions};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_stale_lock() {
+        let dir = TestDir::new("mirror_lock");
+        let mirror = dir.join("mirror");
+        let lock = dir.join("mirror.lock");
+        let opts = SyncOptions {
+            verbosity: -1,
+            ..Default::default()
+        };
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        fs::write(&lock, std::process::id().to_string()).unwrap();
+        let live = is_stale_lock(&lock);
+        fs::write(&lock, dead_pid.to_string()).unwrap();
+        let dead = is_stale_lock(&lock);
+        fs::write(&lock, "").unwrap();
+        let fresh = is_stale_lock(&lock);
+        File::options()
+            .write(true)
+            .open(&lock)
+            .unwrap()
+            .set_modified(SystemTime::now() - LOCK_TIMEOUT - Duration::from_secs(60))
+            .unwrap();
+        let old = is_stale_lock(&lock);
+        let taken = MirrorLock::acquire(&mirror, &opts).map(|_lock| fs::read_to_string(&lock));
+
+        assert!(!live);
+        assert!(dead);
+        assert!(!fresh);
+        assert!(old);
+        assert_eq!(taken.unwrap().unwrap(), std::process::id().to_string());
+    }
+
+    #[test]
+    fn test_take_over_stale_lock() {
+        let dir = TestDir::new("mirror_lock_takeover");
+        let lock = dir.join("mirror.lock");
+
+        // another sync found it stale first, and took it since
+        fs::write(&lock, std::process::id().to_string()).unwrap();
+        take_over_stale_lock(&lock);
+        let kept = fs::read_to_string(&lock);
+        fs::write(&lock, "").unwrap();
+        File::options()
+            .write(true)
+            .open(&lock)
+            .unwrap()
+            .set_modified(SystemTime::now() - LOCK_TIMEOUT - Duration::from_secs(60))
+            .unwrap();
+        take_over_stale_lock(&lock);
+
+        assert_eq!(kept.unwrap(), std::process::id().to_string());
+        assert!(!lock.exists());
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_sync_through_cache() {
+        let dir = TestDir::new("git_cache_sync");
+        let (upstream, cache_dir, checkout) = (
+            dir.join("upstream"),
+            dir.join("cache"),
+            dir.join("checkout"),
+        );
+        fs::create_dir_all(&upstream).unwrap();
+        fs::create_dir_all(&checkout).unwrap();
+        git(&upstream, &["init", "-q"]).unwrap();
+        let commit = |contents: &str| {
+            fs::write(upstream.join("a"), contents).unwrap();
+            git(&upstream, &["add", "a"]).unwrap();
+            git(
+                &upstream,
+                &[
+                    "-c",
+                    "user.name=t",
+                    "-c",
+                    "user.email=t@t",
+                    "commit",
+                    "-qm",
+                    contents,
+                ],
+            )
+            .unwrap();
+            git(&upstream, &["rev-parse", "HEAD"]).unwrap()
+        };
+        let url = format!("file://{}", upstream.display());
+        let opts = SyncOptions {
+            verbosity: -1,
+            cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        };
+        let mirror = cache_dir.join(url_to_cache_dir(&url));
+
+        let first = commit("first");
+        let synced = git_clone(&format!("{}@{}", url, first), &checkout, &opts).unwrap();
+        let alternates = fs::read_to_string(checkout.join(".git/objects/info/alternates"));
+        // all of it is borrowed from the mirror
+        let objects = git(&checkout, &["count-objects", "-v"]).unwrap();
+        // no rev is the remote HEAD, fetched into the mirror
+        let second = commit("second");
+        let updated = git_clone(&url, &checkout, &opts).unwrap();
+
+        assert_eq!(synced, first);
+        assert_eq!(
+            alternates.unwrap(),
+            format!("{}\n", mirror.join("objects").display())
+        );
+        assert!(objects.contains("count: 0"), "{}", objects);
+        assert!(objects.contains("in-pack: 0"), "{}", objects);
+        assert_eq!(updated, second);
+        assert_eq!(git(&checkout, &["rev-parse", "HEAD"]).unwrap(), second);
+        assert_eq!(fs::read_to_string(checkout.join("a")).unwrap(), "second");
+        for commit in [&first, &second] {
+            assert_eq!(
+                git(&mirror, &["rev-parse", &mirror_ref(commit)]).unwrap(),
+                *commit
+            );
+        }
+    }
+
+    #[test]
+    fn test_url_to_cache_dir() {
+        assert_eq!(
+            url_to_cache_dir("https://chromium.googlesource.com/a/chromium/src.git"),
+            "chromium.googlesource.com-chromium-src"
+        );
+        assert_eq!(
+            url_to_cache_dir(
+                "https://chromium.googlesource.com/external/github.com/KhronosGroup/OpenXR-SDK"
+            ),
+            "chromium.googlesource.com-external-github.com-khronosgroup-openxr--sdk"
+        );
+    }
+}
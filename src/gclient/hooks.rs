@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use crate::error::Error;
+use crate::gclient::cloner::SyncOptions;
+use crate::gclient::var_utils::SpecVars;
+use crate::types::deps::{DepsSpec, Hook};
+use crate::types::dotgclient::{Dotgclient, Solution};
+
+impl Hook {
+    pub fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.action.join(" "))
+    }
+}
+
+/// directory the hooks from this DEPS are run in (without the hook's own `cwd`)
+pub fn hooks_cwd<P: AsRef<Path>>(root_path: P, spec: &DepsSpec, solution: &Solution) -> PathBuf {
+    if spec.use_relative_hooks {
+        root_path.as_ref().join(&solution.name)
+    } else {
+        root_path.as_ref().to_path_buf()
+    }
+}
+
+/// filters out the hooks with conditions not met, using the same vars as dependencies
+pub fn hooks_matching_conditions(
+    hooks: &[Hook],
+    spec: &DepsSpec,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+) -> Result<Vec<Hook>, Error> {
+    let spec_vars = SpecVars::new(spec, solution, dotgclient);
+    let mut matching = vec![];
+    for hook in hooks {
+        if let Some(condition) = &hook.condition {
+            let status = spec_vars
+                .eval_condition(condition)
+                .map_err(|e| Error::condition(&format!("hook {}", hook.display_name()), e))?;
+            if !status {
+                continue;
+            }
+        }
+        matching.push(hook.clone());
+    }
+    Ok(matching)
+}
+
+pub fn run_hook<P: AsRef<Path>>(
+    hook: &Hook,
+    hooks_cwd: P,
+    opts: &SyncOptions,
+) -> Result<(), Error> {
+    run_hook_action(hook, hooks_cwd.as_ref(), opts)
+        .map_err(|e| Error::hook(&hook.display_name(), e))
+}
+
+/// gclient runs these with its own python, and we don't ship vpython
+fn hook_program(program: &str) -> &str {
+    match program {
+        "python" | "vpython" | "vpython3" => "python3",
+        p => p,
+    }
+}
+
+fn run_hook_action(hook: &Hook, hooks_cwd: &Path, opts: &SyncOptions) -> Result<()> {
+    let name = hook.display_name();
+    let cwd = match &hook.cwd {
+        Some(cwd) => hooks_cwd.join(cwd),
+        None => hooks_cwd.to_path_buf(),
+    };
+    let (program, args) = match hook.action.split_first() {
+        Some(action) => action,
+        None => bail!("empty action"),
+    };
+    let program = hook_program(program);
+    if opts.verbosity >= 0 {
+        println!("running hook {}", name);
+    }
+    if opts.verbosity >= 1 {
+        println!("{} {} (in {:?})", program, args.join(" "), cwd);
+    }
+    let status = Command::new(program)
+        .args(args)
+        .current_dir(&cwd)
+        .status()
+        .with_context(|| format!("spawning {}", program))?;
+    match status.code() {
+        Some(0) => {}
+        Some(code) => bail!("failed with exit code {}", code),
+        None => bail!("terminated by a signal"),
+    }
+    Ok(())
+}
+
+pub fn run_hooks<P: AsRef<Path>>(
+    hooks: &[Hook],
+    hooks_cwd: P,
+    opts: &SyncOptions,
+) -> Result<(), Error> {
+    for hook in hooks {
+        run_hook(hook, &hooks_cwd, opts)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::types::deps::{DepsSpec, Hook, VarsPrimitive};
+    use crate::types::dotgclient::{Dotgclient, Solution};
+    use crate::types::machine::GclientOS;
+
+    use super::{hook_program, hooks_cwd, hooks_matching_conditions};
+
+    fn hook(name: &str, condition: Option<&str>) -> Hook {
+        Hook {
+            name: Some(name.to_string()),
+            pattern: None,
+            action: vec!["true".to_string()],
+            condition: condition.map(str::to_string),
+            cwd: None,
+        }
+    }
+
+    #[test]
+    fn test_hooks_matching_conditions() {
+        let spec = DepsSpec {
+            vars: HashMap::from([("download_foo".to_string(), VarsPrimitive::Bool(false))]),
+            ..Default::default()
+        };
+        let solution = Solution {
+            name: "src".to_string(),
+            custom_vars: Some(HashMap::from([(
+                "download_bar".to_string(),
+                VarsPrimitive::Bool(true),
+            )])),
+            ..Default::default()
+        };
+        let dotgclient = Dotgclient {
+            target_os: vec![GclientOS::Android],
+            ..Default::default()
+        };
+        let hooks = [
+            hook("always", None),
+            hook("android", Some("checkout_android")),
+            hook("ios", Some("checkout_ios")),
+            hook("foo", Some("download_foo")),
+            hook("bar", Some("download_bar and not checkout_ios")),
+        ];
+        let matching = hooks_matching_conditions(&hooks, &spec, &solution, &dotgclient).unwrap();
+        let names: Vec<_> = matching.iter().map(|h| h.display_name()).collect();
+        assert_eq!(names, ["always", "android", "bar"]);
+
+        let broken = [hook("broken", Some("no_such_var"))];
+        assert!(hooks_matching_conditions(&broken, &spec, &solution, &dotgclient).is_err());
+    }
+
+    #[test]
+    fn test_hooks_cwd() {
+        let solution = Solution {
+            name: "src".to_string(),
+            ..Default::default()
+        };
+        let mut spec = DepsSpec::default();
+        assert_eq!(hooks_cwd("/work", &spec, &solution), Path::new("/work"));
+        spec.use_relative_hooks = true;
+        assert_eq!(hooks_cwd("/work", &spec, &solution), Path::new("/work/src"));
+    }
+
+    #[test]
+    fn test_hook_program() {
+        assert_eq!(hook_program("python"), "python3");
+        assert_eq!(hook_program("vpython"), "python3");
+        assert_eq!(hook_program("vpython3"), "python3");
+        assert_eq!(hook_program("python3"), "python3");
+        assert_eq!(
+            hook_program("download_from_google_storage"),
+            "download_from_google_storage"
+        );
+    }
+}
@@ -1,6 +1,14 @@
 pub mod cloner;
+pub mod deps_editor;
 pub mod deps_parser;
 pub mod dotgclient;
 pub mod entries_cache;
+pub mod eval;
+pub mod flatten;
+pub mod git_cache;
 pub mod gn_args;
+pub mod hooks;
+pub mod revinfo;
+pub mod sync_report;
+pub mod unversioned_trees;
 pub mod var_utils;
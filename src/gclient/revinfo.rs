@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cipd::repository::resolve_instance;
+use crate::gclient::cloner::{deps_matching_conditions, git, split_git_url, SyncOptions};
+use crate::types::deps::{Dependency, DepsSpec};
+use crate::types::dotgclient::{Dotgclient, Solution};
+
+/// A single line of revinfo, either a git checkout or a cipd package
+#[derive(Serialize, Debug, Clone)]
+pub struct RevinfoEntry {
+    /// path relative to the .gclient directory, suffixed with ":{package}" if cipd
+    #[serde(skip)]
+    pub path: String,
+    pub url: String,
+    pub rev: Option<String>,
+}
+
+impl RevinfoEntry {
+    pub fn to_line(&self) -> String {
+        match &self.rev {
+            Some(rev) => format!("{}: {}@{}", self.path, self.url, rev),
+            None => format!("{}: {}", self.path, self.url),
+        }
+    }
+}
+
+/// `git rev-parse HEAD` of an existing checkout
+pub fn git_head<P: AsRef<Path>>(checkout_path: P) -> Result<String> {
+    git(checkout_path, &["rev-parse", "HEAD"])
+}
+
+/// path -> {"url", "rev"}, like --output-json of gclient revinfo
+pub fn revinfo_json(entries: &[RevinfoEntry]) -> Result<String> {
+    let json: BTreeMap<_, _> = entries.iter().map(|e| (&e.path, e)).collect();
+    Ok(serde_json::to_string_pretty(&json)?)
+}
+
+fn git_entry(path: String, url_spec: &str, actual_path: Option<&Path>) -> Result<RevinfoEntry> {
+    let (url, rev) = split_git_url(url_spec)?;
+    let rev = match actual_path {
+        Some(checkout_path) => {
+            Some(git_head(checkout_path).with_context(|| format!("reading HEAD of {}", path))?)
+        }
+        None => rev,
+    };
+    Ok(RevinfoEntry { path, url, rev })
+}
+
+/// the solution checkout itself, if it's managed by us
+pub fn solution_revinfo<P: AsRef<Path>>(
+    root_path: P,
+    solution: &Solution,
+    actual: bool,
+) -> Result<Option<RevinfoEntry>> {
+    if solution.tpot_no_checkout {
+        return Ok(None);
+    }
+    let checkout_path = root_path.as_ref().join(&solution.name);
+    git_entry(
+        solution.name.clone(),
+        &solution.url,
+        actual.then_some(checkout_path.as_path()),
+    )
+    .map(Some)
+}
+
+/// resolves every dependency of the DEPS file that matches its condition,
+/// without cloning anything. with `actual`, git revisions are read from the checkouts instead.
+pub async fn deps_revinfo<P: AsRef<Path>>(
+    spec: &DepsSpec,
+    root_path: P,
+    solution: &Solution,
+    dotgclient: &Dotgclient,
+    actual: bool,
+    opts: &SyncOptions,
+) -> Result<Vec<RevinfoEntry>> {
+    let mut entries = vec![];
+    for (clone_path, dep, _) in deps_matching_conditions(spec, solution, dotgclient, opts)? {
+        // paths are displayed relative to .gclient, like in gclient
+        let path = if spec.use_relative_paths {
+            format!("{}/{}", solution.name, clone_path)
+        } else {
+            clone_path
+        };
+        match dep {
+            Dependency::Git { url, .. } => {
+                let checkout_path = root_path.as_ref().join(&path);
+                entries.push(git_entry(
+                    path,
+                    &url,
+                    actual.then_some(checkout_path.as_path()),
+                )?);
+            }
+            Dependency::CIPD { packages, .. } => {
+                for package in packages {
+                    let instance = resolve_instance(
+                        &opts.cipd_service_url,
+                        &package.package,
+                        &package.version,
+                    )
+                    .await
+                    .with_context(|| {
+                        format!("resolving {}@{}", package.package, package.version)
+                    })?;
+                    entries.push(RevinfoEntry {
+                        path: format!("{}:{}", path, package.package),
+                        url: instance.package,
+                        rev: instance.digest.map(|d| d.hex_digest),
+                    });
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use prost::Message;
+
+    use crate::cipd::test_server::serve;
+    use crate::gclient::cloner::{git, SyncOptions};
+    use crate::test_dir::TestDir;
+    use crate::types::cipd::{InstanceDigest, PackageInstance, ResolveVersionRequest};
+    use crate::types::deps::{DependencyDef, DepsSpec};
+    use crate::types::dotgclient::{Dotgclient, Solution};
+
+    use super::{deps_revinfo, revinfo_json, solution_revinfo, RevinfoEntry};
+
+    const REV: &str = "0123456789012345678901234567890123456789";
+
+    fn spec(use_relative_paths: bool) -> DepsSpec {
+        serde_json::from_value(serde_json::json!({
+            "use_relative_paths": use_relative_paths,
+            "deps": {
+                "third_party/a": format!("https://example.com/a.git@{}", REV),
+                "third_party/b": {
+                    "url": "https://example.com/b.git",
+                    "condition": "checkout_linux or not checkout_linux",
+                },
+                "third_party/never": {
+                    "url": "https://example.com/never.git",
+                    "condition": "False",
+                },
+                "tools": {
+                    "packages": [{"package": "infra/tools/x", "version": "version:1"}],
+                    "dep_type": "cipd",
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn lines(entries: &[RevinfoEntry]) -> Vec<String> {
+        entries.iter().map(RevinfoEntry::to_line).collect()
+    }
+
+    #[tokio::test]
+    async fn test_deps_revinfo() {
+        let service_url = serve(|_, body| {
+            let request = ResolveVersionRequest::decode(body).unwrap();
+            let instance = PackageInstance {
+                package: request.package,
+                digest: Some(InstanceDigest {
+                    algorithm: 2,
+                    hex_digest: "ab".repeat(32),
+                }),
+                publisher: "".to_string(),
+            };
+            (200, instance.encode_to_vec())
+        })
+        .await;
+        let opts = SyncOptions {
+            cipd_service_url: service_url,
+            ..Default::default()
+        };
+        let solution = Solution {
+            name: "src".to_string(),
+            url: format!("https://example.com/src.git@{}", REV),
+            ..Default::default()
+        };
+        let dotgclient = Dotgclient::default();
+
+        let entries = deps_revinfo(&spec(false), "/w", &solution, &dotgclient, false, &opts)
+            .await
+            .unwrap();
+        assert_eq!(
+            lines(&entries),
+            [
+                format!("third_party/a: https://example.com/a.git@{}", REV),
+                "third_party/b: https://example.com/b.git".to_string(),
+                format!("tools:infra/tools/x: infra/tools/x@{}", "ab".repeat(32)),
+            ]
+        );
+
+        // paths are relative to .gclient
+        let entries = deps_revinfo(&spec(true), "/w", &solution, &dotgclient, false, &opts)
+            .await
+            .unwrap();
+        assert_eq!(entries[0].path, "src/third_party/a");
+        assert_eq!(entries[2].path, "src/tools:infra/tools/x");
+
+        let solution_entry = solution_revinfo("/w", &solution, false).unwrap().unwrap();
+        assert_eq!(
+            solution_entry.to_line(),
+            format!("src: https://example.com/src.git@{}", REV)
+        );
+        let internal = Solution {
+            tpot_no_checkout: true,
+            ..solution
+        };
+        assert!(solution_revinfo("/w", &internal, false).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deps_revinfo_actual() {
+        let root = TestDir::new("revinfo");
+        let checkout = root.join("third_party/b");
+        fs::create_dir_all(&checkout).unwrap();
+        let head = git(&checkout, &["init", "-q"])
+            .and_then(|_| {
+                git(
+                    &checkout,
+                    &[
+                        "-c",
+                        "user.name=t",
+                        "-c",
+                        "user.email=t@t",
+                        "commit",
+                        "-q",
+                        "--allow-empty",
+                        "-m",
+                        "synced",
+                    ],
+                )
+            })
+            .and_then(|_| git(&checkout, &["rev-parse", "HEAD"]));
+        let mut spec = spec(false);
+        spec.deps = HashMap::from([(
+            "third_party/b".to_string(),
+            DependencyDef::Simple(format!("https://example.com/b.git@{}", REV)),
+        )]);
+        let entries = deps_revinfo(
+            &spec,
+            &root,
+            &Solution::default(),
+            &Dotgclient::default(),
+            true,
+            &SyncOptions::default(),
+        )
+        .await;
+        let not_synced = deps_revinfo(
+            &spec,
+            root.join("nope"),
+            &Solution::default(),
+            &Dotgclient::default(),
+            true,
+            &SyncOptions::default(),
+        )
+        .await;
+
+        // what's checked out, not what DEPS says
+        let head = head.unwrap();
+        assert_ne!(head, REV);
+        assert_eq!(
+            lines(&entries.unwrap()),
+            [format!("third_party/b: https://example.com/b.git@{}", head)]
+        );
+        assert!(format!("{:#}", not_synced.unwrap_err()).contains("reading HEAD of third_party/b"));
+    }
+
+    #[test]
+    fn test_revinfo_json() {
+        let entries = [
+            RevinfoEntry {
+                path: "src".to_string(),
+                url: "https://example.com/src.git".to_string(),
+                rev: Some(REV.to_string()),
+            },
+            RevinfoEntry {
+                path: "src/tools:infra/tools/x".to_string(),
+                url: "infra/tools/x".to_string(),
+                rev: None,
+            },
+        ];
+        let json: serde_json::Value =
+            serde_json::from_str(&revinfo_json(&entries).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "src": {"url": "https://example.com/src.git", "rev": REV},
+                "src/tools:infra/tools/x": {"url": "infra/tools/x", "rev": null},
+            })
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! `sync --output-json`: what a sync did to every solution and dependency.
+//! laid out like depot_tools' `--output-json`, with a few more fields.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncAction {
+    Cloned,
+    Updated,
+    Deleted,
+    /// unchanged since the last sync
+    Skipped,
+    Failed,
+}
+
+/// A solution, a git dependency or a single cipd package
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncReportEntry {
+    /// name relative to the .gclient directory, suffixed with ":{package}" if cipd
+    #[serde(skip)]
+    pub name: String,
+    /// absolute path of the checkout
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// "git" or "cipd", none for unversioned trees deleted by -D
+    pub scm: Option<&'static str>,
+    pub url: Option<String>,
+    /// revision from .gclient, DEPS or --revision
+    pub requested_revision: Option<String>,
+    /// commit hash or cipd instance id it's synced to
+    pub revision: Option<String>,
+    pub action: SyncAction,
+    pub was_processed: bool,
+    pub was_synced: bool,
+    /// seconds
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloaded_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SyncReportEntry {
+    pub fn new(name: String, path: PathBuf, scm: Option<&'static str>, action: SyncAction) -> Self {
+        SyncReportEntry {
+            name,
+            path,
+            scm,
+            url: None,
+            requested_revision: None,
+            revision: None,
+            action,
+            was_processed: action != SyncAction::Deleted,
+            was_synced: matches!(action, SyncAction::Cloned | SyncAction::Updated),
+            duration: 0.0,
+            downloaded_bytes: None,
+            error: None,
+        }
+    }
+
+    pub fn with_duration(self, duration: Duration) -> Self {
+        SyncReportEntry {
+            duration: duration.as_secs_f64(),
+            ..self
+        }
+    }
+}
+
+/// {"solutions": {"{name}/": entry}}, like depot_tools
+pub fn write_sync_report<P: AsRef<Path>>(path: P, entries: &[SyncReportEntry]) -> Result<()> {
+    let solutions: BTreeMap<_, _> = entries
+        .iter()
+        .map(|e| (format!("{}/", e.name), e))
+        .collect();
+    fs::write(
+        &path,
+        serde_json::to_string_pretty(&json!({ "solutions": solutions }))?,
+    )
+    .with_context(|| format!("writing sync report: {:?}", path.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::test_dir::TestDir;
+
+    use super::{write_sync_report, SyncAction, SyncReportEntry};
+
+    #[test]
+    fn test_write_sync_report() {
+        let cloned = SyncReportEntry {
+            url: Some("https://example.com/src.git".to_string()),
+            requested_revision: Some("main".to_string()),
+            revision: Some("0".repeat(40)),
+            ..SyncReportEntry::new(
+                "src".to_string(),
+                PathBuf::from("/w/src"),
+                Some("git"),
+                SyncAction::Cloned,
+            )
+        }
+        .with_duration(Duration::from_millis(1500));
+        let package = SyncReportEntry {
+            downloaded_bytes: Some(1024),
+            ..SyncReportEntry::new(
+                "src/tools:infra/tools/x".to_string(),
+                PathBuf::from("/w/src/tools"),
+                Some("cipd"),
+                SyncAction::Updated,
+            )
+        };
+        let failed = SyncReportEntry {
+            action: SyncAction::Failed,
+            was_synced: false,
+            error: Some("nope".to_string()),
+            ..SyncReportEntry::new(
+                "src/a".to_string(),
+                PathBuf::from("/w/src/a"),
+                Some("git"),
+                SyncAction::Updated,
+            )
+        };
+        let deleted = SyncReportEntry::new(
+            "src/old".to_string(),
+            PathBuf::from("/w/src/old"),
+            None,
+            SyncAction::Deleted,
+        );
+        let dir = TestDir::new("sync_report");
+        let path = dir.join("report.json");
+
+        write_sync_report(&path, &[cloned, package, failed, deleted]).unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            report,
+            json!({"solutions": {
+                "src/": {
+                    "scm": "git",
+                    "url": "https://example.com/src.git",
+                    "requested_revision": "main",
+                    "revision": "0".repeat(40),
+                    "action": "cloned",
+                    "was_processed": true,
+                    "was_synced": true,
+                    "duration": 1.5,
+                },
+                "src/tools:infra/tools/x/": {
+                    "scm": "cipd",
+                    "url": null,
+                    "requested_revision": null,
+                    "revision": null,
+                    "action": "updated",
+                    "was_processed": true,
+                    "was_synced": true,
+                    "duration": 0.0,
+                    "downloaded_bytes": 1024,
+                },
+                "src/a/": {
+                    "scm": "git",
+                    "url": null,
+                    "requested_revision": null,
+                    "revision": null,
+                    "action": "failed",
+                    "was_processed": true,
+                    "was_synced": false,
+                    "duration": 0.0,
+                    "error": "nope",
+                },
+                "src/old/": {
+                    "scm": null,
+                    "url": null,
+                    "requested_revision": null,
+                    "revision": null,
+                    "action": "deleted",
+                    "was_processed": false,
+                    "was_synced": false,
+                    "duration": 0.0,
+                },
+            }})
+        );
+    }
+}
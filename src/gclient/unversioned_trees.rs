@@ -0,0 +1,125 @@
+//! `sync -D`: git checkouts and cipd packages left on disk that no DEPS references anymore,
+//! either removed before teapot_tools synced the solution or never recorded in .gclient_entries.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cipd::install::CIPD_SITE_ROOT_DIR;
+use crate::error::Error;
+use crate::gclient::cloner::{git, SyncOptions};
+
+/// a `.git` file is a submodule or worktree, not a checkout gclient made
+fn is_tree_root(path: &Path) -> bool {
+    path.join(".git").is_dir() || path.join(CIPD_SITE_ROOT_DIR).is_dir()
+}
+
+/// git checkouts and cipd roots under `dir` that are not `referenced`, and don't contain
+/// anything that is. all paths are absolute
+pub fn find_unversioned_trees(
+    dir: &Path,
+    referenced: &HashSet<PathBuf>,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut trees = vec![];
+    let mut todo = vec![dir.to_path_buf()];
+    while let Some(dir) = todo.pop() {
+        for entry in fs::read_dir(&dir).map_err(|e| Error::filesystem(&dir, e))? {
+            let entry = entry.map_err(|e| Error::filesystem(&dir, e))?;
+            let file_type = entry
+                .file_type()
+                .map_err(|e| Error::filesystem(entry.path(), e))?;
+            // symlinks are not followed, whatever they point to is not ours
+            if !file_type.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            if name == ".git" || name == CIPD_SITE_ROOT_DIR {
+                continue;
+            }
+            let path = entry.path();
+            if is_tree_root(&path) && !referenced.iter().any(|r| r.starts_with(&path)) {
+                trees.push(path);
+            } else {
+                todo.push(path);
+            }
+        }
+    }
+    trees.sort();
+    Ok(trees)
+}
+
+/// deletes the trees, except git checkouts with any changes (untracked files included),
+/// unless forced. returns the deleted ones
+pub fn delete_unversioned_trees(
+    trees: &[PathBuf],
+    opts: &SyncOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut deleted = vec![];
+    for tree in trees {
+        if tree.join(".git").exists() && !opts.force {
+            let changes = git(tree, &["status", "--porcelain"]).map_err(|e| Error::git(tree, e))?;
+            if !changes.is_empty() {
+                println!(
+                    "not deleting {:?}, it has local changes (use --force to delete it anyway):\n{}",
+                    tree, changes
+                );
+                continue;
+            }
+        }
+        if opts.verbosity >= 0 {
+            println!("deleting unversioned tree {:?}", tree);
+        }
+        fs::remove_dir_all(tree).map_err(|e| Error::filesystem(tree, e))?;
+        deleted.push(tree.clone());
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::test_dir::TestDir;
+
+    use super::find_unversioned_trees;
+
+    #[test]
+    fn test_find_unversioned_trees() {
+        let root = TestDir::new("unversioned");
+        for dir in [
+            "src/.git",
+            "src/third_party/kept/.git",
+            "src/third_party/stale/.git",
+            "src/third_party/stale/nested/.git",
+            "src/third_party/parent/.git",
+            "src/third_party/parent/child/.git",
+            "src/buildtools/linux64/.cipd",
+            "src/buildtools/old_cipd/.cipd",
+            "src/out/Default",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::create_dir_all(root.join("src/third_party/submodule")).unwrap();
+        fs::write(
+            root.join("src/third_party/submodule/.git"),
+            "gitdir: ../../.git/modules/submodule",
+        )
+        .unwrap();
+        let referenced = HashSet::from([
+            root.join("src"),
+            root.join("src/third_party/kept"),
+            root.join("src/third_party/parent/child"),
+            root.join("src/buildtools/linux64"),
+        ]);
+
+        let trees = find_unversioned_trees(&root.join("src"), &referenced);
+        assert_eq!(
+            trees.unwrap(),
+            vec![
+                root.join("src/buildtools/old_cipd"),
+                root.join("src/third_party/stale"),
+            ]
+        );
+    }
+}
@@ -1,11 +1,7 @@
 def Var(k):
-    v = gclient_custom_vars.get(k)
-    if v is not None:
-        return v
-    v = vars.get(k)
-    if v is not None:
-        return v
-    v = gclient_builtin_vars.get(k)
-    if v is not None:
-        return v
+    # like gclient, custom_vars first, then the builtin vars, then the vars from DEPS
+    for vars_ in (gclient_custom_vars, gclient_builtin_vars, globals().get('vars', {})):
+        v = vars_.get(k)
+        if v is not None:
+            return v
     raise Exception(f'Var("{k}") unresolved')
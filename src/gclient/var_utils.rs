@@ -1,77 +1,35 @@
 use std::collections::HashMap;
+use std::fmt;
 
-use pyo3::types::{PyDict, PyString};
-use pyo3::PyTypeInfo;
-use pyo3::Python;
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "python")]
+use pyo3::types::PyDict;
 
+use crate::gclient::eval::condition::eval_condition;
+use crate::gclient::eval::Value;
 use crate::host::{gclient_host_cpu, gclient_host_os};
-use crate::types::deps::VarsPrimitive;
-use crate::types::dotgclient::Dotgclient;
+use crate::types::deps::{DepsSpec, VarsPrimitive};
+use crate::types::dotgclient::{Dotgclient, Solution};
 use crate::types::machine::{GclientCPU, OS_LIST};
 
-pub fn set_vars_from_hashmap<'a>(
-    py: Python<'a>,
-    vars: &HashMap<String, VarsPrimitive>,
-) -> (&'a PyDict, &'a PyDict) {
-    let globals = PyDict::new(py);
-    globals
-        .set_item("__builtins__", py.eval("__builtins__", None, None).unwrap())
-        .unwrap();
-    globals
-        .set_item("vars", serde_json::to_string(vars).unwrap())
-        .unwrap();
-    globals
-        .set_item("json", py.import("json").unwrap())
-        .unwrap();
-    py.run(
-        include_str!("str_to_bool_eval.py"),
-        Some(globals),
-        Some(globals),
-    )
-    .unwrap();
-    let vars = py
-        .eval("json.loads(vars)", Some(globals), None)
-        .unwrap()
-        .downcast::<PyDict>()
-        .unwrap();
-    globals.set_item("vars", vars).unwrap();
-    for (var_name, var_value) in vars {
-        if var_value.is_instance(PyString::type_object(py)).unwrap() {
-            vars.set_item(
-                var_name,
-                py.eval(
-                    &format!(
-                        "str({})",
-                        serde_json::to_string(
-                            &var_value.downcast::<PyString>().unwrap().to_string()
-                        )
-                        .unwrap()
-                    ),
-                    Some(globals),
-                    Some(vars),
-                )
-                .unwrap(),
-            )
-            .unwrap();
-        }
-    }
-    (globals, vars)
-}
-
-/// sets up checkout_* vars based on .gclient file, and host_{cpu,os}
-pub fn set_builtin_vars(dotgclient: &Dotgclient, vars: &PyDict) {
-    vars.set_item("host_os", &gclient_host_os().to_string())
-        .unwrap();
+/// checkout_* vars based on .gclient file, host_{cpu,os}, and the os and cpu names themselves
+pub fn builtin_vars(dotgclient: &Dotgclient) -> HashMap<String, VarsPrimitive> {
+    let mut vars = HashMap::new();
+    vars.insert(
+        "host_os".to_string(),
+        VarsPrimitive::String(gclient_host_os().to_string()),
+    );
     for os in OS_LIST {
-        vars.set_item(
+        vars.insert(
             format!("checkout_{}", os),
-            dotgclient.target_os.contains(&os),
-        )
-        .unwrap();
-        vars.set_item(os.to_string(), os.to_string()).unwrap();
+            VarsPrimitive::Bool(dotgclient.target_os.contains(&os)),
+        );
+        vars.insert(os.to_string(), VarsPrimitive::String(os.to_string()));
     }
-    vars.set_item("host_cpu", &gclient_host_cpu().to_string())
-        .unwrap();
+    vars.insert(
+        "host_cpu".to_string(),
+        VarsPrimitive::String(gclient_host_cpu().to_string()),
+    );
     for cpu in [
         GclientCPU::Arm,
         GclientCPU::Arm64,
@@ -82,11 +40,87 @@ pub fn set_builtin_vars(dotgclient: &Dotgclient, vars: &PyDict) {
         GclientCPU::S390,
         GclientCPU::X64,
     ] {
-        vars.set_item(
+        vars.insert(
             format!("checkout_{}", cpu),
-            dotgclient.target_cpu.contains(&cpu),
-        )
-        .unwrap();
-        vars.set_item(cpu.to_string(), cpu.to_string()).unwrap();
+            VarsPrimitive::Bool(dotgclient.target_cpu.contains(&cpu)),
+        );
+        vars.insert(cpu.to_string(), VarsPrimitive::String(cpu.to_string()));
+    }
+    vars
+}
+
+/// copies vars over to a python dict, Str() values as {'literal': ...} like in DEPS
+#[cfg(feature = "python")]
+pub fn set_py_vars(py_vars: &PyDict, vars: &HashMap<String, VarsPrimitive>) -> Result<()> {
+    for (name, value) in vars {
+        match value {
+            VarsPrimitive::String(s) => py_vars.set_item(name, s),
+            VarsPrimitive::LiteralString(s) => {
+                let literal = PyDict::new(py_vars.py());
+                literal.set_item("literal", &s.literal)?;
+                py_vars.set_item(name, literal)
+            }
+            VarsPrimitive::Int(i) => py_vars.set_item(name, i),
+            VarsPrimitive::Float(f) => py_vars.set_item(name, f),
+            VarsPrimitive::Bool(b) => py_vars.set_item(name, b),
+        }?;
+    }
+    Ok(())
+}
+
+/// sets up checkout_* vars based on .gclient file, and host_{cpu,os}
+#[cfg(feature = "python")]
+pub fn set_builtin_vars(dotgclient: &Dotgclient, vars: &PyDict) -> Result<()> {
+    set_py_vars(vars, &builtin_vars(dotgclient))
+}
+
+/// vars from DEPS, overriden by the builtin vars, and custom_vars from .gclient
+/// (like in gclient), ready to evaluate conditions and gn args with
+pub struct SpecVars {
+    vars: HashMap<String, Value>,
+}
+
+impl SpecVars {
+    pub fn new(spec: &DepsSpec, solution: &Solution, dotgclient: &Dotgclient) -> Self {
+        let mut vars = spec.vars.clone();
+        vars.extend(builtin_vars(dotgclient));
+        if let Some(custom_vars) = solution.custom_vars.clone() {
+            vars.extend(custom_vars);
+        }
+        SpecVars {
+            vars: vars
+                .iter()
+                .map(|(name, value)| (name.clone(), value.into()))
+                .collect(),
+        }
+    }
+
+    /// evaluates a `condition` of a dependency or a hook
+    pub fn eval_condition(&self, condition: &str) -> Result<bool> {
+        eval_condition(condition, &self.vars)
+    }
+
+    /// value of a var as written to gclient_gn_args_file
+    pub fn gn_arg(&self, name: &str) -> Result<String> {
+        Ok(match self.vars.get(name) {
+            None => bail!("gn arg {} is not a var", name),
+            Some(Value::LiteralStr(s)) => serde_json::to_string(s)?,
+            Some(Value::Str(condition)) => eval_condition(condition, &self.vars)
+                .with_context(|| format!("evaluating gn arg {}", name))?
+                .to_string(),
+            Some(Value::Bool(b)) => b.to_string(),
+            Some(other) => other.to_py_repr(),
+        })
+    }
+}
+
+impl fmt::Display for SpecVars {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.vars.keys().collect();
+        names.sort();
+        for name in names {
+            writeln!(f, "{} = {}", name, self.vars[name].to_py_repr())?;
+        }
+        Ok(())
     }
 }
@@ -1,58 +1,61 @@
 use std::fs;
 use std::path::Path;
 
-use anyhow::Context;
-use anyhow::Result;
+use anyhow::{anyhow, Context};
 
 use crate::cipd::common::GENERIC_HTTP_CLIENT;
+use crate::error::Error;
 
-pub async fn download<P: AsRef<Path>>(bucket: &str, hash: &str, destination_: P) -> Result<()> {
+pub fn is_sha1_hex(hash: &str) -> bool {
+    hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub async fn download<P: AsRef<Path>>(
+    bucket: &str,
+    hash: &str,
+    destination_: P,
+) -> Result<(), Error> {
     let destination = destination_.as_ref();
     let url = format!(
         "https://commondatastorage.googleapis.com/{}/{}",
         bucket, hash
     );
-    let request = GENERIC_HTTP_CLIENT
-        .get(&url)
-        .send()
-        .await
-        .with_context(|| format!("failed downloading url: {}", url))?
-        .error_for_status()?;
-    fs::write(destination, request.bytes().await?)
-        .with_context(|| format!("writing file: {:?}", destination))?;
+    let bytes = async {
+        GENERIC_HTTP_CLIENT
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    }
+    .await
+    .map_err(|e| Error::gcs(&url, e))?;
+    fs::write(destination, bytes).map_err(|e| Error::filesystem(destination, e))?;
     Ok(())
 }
 
 pub async fn download_from_sha1_file<P: AsRef<Path>>(
     bucket: &str,
     sha1_file_location: P,
-) -> Result<()> {
+) -> Result<(), Error> {
     let sha1_file = sha1_file_location.as_ref();
-    let hash_ = fs::read_to_string(&sha1_file)
-        .with_context(|| format!("reading sha1 file: {:?}", sha1_file))
-        .unwrap();
+    let hash_ = fs::read_to_string(sha1_file)
+        .context("reading sha1 file")
+        .map_err(|e| Error::filesystem(sha1_file, e))?;
     let hash = hash_.trim_end();
-    assert_eq!(hash.len(), 40, "hash must be sha1 hex");
-    assert!(
-        hash.chars().all(|c| c.is_ascii_alphanumeric()),
-        "hash must be sha1 hex"
-    );
-    let target_location = sha1_file.parent().unwrap().join(
-        sha1_file
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .strip_suffix(".sha1")
-            .unwrap(),
-    );
-    download(&bucket, &hash, &target_location)
-        .await
-        .with_context(|| {
-            format!(
-                "downloading {} from bucket {} to {:?}",
-                hash, bucket, target_location
-            )
-        })?;
-    Ok(())
+    if !is_sha1_hex(hash) {
+        return Err(Error::filesystem(
+            sha1_file,
+            anyhow!("hash must be sha1 hex, got {:?}", hash),
+        ));
+    }
+    let target_location = match sha1_file
+        .to_str()
+        .and_then(|path| path.strip_suffix(".sha1"))
+    {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => return Err(Error::filesystem(sha1_file, anyhow!("not a .sha1 file"))),
+    };
+    download(bucket, hash, &target_location).await
 }
@@ -1,5 +1,8 @@
 pub mod cipd;
+pub mod error;
 pub mod gclient;
 pub mod gs;
 pub mod host;
+#[doc(hidden)]
+pub mod test_dir;
 pub mod types;
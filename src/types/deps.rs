@@ -14,20 +14,88 @@ pub enum VarsPrimitive {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LiteralString {
-    literal: String,
+    pub literal: String,
 }
 
 /// The whole DEPS file
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct DepsSpec {
+    #[serde(default)]
     pub vars: HashMap<String, VarsPrimitive>,
+    #[serde(default)]
     pub deps: HashMap<String, DependencyDef>,
     pub gclient_gn_args_file: Option<String>,
     pub gclient_gn_args: Option<Vec<String>>,
     #[serde(default)]
     pub use_relative_paths: bool,
     #[serde(default)]
-    pub recursedeps: Vec<String>,
+    pub recursedeps: Vec<Recursedep>,
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
+    #[serde(default)]
+    pub pre_deps_hooks: Vec<Hook>,
+    /// hooks are run relative to the DEPS directory instead of the .gclient one
+    #[serde(default)]
+    pub use_relative_hooks: bool,
+}
+
+/// a dependency to follow the DEPS of too
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Recursedep {
+    /// with the same deps_file as the DEPS it's in
+    Path(String),
+    /// ('path', 'DEPS.alt')
+    WithDepsFile(String, String),
+}
+
+impl Recursedep {
+    pub fn path(&self) -> &str {
+        match self {
+            Recursedep::Path(path) | Recursedep::WithDepsFile(path, _) => path,
+        }
+    }
+
+    pub fn deps_file(&self) -> Option<&str> {
+        match self {
+            Recursedep::Path(_) => None,
+            Recursedep::WithDepsFile(_, deps_file) => Some(deps_file),
+        }
+    }
+}
+
+impl DepsSpec {
+    /// deps with custom_deps from .gclient applied - overriden urls keep their conditions,
+    /// deps set to None are removed, and the ones not in DEPS are added
+    pub fn deps_with_custom_deps(
+        &self,
+        custom_deps: Option<&HashMap<String, Option<String>>>,
+    ) -> HashMap<String, DependencyDef> {
+        let mut deps = self.deps.clone();
+        for (clone_path, custom_url) in custom_deps.into_iter().flatten() {
+            match custom_url {
+                None => {
+                    deps.remove(clone_path);
+                }
+                Some(url) => {
+                    let condition = deps.get(clone_path).and_then(|dep| match dep.into() {
+                        Dependency::Git { condition, .. } | Dependency::CIPD { condition, .. } => {
+                            condition
+                        }
+                    });
+                    let overriden = match condition {
+                        Some(condition) => DependencyDef::Normal(Dependency::Git {
+                            url: url.clone(),
+                            condition: Some(condition),
+                        }),
+                        None => DependencyDef::Simple(url.clone()),
+                    };
+                    deps.insert(clone_path.clone(), overriden);
+                }
+            }
+        }
+        deps
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -54,7 +122,7 @@ pub enum Dependency {
 pub type CacheKVList = Vec<(String, String)>;
 
 impl Dependency {
-    pub fn to_cache_kv_list(&self, clone_path: &str) -> CacheKVList {
+    pub fn to_cache_kv_list(&self, clone_path: &str, cipd_service_url: &str) -> CacheKVList {
         match self {
             Dependency::CIPD { packages, .. } => packages
                 .iter()
@@ -62,8 +130,8 @@ impl Dependency {
                     (
                         format!("{clone_path}:{}", package.package),
                         format!(
-                            "https://chrome-infra-packages.appspot.com/{}@{}",
-                            package.package, package.version
+                            "{}/{}@{}",
+                            cipd_service_url, package.package, package.version
                         ),
                     )
                 })
@@ -96,3 +164,15 @@ pub struct CipdPackage {
     pub package: String,
     pub version: String,
 }
+
+/// An entry in `hooks` or `pre_deps_hooks`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Hook {
+    pub name: Option<String>,
+    /// ignored, like in gclient with git - we don't know what files have changed
+    pub pattern: Option<String>,
+    pub action: Vec<String>,
+    pub condition: Option<String>,
+    /// relative to the directory the hooks are run in
+    pub cwd: Option<String>,
+}
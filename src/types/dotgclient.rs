@@ -12,6 +12,8 @@ pub struct Solution {
     pub managed: Option<bool>,
     pub deps_file: Option<String>,
     pub custom_vars: Option<HashMap<String, VarsPrimitive>>,
+    /// dependency path -> url to override it with, or None to skip it
+    pub custom_deps: Option<HashMap<String, Option<String>>>,
     #[serde(default)]
     /// do not git checkout, just trust the solution is there and follow the DEPS
     pub tpot_no_checkout: bool,
@@ -19,7 +21,7 @@ pub struct Solution {
     pub tpot_internal_from_recursedeps: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Default, Debug)]
 pub struct Dotgclient {
     #[serde(default)]
     pub solutions: Vec<Solution>,
@@ -31,4 +33,12 @@ pub struct Dotgclient {
     pub target_cpu: Vec<GclientCPU>,
     #[serde(default)]
     pub target_cpu_only: bool,
+    /// directory with git mirrors shared between checkouts
+    pub cache_dir: Option<String>,
+    /// directory with cipd instances shared between checkouts
+    pub cipd_cache_dir: Option<String>,
+    /// in bytes, least recently used instances are evicted above it
+    pub cipd_cache_max_size: Option<u64>,
+    /// cipd backend, like an internal mirror. the default one if not set
+    pub cipd_service_url: Option<String>,
 }
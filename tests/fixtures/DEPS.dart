@@ -0,0 +1,187 @@
+# Trimmed excerpt of dart-lang/sdk DEPS, for parser tests.
+# Not a real snapshot: revisions and versions are made up.
+
+allowed_hosts = [
+  'boringssl.googlesource.com',
+  'chrome-infra-packages.appspot.com',
+  'chromium.googlesource.com',
+  'dart.googlesource.com',
+  'dart-internal.googlesource.com',
+  'fuchsia.googlesource.com',
+]
+
+vars = {
+  # The dart_root is the root of our sdk checkout. This is normally
+  # simply sdk, but if using special gclient specs it can be different.
+  "dart_root": "sdk",
+
+  # We use mirrors of all github repos to guarantee reproducibility and
+  # consistency between what users see and what the bots see.
+  # We need the mirrors to not have 100+ bots pulling github constantly.
+  # We mirror our github repos on Dart's git servers.
+  # DO NOT use this var if you don't see a mirror here:
+  #   https://dart.googlesource.com/
+  "dart_git": "https://dart.googlesource.com/",
+  "dart_internal_git": "https://dart-internal.googlesource.com",
+  # If the repo you want to use is at github.com/dart-lang, but not at
+  # dart.googlesource.com, please file an issue
+  # on github and add the label 'area-infrastructure'.
+  # When the repo is mirrored, you can add it to this DEPS file.
+
+  # Chromium git
+  "chromium_git": "https://chromium.googlesource.com",
+  "fuchsia_git": "https://fuchsia.googlesource.com",
+
+  # Checked-in SDK version. The checked-in SDK is a Dart SDK distribution in a
+  # cipd package used to run Dart scripts in the build and test infrastructure,
+  # which is automatically built on the release commits.
+  "sdk_tag": "version:3.3.0-174.2.beta",
+
+  # co19 is a cipd package. Use update.sh in tests/co19[_2] to update these
+  # hashes.
+  "co19_rev": "d8cd2398b1c1c3a4b2a6b4f8c1e5d7a9b0c2e4f6",
+
+  # The internal benchmarks repository.
+  "benchmarks_internal_rev": "3bd6bc6d207dfb7cf687537e819863cf9a8f2470",
+  "checkout_benchmarks_internal": False,
+
+  # Checkout Android dependencies only on Mac and Linux.
+  "download_android_deps":
+    "host_os == mac or (host_os == linux and host_cpu == x64)",
+
+  # Checkout extra javascript engines for testing or benchmarking.
+  # d8, the V8 shell, is always checked out.
+  "checkout_javascript_engines": False,
+  "d8_tag": "version:12.1.131",
+  "jsshell_tag": "version:120.0",
+
+  # As Flutter does, we use Fuchsia's GN and Clang toolchain. These revision
+  # should be kept up to date with the revisions pulled by the Flutter engine.
+  # The list of revisions for these tools comes from Fuchsia, here:
+  # https://fuchsia.googlesource.com/integration/+/HEAD/toolchain
+  # If there are problems with the toolchain, contact fuchsia-toolchain@.
+  "clang_version": "git_revision:b8afe3e7d1f2a47e22bd3d2c5c7e9f0b1d3e5a7c",
+  "gn_version": "git_revision:b2afae122eeb6ce09c52d63f67dc53fc517dbdc8",
+
+  # Update from https://chrome-infra-packages.appspot.com/p/fuchsia/sdk/core
+  "fuchsia_sdk_version": "version:17.20231130.0.1",
+  "download_fuchsia_deps": False,
+
+  # Ninja, runs the build based on files generated by GN.
+  "ninja_tag": "version:2@1.11.1.chromium.7",
+
+  # Scripts that make 'git cl format' work.
+  "clang_format_scripts_rev": "bb994c6f066d3e5a5ed3a2a1dfa3ae2c1a3e2b88",
+
+  ### /third_party/pkg dependencies
+  # 'tools/rev_sdk_deps.dart' can be used to roll these dependencies.
+  "args_rev": "6a2a3f9d4b5c7e8f0a1b2c3d4e5f60718293a4b5",
+  "async_rev": "75efa6cc08b2fc906fac4b6fdfdbcf6da7d0a2da",
+  "collection_rev": "91afde43ca3b4dac7e0ee06d0aa4ef2e5c9b8a79",
+}
+
+gclient_gn_args_file = Var("dart_root") + '/build/config/gclient_args.gni'
+gclient_gn_args = [
+]
+
+deps = {
+  # Stuff needed for GN build.
+  Var("dart_root") + "/buildtools/clang_format/script":
+    Var("chromium_git") + "/external/github.com/llvm/llvm-project/clang/tools/clang-format.git" +
+    "@" + Var("clang_format_scripts_rev"),
+
+  Var("dart_root") + "/benchmarks-internal": {
+    "url": Var("dart_internal_git") + "/benchmarks-internal.git" +
+           "@" + Var("benchmarks_internal_rev"),
+    "condition": "checkout_benchmarks_internal",
+  },
+  Var("dart_root") + "/tools/sdks/dart-sdk": {
+      "packages": [{
+          "package": "dart/dart-sdk/${{platform}}",
+          "version": Var("sdk_tag"),
+      }],
+      "dep_type": "cipd",
+  },
+  Var("dart_root") + "/tests/co19/src": {
+      "packages": [{
+          "package": "dart/third_party/co19",
+          "version": "git_revision:" + Var("co19_rev"),
+      }],
+      "dep_type": "cipd",
+  },
+  Var("dart_root") + "/third_party/d8": {
+      "packages": [{
+          "package": "dart/d8",
+          "version": Var("d8_tag"),
+      }],
+      "dep_type": "cipd",
+  },
+  Var("dart_root") + "/third_party/jsshell": {
+    "packages": [{
+      "package": "dart/third_party/jsshell",
+      "version": Var("jsshell_tag"),
+    }],
+    "condition": "checkout_javascript_engines",
+    "dep_type": "cipd",
+  },
+  Var("dart_root") + "/third_party/fuchsia/sdk/linux": {
+    "packages": [
+      {
+      "package": "fuchsia/sdk/core/linux-amd64",
+      "version": Var("fuchsia_sdk_version"),
+      }
+    ],
+    "condition": 'download_fuchsia_deps and host_os == "linux"',
+    "dep_type": "cipd",
+  },
+
+  Var("dart_root") + "/third_party/pkg/args":
+      Var("dart_git") + "args.git" + "@" + Var("args_rev"),
+  Var("dart_root") + "/third_party/pkg/async":
+      Var("dart_git") + "async.git" + "@" + Var("async_rev"),
+  Var("dart_root") + "/third_party/pkg/collection":
+      Var("dart_git") + "collection.git" + "@" + Var("collection_rev"),
+
+  Var("dart_root") + "/third_party/android_tools/ndk": {
+      "packages": [
+          {
+            "package": "flutter/android/ndk/${{os}}-amd64",
+            "version": "version:r25b"
+          }
+      ],
+      "condition": "download_android_deps",
+      "dep_type": "cipd",
+  },
+
+  Var("dart_root") + "/buildtools/ninja": {
+      "packages": [{
+          "package": "infra/3pp/tools/ninja/${{platform}}",
+          "version": Var("ninja_tag"),
+      }],
+      "dep_type": "cipd",
+  },
+}
+
+deps_os = {}
+
+hooks = [
+  {
+    # Generate the .dart_tool/package_confg.json file.
+    'name': 'Generate .dart_tool/package_confg.json',
+    'pattern': '.',
+    'action': ['python3', 'sdk/tools/generate_package_config.py'],
+  },
+  {
+    # Generate the sdk/version file.
+    'name': 'Generate sdk/version',
+    'pattern': '.',
+    'action': ['python3', 'sdk/tools/generate_sdk_version_file.py'],
+  },
+  {
+    # Update the Windows toolchain if necessary.
+    'name': 'win_toolchain',
+    'pattern': '.',
+    'action': ['python3', 'sdk/build/vs_toolchain.py', 'update'],
+    'condition': 'checkout_win'
+  },
+]
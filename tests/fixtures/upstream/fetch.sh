@@ -0,0 +1,30 @@
+#!/bin/sh
+# Fetches the DEPS files of projects that use gclient, verbatim, for the
+# python parity test (deps_parser.rs). Each one is pinned to the upstream
+# commit recorded for it in COMMITS; `./fetch.sh latest` (or a project
+# missing from COMMITS) takes the current HEAD of its repository instead.
+set -eu
+cd "$(dirname "$0")"
+touch COMMITS
+: >COMMITS.new
+trap 'rm -f COMMITS.new DEPS.*.b64' EXIT
+
+while read -r name repo; do
+    commit=$(awk -v name="$name" '$1 == name { print $3 }' COMMITS)
+    if [ "${1:-}" = latest ] || [ -z "$commit" ]; then
+        commit=$(git ls-remote "$repo" HEAD)
+        commit=${commit%%	*}
+    fi
+    # gitiles serves raw files base64 encoded
+    curl -sSf -o "DEPS.$name.b64" "$repo/+/$commit/DEPS?format=TEXT"
+    base64 -d "DEPS.$name.b64" >"DEPS.$name"
+    rm "DEPS.$name.b64"
+    echo "$name $repo $commit" >>COMMITS.new
+done <<REPOS
+chromium https://chromium.googlesource.com/chromium/src
+v8 https://chromium.googlesource.com/v8/v8
+webrtc https://webrtc.googlesource.com/src
+dart https://dart.googlesource.com/sdk
+skia https://skia.googlesource.com/skia
+REPOS
+mv COMMITS.new COMMITS